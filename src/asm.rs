@@ -0,0 +1,476 @@
+//! Assembler for the Cowgod-style mnemonics printed by `disasm`.
+//!
+//! One statement per line, with `;` starting a comment:
+//!
+//! ```text
+//! include "sprites.asm"
+//! SPEED equ 2
+//! start:  LD V0, SPEED
+//!         LD I, smiley
+//!         DRW V0, V0, 5
+//! loop:   JP loop
+//! smiley: db 0b00100100, 0x00, 0x81, 0x42, 0x3C
+//! ```
+//!
+//! Numbers can be decimal, `0x` hex or `0b` binary, and anywhere a number is
+//! expected a label or constant can be used instead.
+
+use decoder::{Instruction, RegisterVal, RegisterRegister, RegisterRegisterNibble};
+use emustate::PC_START;
+use encoder;
+use memory::{Byte, Word};
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// How deep includes may nest before we assume a file includes itself.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+const MNEMONICS: [&str; 31] = [
+    "CLS", "RET", "SCR", "SCL", "EXIT", "LOW", "HIGH", "AUDIO", "SCD", "SCU", "PLANE",
+    "JP", "CALL", "SKP", "SKNP", "PITCH", "SE", "SNE", "ADD", "LD", "OR", "AND", "XOR",
+    "SUB", "SUBN", "SAVE", "LOAD", "SHR", "SHL", "RND", "DRW"
+];
+
+/// A problem in the source, with the file and line it was found on.
+#[derive(Debug, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub message: String
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+#[derive(Debug)]
+enum Item {
+    Instruction { mnemonic: String, operands: Vec<String> },
+    Bytes(Vec<String>),
+    Words(Vec<String>)
+}
+
+#[derive(Debug)]
+struct Statement {
+    file: String,
+    line: usize,
+    item: Item
+}
+
+struct Assembler<'a> {
+    loader: &'a mut dyn FnMut(&str) -> io::Result<String>,
+    symbols: HashMap<String, u16>,
+    statements: Vec<Statement>,
+    address: u16,
+    depth: usize
+}
+
+/// Assembles source that doesn't include any other files.
+pub fn assemble(source: &str) -> Result<Vec<Byte>, AsmError> {
+    assemble_with("<source>", source, &mut |name: &str| {
+        Err(io::Error::new(io::ErrorKind::NotFound, format!("cannot include `{}` here", name)))
+    })
+}
+
+/// Assembles a file on disk. Includes are looked up next to it.
+pub fn assemble_file(path: &Path) -> Result<Vec<Byte>, AsmError> {
+    let name = path.display().to_string();
+    let source = fs::read_to_string(path).map_err(|e| AsmError { file: name.clone(), line: 0, message: e.to_string() })?;
+    let directory = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+    assemble_with(&name, &source, &mut |include: &str| fs::read_to_string(directory.join(include)))
+}
+
+/// Assembles `source`, calling `loader` to read the text of each include.
+pub fn assemble_with(name: &str, source: &str, loader: &mut dyn FnMut(&str) -> io::Result<String>) -> Result<Vec<Byte>, AsmError> {
+    let mut assembler = Assembler {
+        loader,
+        symbols: HashMap::new(),
+        statements: Vec::new(),
+        address: PC_START,
+        depth: 0
+    };
+    assembler.read_source(name, source)?;
+    assembler.emit()
+}
+
+impl<'a> Assembler<'a> {
+    /// First pass: gives every label an address and collects the statements.
+    fn read_source(&mut self, name: &str, source: &str) -> Result<(), AsmError> {
+        for (index, text) in source.lines().enumerate() {
+            let line = index + 1;
+            let error = |message: String| AsmError { file: name.to_string(), line, message };
+
+            let mut text = text.split(';').next().unwrap().trim();
+            while let Some(colon) = text.find(':') {
+                let label = text[..colon].trim();
+                if !is_identifier(label) {
+                    break;
+                }
+                self.define(label, self.address).map_err(error)?;
+                text = text[colon + 1..].trim();
+            }
+            if text.is_empty() {
+                continue;
+            }
+
+            let (first, rest) = split_first_word(text);
+            let (second, value) = split_first_word(rest);
+            if second.eq_ignore_ascii_case("equ") {
+                let value = self.value(value).map_err(error)?;
+                self.define(first, value).map_err(error)?;
+                continue;
+            }
+            if first.eq_ignore_ascii_case("include") {
+                let include = rest.trim_matches('"');
+                let source = self.load(include).map_err(error)?;
+                self.depth += 1;
+                let result = self.read_source(include, &source);
+                self.depth -= 1;
+                result?;
+                continue;
+            }
+
+            let operands = split_operands(rest);
+            let (item, size) = match first.to_ascii_uppercase().as_str() {
+                "DB" => (Item::Bytes(operands.clone()), operands.len()),
+                "DW" => (Item::Words(operands.clone()), operands.len() * 2),
+                mnemonic => {
+                    let size = if is_long_load(mnemonic, &operands) { 4 } else { 2 };
+                    (Item::Instruction { mnemonic: mnemonic.to_string(), operands }, size)
+                }
+            };
+            self.statements.push(Statement { file: name.to_string(), line, item });
+            self.address = self.address.checked_add(size as u16)
+                .ok_or_else(|| error("program does not fit in memory".to_string()))?;
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, name: &str) -> Result<String, String> {
+        if self.depth == MAX_INCLUDE_DEPTH {
+            return Err(format!("includes nested too deeply at `{}`", name));
+        }
+        (self.loader)(name).map_err(|e| format!("cannot include `{}`: {}", name, e))
+    }
+
+    fn define(&mut self, name: &str, value: u16) -> Result<(), String> {
+        if !is_identifier(name) {
+            return Err(format!("`{}` is not a valid name", name));
+        }
+        if self.symbols.insert(name.to_string(), value).is_some() {
+            return Err(format!("`{}` is defined twice", name));
+        }
+        Ok(())
+    }
+
+    /// Second pass: encodes every statement now all labels are known.
+    fn emit(&self) -> Result<Vec<Byte>, AsmError> {
+        let mut rom = Vec::new();
+        for statement in &self.statements {
+            let error = |message: String| AsmError { file: statement.file.clone(), line: statement.line, message };
+            match statement.item {
+                Item::Bytes(ref values) => {
+                    for value in values {
+                        rom.push(self.sized(value, 0xFF).map_err(error)? as Byte);
+                    }
+                },
+                Item::Words(ref values) => {
+                    for value in values {
+                        let word = Word::new_from_full(self.value(value).map_err(error)?);
+                        rom.push(word.high());
+                        rom.push(word.low());
+                    }
+                },
+                Item::Instruction { ref mnemonic, ref operands } => {
+                    let (instruction, long) = self.instruction(mnemonic, operands).map_err(error)?;
+                    let opcode = encoder::encode(&instruction);
+                    rom.push(opcode.high());
+                    rom.push(opcode.low());
+                    if let Some(long) = long {
+                        rom.push(long.high());
+                        rom.push(long.low());
+                    }
+                }
+            }
+        }
+        Ok(rom)
+    }
+
+    /// The instruction for one line, plus the address that follows `LD I, LONG`.
+    fn instruction(&self, mnemonic: &str, operands: &[String]) -> Result<(Instruction, Option<Word>), String> {
+        let ops: Vec<&str> = operands.iter().map(|operand| operand.as_str()).collect();
+        let upper: Vec<String> = ops.iter().map(|operand| operand.to_ascii_uppercase()).collect();
+        let upper: Vec<&str> = upper.iter().map(|operand| operand.as_str()).collect();
+        let bad_operands = || format!("bad operands for `{}`: `{}`", mnemonic, operands.join(", "));
+
+        let instruction = match (mnemonic, upper.as_slice()) {
+            ("CLS", []) => Instruction::CLS,
+            ("RET", []) => Instruction::RET,
+            ("SCR", []) => Instruction::SCR,
+            ("SCL", []) => Instruction::SCL,
+            ("EXIT", []) => Instruction::EXIT,
+            ("LOW", []) => Instruction::LOW,
+            ("HIGH", []) => Instruction::HIGH,
+            ("AUDIO", []) => Instruction::AUDIO,
+            ("SCD", [_]) => Instruction::SCD(self.sized(ops[0], 0xF)? as Byte),
+            ("SCU", [_]) => Instruction::SCU(self.sized(ops[0], 0xF)? as Byte),
+            ("PLANE", [_]) => Instruction::PLANE(self.sized(ops[0], 0xF)? as Byte),
+            ("JP", ["V0", _]) => Instruction::JUMPV0(self.address(ops[1])?),
+            ("JP", [_]) => Instruction::JUMP(self.address(ops[0])?),
+            ("CALL", [_]) => Instruction::CALL(self.address(ops[0])?),
+            ("SKP", [x]) => Instruction::SKP(register(x).ok_or_else(bad_operands)?),
+            ("SKNP", [x]) => Instruction::SKNP(register(x).ok_or_else(bad_operands)?),
+            ("PITCH", [x]) => Instruction::PITCH(register(x).ok_or_else(bad_operands)?),
+            ("SE", [x, y]) | ("SNE", [x, y]) | ("ADD", [x, y]) | ("LD", [x, y]) if is_register(x) && is_register(y) => {
+                let registers = both_registers(x, y);
+                match mnemonic {
+                    "SE" => Instruction::SER(registers),
+                    "SNE" => Instruction::SNER(registers),
+                    "ADD" => Instruction::ADDR(registers),
+                    _ => Instruction::LDR(registers)
+                }
+            },
+            ("OR", [x, y]) | ("AND", [x, y]) | ("XOR", [x, y]) | ("SUB", [x, y]) | ("SUBN", [x, y])
+                | ("SAVE", [x, y]) | ("LOAD", [x, y]) if is_register(x) && is_register(y) => {
+                let registers = both_registers(x, y);
+                match mnemonic {
+                    "OR" => Instruction::ORR(registers),
+                    "AND" => Instruction::ANDR(registers),
+                    "XOR" => Instruction::XORR(registers),
+                    "SUB" => Instruction::SUBR(registers),
+                    "SUBN" => Instruction::SUBNR(registers),
+                    "SAVE" => Instruction::SAVERNG(registers),
+                    _ => Instruction::LOADRNG(registers)
+                }
+            },
+            ("SHR", [x]) | ("SHL", [x]) | ("SHR", [x, _]) | ("SHL", [x, _]) if is_register(x) => {
+                let y = upper.get(1).cloned().unwrap_or(x);
+                if !is_register(y) {
+                    return Err(bad_operands());
+                }
+                let registers = both_registers(x, y);
+                if mnemonic == "SHR" { Instruction::SHR(registers) } else { Instruction::SHL(registers) }
+            },
+            ("ADD", ["I", x]) if is_register(x) => Instruction::ADDI(register(x).unwrap()),
+            ("LD", ["I", _]) if upper[1].starts_with("LONG ") => {
+                let address = self.value(ops[1][5..].trim())?;
+                return Ok((Instruction::LDILONG, Some(Word::new_from_full(address))));
+            },
+            ("LD", ["I", _]) => Instruction::LDI(self.address(ops[1])?),
+            ("LD", [x, "DT"]) if is_register(x) => Instruction::LDVDT(register(x).unwrap()),
+            ("LD", [x, "K"]) if is_register(x) => Instruction::LDK(register(x).unwrap()),
+            ("LD", [x, "[I]"]) if is_register(x) => Instruction::LDARR(register(x).unwrap()),
+            ("LD", [x, "R"]) if is_register(x) => Instruction::LDRPL(register(x).unwrap()),
+            ("LD", [target, x]) if is_register(x) => {
+                let x = register(x).unwrap();
+                match *target {
+                    "DT" => Instruction::LDDTV(x),
+                    "ST" => Instruction::LDSTV(x),
+                    "F" => Instruction::LDFONT(x),
+                    "HF" => Instruction::LDHF(x),
+                    "B" => Instruction::LDBCD(x),
+                    "[I]" => Instruction::STARR(x),
+                    "R" => Instruction::STRPL(x),
+                    _ => return Err(bad_operands())
+                }
+            },
+            ("SE", [x, _]) | ("SNE", [x, _]) | ("LD", [x, _]) | ("ADD", [x, _]) | ("RND", [x, _]) if is_register(x) => {
+                let register_val = RegisterVal {
+                    register: register(x).unwrap(),
+                    value: self.sized(ops[1], 0xFF)? as Byte
+                };
+                match mnemonic {
+                    "SE" => Instruction::SE(register_val),
+                    "SNE" => Instruction::SNE(register_val),
+                    "LD" => Instruction::LD(register_val),
+                    "ADD" => Instruction::ADD(register_val),
+                    _ => Instruction::RND(register_val)
+                }
+            },
+            ("DRW", [x, y, _]) if is_register(x) && is_register(y) => {
+                Instruction::DRW(RegisterRegisterNibble {
+                    first_reg: register(x).unwrap(),
+                    second_reg: register(y).unwrap(),
+                    nibble: self.sized(ops[2], 0xF)? as Byte
+                })
+            },
+            _ if MNEMONICS.contains(&mnemonic) => return Err(bad_operands()),
+            _ => return Err(format!("unknown instruction `{}`", mnemonic))
+        };
+        Ok((instruction, None))
+    }
+
+    /// A 12 bit address operand.
+    fn address(&self, operand: &str) -> Result<Word, String> {
+        self.sized(operand, 0xFFF).map(Word::new_from_full)
+    }
+
+    fn sized(&self, operand: &str, max: u16) -> Result<u16, String> {
+        let value = self.value(operand)?;
+        if value > max {
+            return Err(format!("`{}` is 0x{:X}, which is larger than 0x{:X}", operand, value, max));
+        }
+        Ok(value)
+    }
+
+    /// A number, or the value of a label or constant.
+    fn value(&self, operand: &str) -> Result<u16, String> {
+        let operand = operand.trim();
+        if let Some(number) = parse_number(operand) {
+            return Ok(number);
+        }
+        self.symbols.get(operand).cloned().ok_or_else(|| format!("`{}` is not a number or a known name", operand))
+    }
+}
+
+fn parse_number(text: &str) -> Option<u16> {
+    let lower = text.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        u16::from_str_radix(binary, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {},
+        _ => return false
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+fn split_first_word(text: &str) -> (&str, &str) {
+    let text = text.trim();
+    match text.find(char::is_whitespace) {
+        Some(space) => (&text[..space], text[space..].trim()),
+        None => (text, "")
+    }
+}
+
+fn split_operands(text: &str) -> Vec<String> {
+    if text.is_empty() {
+        return Vec::new();
+    }
+    text.split(',').map(|operand| operand.trim().to_string()).collect()
+}
+
+fn is_long_load(mnemonic: &str, operands: &[String]) -> bool {
+    mnemonic == "LD" && operands.len() == 2 && operands[0].eq_ignore_ascii_case("I")
+        && operands[1].to_ascii_uppercase().starts_with("LONG ")
+}
+
+/// `V0` to `VF`, in either case.
+fn register(operand: &str) -> Option<Byte> {
+    let upper = operand.to_ascii_uppercase();
+    let digit = upper.strip_prefix('V')?;
+    if digit.len() != 1 {
+        return None;
+    }
+    u8::from_str_radix(digit, 16).ok()
+}
+
+fn is_register(operand: &str) -> bool {
+    register(operand).is_some()
+}
+
+fn both_registers(x: &str, y: &str) -> RegisterRegister {
+    RegisterRegister {
+        first_reg: register(x).unwrap(),
+        second_reg: register(y).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use asm;
+    use disasm;
+    use platform::Platform;
+
+    use std::collections::HashMap;
+    use std::io;
+
+    #[test]
+    fn test_assembles_instructions() {
+        let rom = asm::assemble("CLS\nLD VA, 0x02\nDRW V0, V1, 5\nld [i], v3 ; comment\nSHR V1").unwrap();
+
+        assert_eq!(rom, vec![0x00, 0xE0, 0x6A, 0x02, 0xD0, 0x15, 0xF3, 0x55, 0x81, 0x16]);
+    }
+
+    #[test]
+    fn test_labels_constants_and_data() {
+        let source = "
+            SPEED equ 3
+            start:  LD V0, SPEED
+                    LD I, sprite
+            loop:   JP loop
+            sprite: db 0b11110000, 0x90
+                    dw 0x1234, start
+        ";
+        let rom = asm::assemble(source).unwrap();
+
+        assert_eq!(rom, vec![0x60, 0x03, 0xA2, 0x06, 0x12, 0x04, 0xF0, 0x90, 0x12, 0x34, 0x02, 0x00]);
+    }
+
+    #[test]
+    fn test_long_load_takes_four_bytes() {
+        let rom = asm::assemble("LD I, LONG data\nJP data\ndata: db 1").unwrap();
+
+        assert_eq!(rom, vec![0xF0, 0x00, 0x02, 0x06, 0x12, 0x06, 0x01]);
+    }
+
+    #[test]
+    fn test_includes() {
+        let mut files = HashMap::new();
+        files.insert("font.asm", "digit: db 0xF0");
+        files.insert("bad.asm", "\nLD V0");
+        let mut loader = |name: &str| {
+            files.get(name).map(|text| text.to_string()).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "missing"))
+        };
+        let rom = asm::assemble_with("main.asm", "LD I, digit\ninclude \"font.asm\"", &mut loader).unwrap();
+
+        assert_eq!(rom, vec![0xA2, 0x02, 0xF0]);
+
+        let error = asm::assemble_with("main.asm", "include \"bad.asm\"", &mut loader).unwrap_err();
+        assert_eq!(error.to_string(), "bad.asm:2: bad operands for `LD`: `V0`");
+    }
+
+    #[test]
+    fn test_errors_name_the_line() {
+        let error = asm::assemble("CLS\nLD V0, 0x100").unwrap_err();
+        assert_eq!(error.to_string(), "<source>:2: `0x100` is 0x100, which is larger than 0xFF");
+
+        let error = asm::assemble("\n\nFOO V1").unwrap_err();
+        assert_eq!(error.to_string(), "<source>:3: unknown instruction `FOO`");
+
+        let error = asm::assemble("JP nowhere").unwrap_err();
+        assert_eq!(error.line, 1);
+
+        let error = asm::assemble("a: CLS\na: CLS").unwrap_err();
+        assert_eq!(error.message, "`a` is defined twice");
+    }
+
+    #[test]
+    fn test_reassembles_disassembly() {
+        let rom = vec![0x00, 0xE0, 0x22, 0x08, 0x12, 0x00, 0xF0, 0x00, 0x12, 0x34, 0x81, 0x2E, 0xFF, 0xFF, 0xAB];
+        let listing = disasm::disassemble(&rom, Platform::XoChip);
+        let source: String = listing.lines()
+            .map(|line| if line.ends_with(':') { line } else { line.splitn(3, "  ").nth(2).unwrap().trim() })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        assert_eq!(asm::assemble(&source).unwrap(), rom);
+    }
+}
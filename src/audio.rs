@@ -0,0 +1,154 @@
+pub const SAMPLE_RATE : u32 = 44100;
+pub const TIMER_HZ : u32 = 60;
+
+const TONE_HZ : f32 = 440.0;
+const VOLUME : f32 = 0.25;
+
+/// An XO-CHIP audio pattern: 128 one bit samples played in a loop at a rate
+/// set by the pitch register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pattern {
+    pub buffer: [u8; 16],
+    pub pitch: u8
+}
+
+/// A sink for the CHIP-8 buzzer. `update` is called once per timer tick with
+/// whether the sound timer is running, and the XO-CHIP pattern to play if the
+/// program has loaded one.
+pub trait Audio {
+    fn update(&mut self, buzzer: bool, pattern: Option<&Pattern>);
+}
+
+impl Pattern {
+    /// Pitch 64 plays at 4000 samples a second, each 48 steps is an octave.
+    pub fn sample_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+
+    pub fn bit(&self, index: usize) -> bool {
+        let index = index % 128;
+        (self.buffer[index / 8] >> (7 - index % 8)) & 1 != 0
+    }
+}
+
+/// Generates the buzzer sound as `f32` samples in `-volume..volume`: a 440 Hz
+/// square wave, or an XO-CHIP pattern once one is set.
+pub struct Oscillator {
+    sample_rate: f32,
+    pattern: Option<Pattern>,
+    phase: f32,
+    volume: f32 
+}
+
+impl Oscillator {
+    pub fn new(sample_rate: u32) -> Oscillator {
+        Oscillator {
+            sample_rate: sample_rate as f32,
+            pattern: None,
+            phase: 0.0,
+            volume: VOLUME
+        }
+    }
+
+    pub fn set_pattern(&mut self, pattern: Option<&Pattern>) {
+        self.pattern = pattern.cloned();
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        match self.pattern {
+            Some(pattern) => {
+                let high = pattern.bit(self.phase as usize);
+                self.phase = (self.phase + pattern.sample_rate() / self.sample_rate) % 128.0;
+                if high { self.volume } else { -self.volume }
+            },
+            None => {
+                let sample = if self.phase <= 0.5 { self.volume } else { -self.volume };
+                self.phase = (self.phase + TONE_HZ / self.sample_rate) % 1.0;
+                sample
+            }
+        }
+    }
+}
+
+/// Discards all sound. Used when there is no audio device.
+pub struct SilentAudio;
+
+impl Audio for SilentAudio {
+    fn update(&mut self, _buzzer: bool, _pattern: Option<&Pattern>) {}
+}
+
+/// Renders the buzzer into a sample buffer instead of playing it, producing
+/// `sample_rate / TIMER_HZ` samples per timer tick (silence while off).
+pub struct RecordingAudio {
+    wave: Oscillator,
+    samples_per_tick: usize,
+    samples: Vec<f32>
+}
+
+impl RecordingAudio {
+    pub fn new(sample_rate: u32) -> RecordingAudio {
+        RecordingAudio {
+            wave: Oscillator::new(sample_rate),
+            samples_per_tick: (sample_rate / TIMER_HZ) as usize,
+            samples: Vec::new()
+        }
+    }
+
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    /// Number of timer ticks for which the buzzer was on.
+    pub fn ticks_sounding(&self) -> usize {
+        self.samples.chunks(self.samples_per_tick)
+            .filter(|tick| tick.iter().any(|sample| *sample != 0.0))
+            .count()
+    }
+}
+
+impl Default for RecordingAudio {
+    fn default() -> RecordingAudio {
+        RecordingAudio::new(SAMPLE_RATE)
+    }
+}
+
+impl Audio for RecordingAudio {
+    fn update(&mut self, buzzer: bool, pattern: Option<&Pattern>) {
+        self.wave.set_pattern(pattern);
+        for _ in 0..self.samples_per_tick {
+            let sample = if buzzer { self.wave.next_sample() } else { 0.0 };
+            self.samples.push(sample);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use audio::{Audio, RecordingAudio, Pattern};
+
+    #[test]
+    fn test_recording_renders_one_tick_per_update() {
+        let mut audio = RecordingAudio::new(600);
+        audio.update(true, None);
+        audio.update(false, None);
+        audio.update(true, None);
+
+        assert_eq!(audio.samples().len(), 30);
+        assert_eq!(audio.ticks_sounding(), 2);
+        assert!(audio.samples()[10..20].iter().all(|sample| *sample == 0.0));
+        assert_eq!(audio.samples()[0], 0.25);
+    }
+
+    #[test]
+    fn test_recording_plays_pattern() {
+        let mut buffer = [0u8; 16];
+        buffer[0] = 0b10100000;
+        let pattern = Pattern { buffer, pitch: 64 };
+        let mut audio = RecordingAudio::new(4000);
+
+        audio.update(true, Some(&pattern));
+
+        assert_eq!(&audio.samples()[0..4], &[0.25, -0.25, 0.25, -0.25]);
+        assert!(audio.samples()[4..66].iter().all(|sample| *sample == -0.25));
+    }
+}
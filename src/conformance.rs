@@ -0,0 +1,212 @@
+//! Runs a directory of test ROMs headlessly and compares the screen each one
+//! ends on with a golden copy checked in next to it.
+//!
+//! The directory holds a `manifest.txt` with one case per line:
+//!
+//! ```text
+//! # NAME      ROM          FRAMES  OPTIONS
+//! font        font.8o      10
+//! keypad      keypad.8o    40      input=keypad.input
+//! shift-vip   quirks.8o    10      quirks=vip platform=chip8 clock=600
+//! ```
+//!
+//! ROMs can be binaries or Octo source (`.8o`). `input` is a `ScriptedInput`
+//! script. Goldens are the `screenshot::to_ascii` text of the final screen,
+//! stored as `golden/NAME.txt`, so changes to them show up clearly in diffs.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use emustate::Chip8State;
+use headless::{self, Limit};
+use input::ScriptedInput;
+use platform::Platform;
+use quirks::Quirks;
+use rom;
+use scheduler;
+use screenshot;
+
+pub const MANIFEST : &str = "manifest.txt";
+pub const GOLDEN_DIRECTORY : &str = "golden";
+
+/// Differing rows shown before the rest are only counted.
+const MAX_DIFF_ROWS : usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Case {
+    pub name: String,
+    pub rom: PathBuf,
+    pub frames: u64,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub clock_hz: u32,
+    pub input: Option<PathBuf>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    /// The golden was written or updated with `--bless`.
+    Blessed,
+    Failed(String)
+}
+
+/// Reads `manifest.txt` in `directory`. Paths in it are relative to the
+/// directory.
+pub fn read_manifest(directory: &Path) -> Result<Vec<Case>, String> {
+    let filename = directory.join(MANIFEST);
+    let text = fs::read_to_string(&filename).map_err(|e| format!("{}: {}", filename.display(), e))?;
+    let mut cases: Vec<Case> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let error = |message: String| format!("{} line {}: {}", filename.display(), number + 1, message);
+        let words: Vec<&str> = line.split('#').next().unwrap_or("").split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        if words.len() < 3 {
+            return Err(error(String::from("expected NAME ROM FRAMES [OPTIONS]")));
+        }
+        if cases.iter().any(|case| case.name == words[0]) {
+            return Err(error(format!("`{}` is already a case", words[0])));
+        }
+
+        let mut case = Case {
+            name: words[0].to_string(),
+            rom: directory.join(words[1]),
+            frames: words[2].parse().map_err(|_| error(format!("bad frame count `{}`", words[2])))?,
+            platform: Platform::default(),
+            quirks: Platform::default().default_quirks(),
+            clock_hz: scheduler::DEFAULT_INSTRUCTIONS_PER_FRAME * scheduler::FRAME_RATE,
+            input: None
+        };
+        let mut quirks = None;
+        for option in &words[3..] {
+            let (key, value) = option.split_once('=').ok_or_else(|| error(format!("expected KEY=VALUE, got `{}`", option)))?;
+            match key {
+                "platform" => case.platform = Platform::from_name(value).ok_or_else(|| error(format!("unknown platform `{}`", value)))?,
+                "quirks" => quirks = Some(Quirks::from_name(value).ok_or_else(|| error(format!("unknown quirks `{}`", value)))?),
+                "clock" => case.clock_hz = value.parse().ok().filter(|&hz| hz > 0).ok_or_else(|| error(format!("bad clock speed `{}`", value)))?,
+                "input" => case.input = Some(directory.join(value)),
+                _ => return Err(error(format!("unknown option `{}`", key)))
+            }
+        }
+        case.quirks = quirks.unwrap_or_else(|| case.platform.default_quirks());
+        cases.push(case);
+    }
+    Ok(cases)
+}
+
+impl Case {
+    /// Runs the ROM for its frames and returns the final screen as ASCII.
+    pub fn run(&self) -> Result<String, String> {
+        let error = |message: String| format!("{}: {}", self.name, message);
+        let rom = rom::read_rom(&self.rom).map_err(error)?;
+        let mut input = match self.input {
+            Some(ref filename) => ScriptedInput::from_file(&filename.to_string_lossy()).map_err(error)?,
+            None => ScriptedInput::new()
+        };
+
+        let mut state = Chip8State::default();
+        state.set_platform(self.platform);
+        state.set_quirks(self.quirks);
+        state.seed_rng(0);
+        state.load_instructions(rom).map_err(|e| error(e.to_string()))?;
+        state.load_font();
+        headless::run(&mut state, self.clock_hz, &mut input, Limit::Frames(self.frames)).map_err(|e| error(e.to_string()))?;
+        Ok(screenshot::to_ascii(state.framebuffer()))
+    }
+
+    /// Runs the case and compares it with its golden in `directory`, or
+    /// replaces the golden if `bless` is set.
+    pub fn check(&self, directory: &Path, bless: bool) -> Result<Outcome, String> {
+        let actual = self.run()?;
+        let golden = directory.join(GOLDEN_DIRECTORY).join(format!("{}.txt", self.name));
+        let expected = fs::read_to_string(&golden).ok();
+        if expected.as_ref() == Some(&actual) {
+            return Ok(Outcome::Passed);
+        }
+        if bless {
+            fs::create_dir_all(directory.join(GOLDEN_DIRECTORY))
+                .and_then(|_| fs::write(&golden, &actual))
+                .map_err(|e| format!("{}: {}", golden.display(), e))?;
+            return Ok(Outcome::Blessed);
+        }
+        Ok(Outcome::Failed(match expected {
+            Some(expected) => diff(&expected, &actual),
+            None => format!("there is no golden at {}; run with --bless to create it\n", golden.display())
+        }))
+    }
+}
+
+/// Runs every case in `directory`, returning a line (and a diff) per case and
+/// the number which failed.
+pub fn run_directory(directory: &Path, bless: bool) -> Result<(String, usize), String> {
+    let mut report = String::new();
+    let mut failures = 0;
+    for case in read_manifest(directory)? {
+        match case.check(directory, bless) {
+            Ok(Outcome::Passed) => report += &format!("ok       {}\n", case.name),
+            Ok(Outcome::Blessed) => report += &format!("blessed  {}\n", case.name),
+            Ok(Outcome::Failed(diff)) => {
+                failures += 1;
+                report += &format!("FAILED   {}\n{}", case.name, diff);
+            },
+            Err(e) => {
+                failures += 1;
+                report += &format!("ERROR    {}\n", e);
+            }
+        }
+    }
+    Ok((report, failures))
+}
+
+/// Shows the rows of two ASCII screens which differ, with a `^` under each
+/// differing pixel.
+pub fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let size = |screen: &[&str]| format!("{}x{}", screen.first().map_or(0, |row| row.len()), screen.len());
+    let mut report = String::new();
+    if size(&expected) != size(&actual) {
+        report += &format!("  expected a {} screen, got {}\n", size(&expected), size(&actual));
+    }
+
+    let rows: Vec<usize> = (0..expected.len().max(actual.len()))
+        .filter(|&row| expected.get(row) != actual.get(row))
+        .collect();
+    for &row in rows.iter().take(MAX_DIFF_ROWS) {
+        let expected = expected.get(row).cloned().unwrap_or("");
+        let actual = actual.get(row).cloned().unwrap_or("");
+        let marks: String = (0..expected.len().max(actual.len()))
+            .map(|column| if expected.as_bytes().get(column) == actual.as_bytes().get(column) { ' ' } else { '^' })
+            .collect();
+        report += &format!("  row {:>2} expected {}\n         actual   {}\n                  {}\n", row, expected, actual, marks.trim_end());
+    }
+    if rows.len() > MAX_DIFF_ROWS {
+        report += &format!("  ... and {} more rows\n", rows.len() - MAX_DIFF_ROWS);
+    }
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use conformance;
+    use std::path::Path;
+
+    #[test]
+    fn test_diff_marks_pixels() {
+        let diff = conformance::diff("....\n.##.\n", "....\n.#.#\n");
+        assert_eq!(diff, "  row  1 expected .##.\n         actual   .#.#\n                    ^^\n");
+        assert!(conformance::diff("..\n", "....\n....\n").starts_with("  expected a 2x1 screen, got 4x2\n"));
+    }
+
+    /// The ROMs in tests/conformance. Run
+    /// `cargo run -- conformance tests/conformance --bless` after an
+    /// intended change to the screens they end on.
+    #[test]
+    fn test_conformance_roms() {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("conformance");
+        let (report, failures) = conformance::run_directory(&directory, false).unwrap();
+        assert!(failures == 0, "\n{}", report);
+    }
+}
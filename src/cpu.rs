@@ -1,20 +1,23 @@
-extern crate rand;
-use self::rand::Rng;
-use std::num::Wrapping;
-
 use memory::Byte;
 use memory::Word;
 use memory::Memory;
 
-use display::Display;
+use display::Video;
 use display::Sprite;
 
+use input::Keypad;
+
 use emustate;
-use decoder::Instruction;
+use decoder::{Instruction, RegisterRegister};
+use error::EmulatorError;
+use input::KEY_COUNT;
+use quirks::{Quirks, LoadStore};
+use platform::Platform;
+use audio::Pattern;
+use rng::Rng;
+use savestate::{StateWriter, StateReader, SaveStateError};
 use util;
 
-use std::mem;
-
 #[derive(Debug)]
 pub struct Cpu {
      v_registers : Vec<Byte>,
@@ -22,7 +25,15 @@ pub struct Cpu {
      stack_pointer: Word,
      sound_timer: Byte,
      delay_time: Byte,
-     program_counter: Word   
+     program_counter: Word,
+     quirks: Quirks,
+     platform: Platform,
+     waiting_for_vblank: bool,
+     rpl_flags: [Byte; 16],
+     halted: bool,
+     audio_buffer: Option<[Byte; 16]>,
+     pitch: Byte,
+     rng: Rng
 }
 
 impl Cpu {
@@ -33,10 +44,107 @@ impl Cpu {
             stack_pointer: Word::new_from_full(0),
             sound_timer: 0,
             delay_time: 0,
-            program_counter: Word::new_from_full(emustate::PC_START)
+            program_counter: Word::new_from_full(emustate::PC_START),
+            quirks: Quirks::default(),
+            platform: Platform::default(),
+            waiting_for_vblank: false,
+            rpl_flags: [0; 16],
+            halted: false,
+            audio_buffer: None,
+            pitch: 64,
+            rng: Rng::from_entropy()
         }
     }
 
+    /// Restarts the `RND` sequence from `seed`, making runs reproducible.
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    /// Replaces the generator behind `RND`, e.g. with a fixed sequence.
+    pub fn set_rng(&mut self, rng: Rng) {
+        self.rng = rng;
+    }
+
+    pub fn write_state(&self, writer: &mut StateWriter) {
+        writer.bytes(&self.v_registers);
+        writer.word(&self.i_register);
+        writer.word(&self.stack_pointer);
+        writer.u8(self.sound_timer);
+        writer.u8(self.delay_time);
+        writer.word(&self.program_counter);
+        writer.quirks(&self.quirks);
+        writer.platform(self.platform);
+        writer.bool(self.waiting_for_vblank);
+        writer.bytes(&self.rpl_flags);
+        writer.bool(self.halted);
+        writer.bool(self.audio_buffer.is_some());
+        writer.bytes(&self.audio_buffer.unwrap_or([0; 16]));
+        writer.u8(self.pitch);
+        self.rng.write_state(writer);
+    }
+
+    pub fn read_state(reader: &mut StateReader) -> Result<Cpu, SaveStateError> {
+        let v_registers = reader.bytes()?.to_vec();
+        if v_registers.len() != 16 {
+            return Err(SaveStateError::Invalid("register count"));
+        }
+        let mut cpu = Cpu {
+            v_registers,
+            i_register: reader.word()?,
+            stack_pointer: reader.word()?,
+            sound_timer: reader.u8()?,
+            delay_time: reader.u8()?,
+            program_counter: reader.word()?,
+            quirks: reader.quirks()?,
+            platform: reader.platform()?,
+            waiting_for_vblank: reader.bool()?,
+            ..Cpu::new()
+        };
+        cpu.rpl_flags = sixteen_bytes(reader.bytes()?)?;
+        cpu.halted = reader.bool()?;
+        let has_audio = reader.bool()?;
+        let audio_buffer = sixteen_bytes(reader.bytes()?)?;
+        cpu.audio_buffer = if has_audio { Some(audio_buffer) } else { None };
+        cpu.pitch = reader.u8()?;
+        // Older states didn't keep the generator, so they get a fresh seed.
+        if reader.version() >= 2 {
+            cpu.rng = Rng::read_state(reader)?;
+        }
+        Ok(cpu)
+    }
+
+    /// The XO-CHIP audio pattern, once the program has loaded one.
+    pub fn audio_pattern(&self) -> Option<Pattern> {
+        self.audio_buffer.map(|buffer| Pattern { buffer, pitch: self.pitch })
+    }
+
+    pub fn platform(&self) -> Platform {
+        self.platform
+    }
+
+    pub fn set_platform(&mut self, platform: Platform) {
+        self.platform = platform;
+    }
+
+    /// True once the program has run the SUPER-CHIP `EXIT` instruction.
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn quirks(&self) -> &Quirks {
+        &self.quirks
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// True after a `DRW` with the display wait quirk, until the next frame.
+    pub fn waiting_for_vblank(&self) -> bool {
+        self.waiting_for_vblank
+    }
+
     pub fn program_counter_mut(&mut self) -> &mut Word {
         &mut self.program_counter
     }
@@ -45,6 +153,35 @@ impl Cpu {
         &self.program_counter
     }
 
+    pub fn i_register(&self) -> &Word {
+        &self.i_register
+    }
+
+    pub fn set_i_register(&mut self, value: Word) {
+        self.i_register = value;
+    }
+
+    pub fn delay_timer(&self) -> Byte {
+        self.delay_time
+    }
+
+    pub fn set_delay_timer(&mut self, value: Byte) {
+        self.delay_time = value;
+    }
+
+    pub fn sound_timer(&self) -> Byte {
+        self.sound_timer
+    }
+
+    pub fn set_sound_timer(&mut self, value: Byte) {
+        self.sound_timer = value;
+    }
+
+    /// The buzzer sounds for as long as the sound timer is non-zero.
+    pub fn sound_active(&self) -> bool {
+        self.sound_timer > 0
+    }
+
     pub fn get_reg(&self, register: Byte) -> Byte {
         assert!((register as usize) < self.v_registers.len());
 
@@ -57,168 +194,158 @@ impl Cpu {
         self.v_registers[register as usize] = value;
     }
 
-    pub fn get_mut_reg(&mut self, register: Byte) -> &mut u8 {
-        if let Some(current) = self.v_registers.get_mut(register as usize) {
-            current 
-        } else {
-            error!("Fatal: tried to access non-existent register");
-            panic!();
-        }
+    pub fn get_mut_reg(&mut self, register: Byte) -> Option<&mut u8> {
+        self.v_registers.get_mut(register as usize)
     }
 
-    pub fn run_instruction(&mut self, instruction: Instruction, memory: &mut Memory, display: &mut Display, keys: &[bool]) {
+    /// Counts the delay and sound timers down by one tick. This is the start
+    /// of a new frame, so also ends any wait for vblank.
+    pub fn tick_timers(&mut self) {
+        self.waiting_for_vblank = false;
         if self.delay_time > 0 {
             self.delay_time -= 1;
         }
         if self.sound_timer > 0 {
-            display.play_audio();
             self.sound_timer -= 1;
-        } else {
-            display.pause_audio();
         }
+    }
+
+    /// Executes an instruction which has already been fetched, so the program
+    /// counter points past it.
+    pub fn run_instruction(&mut self, instruction: Instruction, memory: &mut Memory, video: &mut dyn Video, keys: &Keypad) -> Result<(), EmulatorError> {
+        let pc = self.program_counter.full().wrapping_sub(2);
+        let fault = move |error| EmulatorError::from_memory(error, pc);
+
         match instruction {
             Instruction::CLS => {
-                display.clear_screen();
+                video.clear();
             }
             Instruction::RET => {
-                if let Some(value) = memory.pop_stack() {
-                    self.program_counter = value;
-                    self.stack_pointer -= 1;
-                } else {
-                    error!("Fatal: attempted to pop empty stack");
-                    panic!();
-                }
+                self.program_counter = memory.pop_stack().map_err(fault)?;
+                self.stack_pointer -= 1;
             },
             Instruction::JUMP(address) => {
                 self.program_counter = address;
             },
             Instruction::CALL(address) => {
-                memory.push_stack(mem::replace(&mut self.program_counter, address));
+                memory.push_stack(self.program_counter.clone()).map_err(fault)?;
+                self.program_counter = address;
                 self.stack_pointer += 1;
             },
             Instruction::SE(reg_val) => {
                 if self.get_reg(reg_val.register) == reg_val.value {
-                    self.program_counter += 2;
+                    self.skip_next_instruction(memory);
                 }
             },
             Instruction::SNE(reg_val) => {
                 if self.get_reg(reg_val.register) != reg_val.value {
-                    self.program_counter += 2;
+                    self.skip_next_instruction(memory);
                 }
             },
             Instruction::SER(registers) => {
                 if self.get_reg(registers.first_reg) == self.get_reg(registers.second_reg) {
-                    self.program_counter += 2;
+                    self.skip_next_instruction(memory);
                 }
             }
             Instruction::LD(reg_val) => {
                 self.set_reg(reg_val.register, reg_val.value);
             },
             Instruction::ADD(reg_val) => {
-                let current = self.get_reg(reg_val.register);
-                let new_val = current as u16 + reg_val.value as u16;
-                if new_val > 255 {
-                    self.set_reg(0xF, 1);
-                } else {
-                    self.set_reg(0xF, 0);
-                }
-                let new_val = Wrapping(self.get_reg(reg_val.register)) + Wrapping(reg_val.value);
-                self.set_reg(reg_val.register, new_val.0);
+                // Unlike ADDR, adding a constant never changes VF.
+                let value = self.get_reg(reg_val.register).wrapping_add(reg_val.value);
+                self.set_reg(reg_val.register, value);
             },
             Instruction::LDR(registers) => {
                 let value = self.get_reg(registers.second_reg);
                 self.set_reg(registers.first_reg, value);
             },
             Instruction::ORR(registers) => {
-                let value = self.get_reg(registers.second_reg);
-                let current = self.get_mut_reg(registers.first_reg);
-                *current |= value;
+                let value = self.get_reg(registers.first_reg) | self.get_reg(registers.second_reg);
+                self.set_reg(registers.first_reg, value);
+                self.reset_vf();
             },
             Instruction::ANDR(registers) => {
-                let value = self.get_reg(registers.second_reg);
-                let current = self.get_mut_reg(registers.first_reg);
-                *current &= value;
+                let value = self.get_reg(registers.first_reg) & self.get_reg(registers.second_reg);
+                self.set_reg(registers.first_reg, value);
+                self.reset_vf();
             },
             Instruction::XORR(registers) => {
-                let value = self.get_reg(registers.second_reg);
-                let current = self.get_mut_reg(registers.first_reg);
-                *current ^= value;
+                let value = self.get_reg(registers.first_reg) ^ self.get_reg(registers.second_reg);
+                self.set_reg(registers.first_reg, value);
+                self.reset_vf();
             },
+            // The arithmetic and shift instructions write VF after the
+            // result, so when Vx is VF it ends up holding the flag.
             Instruction::ADDR(registers) => {
-                let new_val = self.get_reg(registers.first_reg) as u16 + self.get_reg(registers.second_reg) as u16;
-                if new_val > 255 {
-                    self.set_reg(0xF, 1);
-                } else {
-                    self.set_reg(0xF, 0);
-                }
-                let new_val = Wrapping(self.get_reg(registers.first_reg)) + Wrapping(self.get_reg(registers.second_reg));
-                self.set_reg(registers.first_reg, new_val.0);
+                let (value, carry) = self.get_reg(registers.first_reg).overflowing_add(self.get_reg(registers.second_reg));
+                self.set_reg(registers.first_reg, value);
+                self.set_reg(0xF, carry as Byte);
             },
             Instruction::SUBR(registers) => {
-                let reg1 = self.get_reg(registers.first_reg);
-                let reg2 = self.get_reg(registers.second_reg);
-
-                if reg1 > reg2 {
-                    self.set_reg(0xF, 1);
-                } else {
-                    self.set_reg(0xF, 0);
-                }
-
-                self.set_reg(registers.first_reg, (Wrapping(reg1) - Wrapping(reg2)).0);
+                let (value, borrow) = self.get_reg(registers.first_reg).overflowing_sub(self.get_reg(registers.second_reg));
+                self.set_reg(registers.first_reg, value);
+                self.set_reg(0xF, !borrow as Byte);
             },
-            Instruction::SHR(reg) => {
-                let current = self.get_reg(reg);
+            Instruction::SHR(registers) => {
+                let current = self.shift_source(&registers);
+                self.set_reg(registers.first_reg, current >> 1);
                 self.set_reg(0xF, current & 0b00000001);
-                self.set_reg(reg, current >> 1);
             },
             Instruction::SUBNR(registers) => {
-                let reg1 = self.get_reg(registers.first_reg);
-                let reg2 = self.get_reg(registers.second_reg);
-
-                if reg2 > reg1 {
-                    self.set_reg(0xF, 1);
-                } else {
-                    self.set_reg(0xF, 0);
-                }
-                self.set_reg(registers.first_reg, (Wrapping(reg2) - Wrapping(reg1)).0)
+                let (value, borrow) = self.get_reg(registers.second_reg).overflowing_sub(self.get_reg(registers.first_reg));
+                self.set_reg(registers.first_reg, value);
+                self.set_reg(0xF, !borrow as Byte);
             },
-            Instruction::SHL(reg) => {
-                let current = self.get_reg(reg);
+            Instruction::SHL(registers) => {
+                let current = self.shift_source(&registers);
+                self.set_reg(registers.first_reg, current << 1);
                 self.set_reg(0xF, current >> 7);
-                self.set_reg(reg, current << 1);
             },
             Instruction::SNER(registers) => {
                 if self.get_reg(registers.first_reg) != self.get_reg(registers.second_reg) {
-                    self.program_counter += 2;
+                    self.skip_next_instruction(memory);
                 }
             },
             Instruction::LDI(address) => {
                 self.i_register = address;
             },
             Instruction::JUMPV0(address) => {
-                self.program_counter = address + self.get_reg(0) as u16;
+                let register = if self.quirks.jump { address.high() & 0x0F } else { 0 };
+                self.program_counter = address + self.get_reg(register) as u16;
             },
             Instruction::RND(reg_val) => {
-                let mut rng = rand::thread_rng();
-                let rand = rng.gen_range(0, 256) as Byte;
-                self.set_reg(reg_val.register, rand & reg_val.value);
+                let random = self.rng.next_byte();
+                self.set_reg(reg_val.register, random & reg_val.value);
             },
             Instruction::DRW(reg_nibble) => {
                 let x = self.get_reg(reg_nibble.first_reg);
                 let y = self.get_reg(reg_nibble.second_reg);
-                let sprite = Sprite::new(memory.read_slice(&self.i_register, reg_nibble.nibble));
-                self.set_reg(0xF, display.draw_sprite(x, y, sprite));
+                let planes = video.framebuffer().selected_plane_count() as Byte;
+                let sprite = if reg_nibble.nibble == 0 && self.platform != Platform::Chip8 {
+                    Sprite::wide(&memory.read_slice(&self.i_register, 32 * planes).map_err(fault)?)
+                } else {
+                    Sprite::new(&memory.read_slice(&self.i_register, reg_nibble.nibble * planes).map_err(fault)?)
+                };
+                let collision = video.draw_sprite(x, y, &sprite, &self.quirks);
+                self.set_reg(0xF, collision);
+                self.waiting_for_vblank = self.quirks.display_wait;
             },
             Instruction::SKP(reg) => {
                 let keycode = self.get_reg(reg);
-                if keys[keycode as usize] {
-                    self.program_counter += 2;
+                if keycode as usize >= KEY_COUNT {
+                    return Err(EmulatorError::InvalidKey { pc, key: keycode });
+                }
+                if keys.is_pressed(keycode) {
+                    self.skip_next_instruction(memory);
                 }
             },
             Instruction::SKNP(reg) => {
                 let keycode = self.get_reg(reg);
-                if !keys[keycode as usize] {
-                    self.program_counter += 2;
+                if keycode as usize >= KEY_COUNT {
+                    return Err(EmulatorError::InvalidKey { pc, key: keycode });
+                }
+                if !keys.is_pressed(keycode) {
+                    self.skip_next_instruction(memory);
                 }
             },
             Instruction::LDVDT(reg) => {
@@ -226,20 +353,17 @@ impl Cpu {
                 self.set_reg(reg, delay);
             },
             Instruction::LDK(reg) => {
-                for (i, key) in keys.iter().enumerate() {
-                    if *key {
-                        self.set_reg(reg, i as u8);
-                        return;
-                    }
+                if let Some(key) = keys.first_pressed() {
+                    self.set_reg(reg, key);
+                } else {
+                    self.program_counter -= 2;
                 }
-                self.program_counter -= 2;
             },
             Instruction::LDDTV(reg) => {
                 self.delay_time = self.get_reg(reg);
             },
             Instruction::LDSTV(reg) => {
                 self.sound_timer = self.get_reg(reg);
-                display.play_audio();
             },
             Instruction::ADDI(reg) => {
                 let i_val = self.i_register.clone();
@@ -254,35 +378,663 @@ impl Cpu {
                 let mut digits : Vec<Byte> = Vec::with_capacity(3);
                 util::get_digits(val as u16, &mut digits);
                 
-                let mut address = self.i_register.clone();
-
-                for digit in digits {
-                    memory.write(&address, digit);
-                    address += 1;
+                for (offset, digit) in digits.into_iter().enumerate() {
+                    let address = self.i_register.clone() + offset as u16;
+                    memory.write(&address, digit).map_err(fault)?;
                 }
-
-                memory.print_mem_section(address.full() - 3, address.full());
             },
             Instruction::STARR(reg) => {
-                let mut address = self.i_register.clone();
                 for n in 0..reg + 1 {
-                    memory.write(&address, self.get_reg(n));
-                    address += 1;
+                    let address = self.i_register.clone() + n as u16;
+                    memory.write(&address, self.get_reg(n)).map_err(fault)?;
                 }
-
-                memory.print_mem_section(address.full() - (reg + 1) as u16, address.full());
+                self.advance_i_after_load_store(reg);
             },
             Instruction::LDARR(reg) => {
-                let mut address = self.i_register.clone();
                 for n in 0..reg + 1 {
-                    self.set_reg(n, memory.read(&address));
-                    address += 1;
+                    let address = self.i_register.clone() + n as u16;
+                    self.set_reg(n, memory.read(&address).map_err(fault)?);
                 }
+                self.advance_i_after_load_store(reg);
+            },
+            Instruction::SCD(lines) => {
+                video.framebuffer_mut().scroll_down(lines as usize);
+            },
+            Instruction::SCR => {
+                video.framebuffer_mut().scroll_right(4);
+            },
+            Instruction::SCL => {
+                video.framebuffer_mut().scroll_left(4);
+            },
+            Instruction::EXIT => {
+                self.halted = true;
+            },
+            Instruction::LOW => {
+                video.framebuffer_mut().set_hires(false);
+            },
+            Instruction::HIGH => {
+                video.framebuffer_mut().set_hires(true);
+            },
+            Instruction::LDHF(reg) => {
+                let font_code = self.get_reg(reg);
+                self.i_register = memory.get_big_font(font_code);
+            },
+            Instruction::STRPL(reg) => {
+                for n in 0..reg + 1 {
+                    self.rpl_flags[n as usize] = self.get_reg(n);
+                }
+            },
+            Instruction::LDRPL(reg) => {
+                for n in 0..reg + 1 {
+                    let flag = self.rpl_flags[n as usize];
+                    self.set_reg(n, flag);
+                }
+            },
+            Instruction::SCU(lines) => {
+                video.framebuffer_mut().scroll_up(lines as usize);
+            },
+            Instruction::SAVERNG(registers) => {
+                for (offset, register) in register_range(&registers).enumerate() {
+                    let address = self.i_register.clone() + offset as u16;
+                    memory.write(&address, self.get_reg(register)).map_err(fault)?;
+                }
+            },
+            Instruction::LOADRNG(registers) => {
+                for (offset, register) in register_range(&registers).enumerate() {
+                    let address = self.i_register.clone() + offset as u16;
+                    self.set_reg(register, memory.read(&address).map_err(fault)?);
+                }
+            },
+            Instruction::LDILONG => {
+                let high = memory.peek(&self.program_counter).map_err(fault)?;
+                let low = memory.peek(&(self.program_counter.clone() + 1)).map_err(fault)?;
+                self.i_register = Word::new_from_bytes(high, low);
+                self.program_counter += 2;
+            },
+            Instruction::PLANE(planes) => {
+                video.framebuffer_mut().select_planes(planes);
+            },
+            Instruction::AUDIO => {
+                let mut buffer = [0; 16];
+                buffer.copy_from_slice(&memory.read_slice(&self.i_register, 16).map_err(fault)?);
+                self.audio_buffer = Some(buffer);
+            },
+            Instruction::PITCH(reg) => {
+                self.pitch = self.get_reg(reg);
             },
             Instruction::Unknown(opcode) => {
-                error!("Fatal: unknown opcode: {}", opcode);
-                panic!();
+                return Err(EmulatorError::UnknownOpcode { pc, opcode: opcode.full() });
             }
         }
+
+        Ok(())
+    }
+
+    /// Moves past the next instruction, which on XO-CHIP may be the four byte
+    /// `LD I, long`.
+    fn skip_next_instruction(&mut self, memory: &Memory) {
+        let next = memory.peek(&self.program_counter).ok();
+        let following = memory.peek(&(self.program_counter.clone() + 1)).ok();
+        if self.platform == Platform::XoChip && next == Some(0xF0) && following == Some(0x00) {
+            self.program_counter += 4;
+        } else {
+            self.program_counter += 2;
+        }
+    }
+
+    /// The value `SHR`/`SHL` shift: Vx itself, or Vy on the COSMAC VIP.
+    fn shift_source(&self, registers: &RegisterRegister) -> Byte {
+        if self.quirks.shift {
+            self.get_reg(registers.first_reg)
+        } else {
+            self.get_reg(registers.second_reg)
+        }
+    }
+
+    fn reset_vf(&mut self) {
+        if self.quirks.vf_reset {
+            self.set_reg(0xF, 0);
+        }
     }
-}
\ No newline at end of file
+
+    fn advance_i_after_load_store(&mut self, last_register: Byte) {
+        let increment = match self.quirks.load_store {
+            LoadStore::Increment => last_register as u16 + 1,
+            LoadStore::IncrementByX => last_register as u16,
+            LoadStore::Unchanged => return
+        };
+        self.i_register += increment;
+    }
+}
+
+/// The registers named by `save`/`load vx - vy`, in order from x to y, which
+/// counts downwards if x > y.
+fn register_range(registers: &RegisterRegister) -> Box<dyn Iterator<Item = Byte>> {
+    let (first, second) = (registers.first_reg, registers.second_reg);
+    if first <= second {
+        Box::new(first..second + 1)
+    } else {
+        Box::new((second..first + 1).rev())
+    }
+}
+
+fn sixteen_bytes(bytes: &[Byte]) -> Result<[Byte; 16], SaveStateError> {
+    let mut array = [0; 16];
+    if bytes.len() != array.len() {
+        return Err(SaveStateError::Invalid("buffer length"));
+    }
+    array.copy_from_slice(bytes);
+    Ok(array)
+}
+
+impl Default for Cpu {
+    fn default() -> Cpu {
+        Cpu::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cpu::Cpu;
+    use decoder;
+    use display::Framebuffer;
+    use error::EmulatorError;
+    use font::{FONTS, BIG_FONTS};
+    use input::Keypad;
+    use memory::{Memory, Word, BIG_FONT_START, STACK_DEPTH};
+    use platform::Platform;
+    use quirks::{Quirks, LoadStore};
+    use rng::Rng;
+
+    /// A CPU with its own memory and in-memory framebuffer, so single
+    /// instructions can be run without the rest of the machine.
+    struct Machine {
+        cpu: Cpu,
+        memory: Memory,
+        framebuffer: Framebuffer,
+        keys: Keypad
+    }
+
+    impl Machine {
+        fn new(quirks: Quirks) -> Machine {
+            let mut machine = Machine::with_platform(Platform::Chip8);
+            machine.cpu.set_quirks(quirks);
+            machine
+        }
+
+        fn with_platform(platform: Platform) -> Machine {
+            let mut cpu = Cpu::new();
+            cpu.set_platform(platform);
+            cpu.set_quirks(platform.default_quirks());
+            let mut memory = Memory::new(platform.memory_size());
+            memory.write_slice(&Word::new_from_full(0), &FONTS).unwrap();
+            memory.write_slice(&Word::new_from_full(BIG_FONT_START), &BIG_FONTS).unwrap();
+            Machine { cpu, memory, framebuffer: Framebuffer::new(), keys: Keypad::new() }
+        }
+
+        /// Runs `opcode` as though it had just been fetched from PC.
+        fn run(&mut self, opcode: u16) -> Result<(), EmulatorError> {
+            *self.cpu.program_counter_mut() += 2;
+            let instruction = decoder::decode(Word::new_from_full(opcode), self.cpu.platform());
+            self.cpu.run_instruction(instruction, &mut self.memory, &mut self.framebuffer, &self.keys)
+        }
+
+        fn set(&mut self, registers: &[(u8, u8)]) {
+            for &(register, value) in registers {
+                self.cpu.set_reg(register, value);
+            }
+        }
+
+        fn reg(&self, register: u8) -> u8 {
+            self.cpu.get_reg(register)
+        }
+
+        fn pc(&self) -> u16 {
+            self.cpu.program_counter().full()
+        }
+
+        fn i(&self) -> u16 {
+            self.cpu.i_register().full()
+        }
+
+        fn set_i(&mut self, address: u16) {
+            self.cpu.set_i_register(Word::new_from_full(address));
+        }
+
+        fn byte(&self, address: u16) -> u8 {
+            self.memory.peek(&Word::new_from_full(address)).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_cls_jump_call_ret() {
+        let mut machine = Machine::new(Quirks::default());
+        machine.set_i(0);
+        machine.run(0xD015).unwrap();
+        assert!(machine.framebuffer.pixel(0, 0));
+        machine.run(0x00E0).unwrap();
+        assert!(!machine.framebuffer.pixel(0, 0));
+
+        machine.run(0x2400).unwrap();
+        assert_eq!(machine.pc(), 0x400);
+        assert_eq!(machine.memory.stack(), &[Word::new_from_full(0x206)]);
+        machine.run(0x00EE).unwrap();
+        assert_eq!(machine.pc(), 0x206);
+        assert_eq!(machine.run(0x00EE), Err(EmulatorError::StackUnderflow { pc: 0x206 }));
+
+        machine.run(0x1ABC).unwrap();
+        assert_eq!(machine.pc(), 0xABC);
+
+        for _ in 0..STACK_DEPTH {
+            machine.run(0x2300).unwrap();
+        }
+        assert_eq!(machine.run(0x2300), Err(EmulatorError::StackOverflow { pc: 0x300 }));
+    }
+
+    #[test]
+    fn test_skips() {
+        let mut machine = Machine::new(Quirks::default());
+        machine.set(&[(0, 0x12), (1, 0x12), (2, 0x34)]);
+        let skipped = |machine: &mut Machine, opcode| {
+            let start = machine.pc();
+            machine.run(opcode).unwrap();
+            machine.pc() - start == 4
+        };
+
+        assert!(skipped(&mut machine, 0x3012));
+        assert!(!skipped(&mut machine, 0x3013));
+        assert!(skipped(&mut machine, 0x4013));
+        assert!(!skipped(&mut machine, 0x4012));
+        assert!(skipped(&mut machine, 0x5010));
+        assert!(!skipped(&mut machine, 0x5020));
+        assert!(skipped(&mut machine, 0x9020));
+        assert!(!skipped(&mut machine, 0x9010));
+    }
+
+    #[test]
+    fn test_xochip_skips_over_long_load() {
+        let mut machine = Machine::with_platform(Platform::XoChip);
+        machine.memory.write_slice(&Word::new_from_full(0x202), &[0xF0, 0x00, 0x12, 0x34]).unwrap();
+        machine.run(0x3000).unwrap();
+        assert_eq!(machine.pc(), 0x206);
+
+        let mut machine = Machine::new(Quirks::default());
+        machine.memory.write_slice(&Word::new_from_full(0x202), &[0xF0, 0x00, 0x12, 0x34]).unwrap();
+        machine.run(0x3000).unwrap();
+        assert_eq!(machine.pc(), 0x204);
+    }
+
+    #[test]
+    fn test_load_and_add_constant() {
+        let mut machine = Machine::new(Quirks::default());
+        machine.run(0x63AB).unwrap();
+        assert_eq!(machine.reg(3), 0xAB);
+
+        // Wraps, and leaves VF alone even when it overflows.
+        machine.set(&[(0xF, 0x55)]);
+        machine.run(0x7360).unwrap();
+        assert_eq!(machine.reg(3), 0x0B);
+        assert_eq!(machine.reg(0xF), 0x55);
+
+        machine.run(0x7FFF).unwrap();
+        assert_eq!(machine.reg(0xF), 0x54);
+
+        machine.run(0x8430).unwrap();
+        assert_eq!(machine.reg(4), 0x0B);
+    }
+
+    #[test]
+    fn test_logic_and_vf_reset_quirk() {
+        for &(opcode, result) in &[(0x8011, 0xFC), (0x8012, 0x30), (0x8013, 0xCC)] {
+            let mut machine = Machine::new(Quirks::default());
+            machine.set(&[(0, 0xF0), (1, 0x3C), (0xF, 7)]);
+            machine.run(opcode).unwrap();
+            assert_eq!(machine.reg(0), result);
+            assert_eq!(machine.reg(0xF), 7);
+
+            let mut machine = Machine::new(Quirks::cosmac_vip());
+            machine.set(&[(0, 0xF0), (1, 0x3C), (0xF, 7)]);
+            machine.run(opcode).unwrap();
+            assert_eq!(machine.reg(0), result);
+            assert_eq!(machine.reg(0xF), 0);
+        }
+    }
+
+    #[test]
+    fn test_add_registers() {
+        let mut machine = Machine::new(Quirks::default());
+        machine.set(&[(0, 0xF0), (1, 0x20)]);
+        machine.run(0x8014).unwrap();
+        assert_eq!((machine.reg(0), machine.reg(0xF)), (0x10, 1));
+        machine.run(0x8014).unwrap();
+        assert_eq!((machine.reg(0), machine.reg(0xF)), (0x30, 0));
+
+        // The carry wins over the sum when Vx is VF.
+        machine.set(&[(0xF, 0xFF), (1, 0x01)]);
+        machine.run(0x8F14).unwrap();
+        assert_eq!(machine.reg(0xF), 1);
+        machine.set(&[(0xF, 0x10)]);
+        machine.run(0x8F14).unwrap();
+        assert_eq!(machine.reg(0xF), 0);
+    }
+
+    #[test]
+    fn test_subtract_registers() {
+        let mut machine = Machine::new(Quirks::default());
+        machine.set(&[(0, 5), (1, 3)]);
+        machine.run(0x8015).unwrap();
+        assert_eq!((machine.reg(0), machine.reg(0xF)), (2, 1));
+        machine.run(0x8015).unwrap();
+        assert_eq!((machine.reg(0), machine.reg(0xF)), (0xFF, 0));
+
+        // Equal values don't borrow.
+        machine.set(&[(0, 3)]);
+        machine.run(0x8015).unwrap();
+        assert_eq!((machine.reg(0), machine.reg(0xF)), (0, 1));
+
+        machine.set(&[(0xF, 3), (1, 5)]);
+        machine.run(0x8F15).unwrap();
+        assert_eq!(machine.reg(0xF), 0);
+        machine.set(&[(0xF, 5), (1, 3)]);
+        machine.run(0x8F15).unwrap();
+        assert_eq!(machine.reg(0xF), 1);
+    }
+
+    #[test]
+    fn test_subtract_reversed() {
+        let mut machine = Machine::new(Quirks::default());
+        machine.set(&[(0, 3), (1, 5)]);
+        machine.run(0x8017).unwrap();
+        assert_eq!((machine.reg(0), machine.reg(0xF)), (2, 1));
+        machine.set(&[(0, 5), (1, 3)]);
+        machine.run(0x8017).unwrap();
+        assert_eq!((machine.reg(0), machine.reg(0xF)), (0xFE, 0));
+        machine.set(&[(0, 3), (1, 3)]);
+        machine.run(0x8017).unwrap();
+        assert_eq!((machine.reg(0), machine.reg(0xF)), (0, 1));
+
+        machine.set(&[(0xF, 5), (1, 3)]);
+        machine.run(0x8F17).unwrap();
+        assert_eq!(machine.reg(0xF), 0);
+    }
+
+    #[test]
+    fn test_shifts_and_shift_quirk() {
+        // In place: Vy is ignored.
+        let mut machine = Machine::new(Quirks::default());
+        machine.set(&[(0, 0x81), (1, 0x02)]);
+        machine.run(0x8016).unwrap();
+        assert_eq!((machine.reg(0), machine.reg(0xF)), (0x40, 1));
+        machine.run(0x801E).unwrap();
+        assert_eq!((machine.reg(0), machine.reg(0xF)), (0x80, 0));
+        machine.run(0x801E).unwrap();
+        assert_eq!((machine.reg(0), machine.reg(0xF)), (0x00, 1));
+
+        // The COSMAC VIP shifts Vy into Vx.
+        let mut machine = Machine::new(Quirks::cosmac_vip());
+        machine.set(&[(0, 0xFF), (1, 0x81)]);
+        machine.run(0x8016).unwrap();
+        assert_eq!((machine.reg(0), machine.reg(1), machine.reg(0xF)), (0x40, 0x81, 1));
+        machine.run(0x801E).unwrap();
+        assert_eq!((machine.reg(0), machine.reg(0xF)), (0x02, 1));
+
+        // The shifted out bit wins when Vx is VF.
+        let mut machine = Machine::new(Quirks::default());
+        machine.set(&[(0xF, 0x02)]);
+        machine.run(0x8F06).unwrap();
+        assert_eq!(machine.reg(0xF), 0);
+        machine.set(&[(0xF, 0x81)]);
+        machine.run(0x8F0E).unwrap();
+        assert_eq!(machine.reg(0xF), 1);
+
+        let mut machine = Machine::new(Quirks::cosmac_vip());
+        machine.set(&[(0xF, 0x02)]);
+        machine.run(0x80F6).unwrap();
+        assert_eq!((machine.reg(0), machine.reg(0xF)), (0x01, 0));
+    }
+
+    #[test]
+    fn test_load_i_and_jump_quirk() {
+        let mut machine = Machine::new(Quirks::default());
+        machine.run(0xA123).unwrap();
+        assert_eq!(machine.i(), 0x123);
+
+        machine.set(&[(0, 0x10), (3, 0x20)]);
+        machine.run(0xB300).unwrap();
+        assert_eq!(machine.pc(), 0x310);
+
+        let mut machine = Machine::new(Quirks::superchip());
+        machine.set(&[(0, 0x10), (3, 0x20)]);
+        machine.run(0xB300).unwrap();
+        assert_eq!(machine.pc(), 0x320);
+    }
+
+    #[test]
+    fn test_random_is_masked() {
+        let mut machine = Machine::new(Quirks::default());
+        machine.cpu.set_rng(Rng::sequence(vec![0xAB, 0xCD]));
+        machine.run(0xC00F).unwrap();
+        machine.run(0xC1F0).unwrap();
+        assert_eq!((machine.reg(0), machine.reg(1)), (0x0B, 0xC0));
+    }
+
+    #[test]
+    fn test_draw_collision_and_edges() {
+        let mut machine = Machine::new(Quirks::default());
+        machine.set_i(0);
+        machine.run(0xD015).unwrap();
+        assert_eq!(machine.reg(0xF), 0);
+        machine.run(0xD015).unwrap();
+        assert_eq!(machine.reg(0xF), 1);
+        assert!(!machine.framebuffer.pixel(0, 0));
+
+        // The top row of "0" is four pixels wide; it wraps from x = 62, and
+        // so does a starting position off the screen.
+        machine.set(&[(0, 62), (1, 66)]);
+        machine.run(0xD025).unwrap();
+        assert!(machine.framebuffer.pixel(63, 0) && machine.framebuffer.pixel(1, 0));
+        machine.run(0x00E0).unwrap();
+        machine.run(0xD125).unwrap();
+        assert!(machine.framebuffer.pixel(2, 0) && machine.framebuffer.pixel(5, 0));
+
+        let mut machine = Machine::new(Quirks::cosmac_vip());
+        machine.set_i(0);
+        machine.set(&[(0, 62)]);
+        machine.run(0xD015).unwrap();
+        assert!(machine.framebuffer.pixel(63, 0) && !machine.framebuffer.pixel(0, 0));
+        assert!(machine.cpu.waiting_for_vblank());
+        machine.cpu.tick_timers();
+        assert!(!machine.cpu.waiting_for_vblank());
+
+        machine.set_i(0xFFE);
+        assert_eq!(machine.run(0xD005), Err(EmulatorError::MemoryFault { pc: 0x202, address: 0x1000 }));
+    }
+
+    #[test]
+    fn test_draw_superchip_sprite() {
+        let mut machine = Machine::with_platform(Platform::SuperChip);
+        machine.run(0x00FF).unwrap();
+        machine.memory.write_slice(&Word::new_from_full(0x300), &[0xFF; 32]).unwrap();
+        machine.set_i(0x300);
+        machine.run(0xD000).unwrap();
+        assert!(machine.framebuffer.pixel(15, 15));
+        assert!(!machine.framebuffer.pixel(16, 0) && !machine.framebuffer.pixel(0, 16));
+
+        // On CHIP-8, DXY0 draws nothing.
+        let mut machine = Machine::new(Quirks::default());
+        machine.set_i(0x300);
+        machine.run(0xD000).unwrap();
+        assert!(!machine.framebuffer.pixel(0, 0));
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut machine = Machine::new(Quirks::default());
+        machine.keys = Keypad::with_pressed(&[0xA]);
+        machine.set(&[(0, 0xA), (1, 0xB), (2, 0x10)]);
+
+        machine.run(0xE09E).unwrap();
+        assert_eq!(machine.pc(), 0x204);
+        machine.run(0xE19E).unwrap();
+        assert_eq!(machine.pc(), 0x206);
+        machine.run(0xE1A1).unwrap();
+        assert_eq!(machine.pc(), 0x20A);
+        machine.run(0xE0A1).unwrap();
+        assert_eq!(machine.pc(), 0x20C);
+        assert_eq!(machine.run(0xE29E), Err(EmulatorError::InvalidKey { pc: 0x20C, key: 0x10 }));
+
+        machine.run(0xF30A).unwrap();
+        assert_eq!(machine.reg(3), 0xA);
+        machine.keys = Keypad::new();
+        let pc = machine.pc();
+        machine.run(0xF40A).unwrap();
+        assert_eq!(machine.pc(), pc);
+    }
+
+    #[test]
+    fn test_timers() {
+        let mut machine = Machine::new(Quirks::default());
+        machine.set(&[(0, 2), (1, 1)]);
+        machine.run(0xF015).unwrap();
+        machine.run(0xF118).unwrap();
+        assert!(machine.cpu.sound_active());
+        machine.cpu.tick_timers();
+        machine.run(0xF207).unwrap();
+        assert_eq!(machine.reg(2), 1);
+        assert!(!machine.cpu.sound_active());
+        machine.cpu.tick_timers();
+        machine.cpu.tick_timers();
+        assert_eq!(machine.cpu.delay_timer(), 0);
+    }
+
+    #[test]
+    fn test_i_arithmetic_font_and_bcd() {
+        let mut machine = Machine::new(Quirks::default());
+        machine.set_i(0xFFFF);
+        machine.set(&[(0, 2)]);
+        machine.run(0xF01E).unwrap();
+        assert_eq!(machine.i(), 1);
+
+        machine.set(&[(0, 0x1A)]);
+        machine.run(0xF029).unwrap();
+        assert_eq!(machine.i(), 0xA * 5);
+
+        machine.set_i(0x300);
+        machine.set(&[(0, 254)]);
+        machine.run(0xF033).unwrap();
+        assert_eq!((machine.byte(0x300), machine.byte(0x301), machine.byte(0x302)), (2, 5, 4));
+        assert_eq!(machine.i(), 0x300);
+
+        machine.set_i(0xFFE);
+        assert_eq!(machine.run(0xF033), Err(EmulatorError::MemoryFault { pc: 0x206, address: 0x1000 }));
+    }
+
+    #[test]
+    fn test_accesses_wrap_at_the_top_of_memory() {
+        // F000 FFFE; 60FF; F033 and F155 used to panic working out what to log,
+        // and DRW faulted where the stores wrapped.
+        let mut machine = Machine::with_platform(Platform::XoChip);
+        machine.set_i(0xFFFE);
+        machine.set(&[(0, 0xFF), (1, 0x12)]);
+        machine.run(0xF033).unwrap();
+        assert_eq!((machine.byte(0xFFFE), machine.byte(0xFFFF), machine.byte(0)), (2, 5, 5));
+
+        machine.run(0xF155).unwrap();
+        assert_eq!((machine.byte(0xFFFE), machine.byte(0xFFFF)), (0xFF, 0x12));
+
+        // Sprites wrap the same way rather than faulting.
+        machine.set_i(0xFFFE);
+        machine.set(&[(2, 0)]);
+        machine.run(0xD223).unwrap();
+        assert!(machine.framebuffer.pixel(7, 0) && machine.framebuffer.pixel(3, 1) && machine.framebuffer.pixel(5, 2));
+        assert!(!machine.framebuffer.pixel(4, 2));
+    }
+
+    #[test]
+    fn test_load_store_quirk() {
+        for &(load_store, i_after) in &[(LoadStore::Increment, 0x303), (LoadStore::IncrementByX, 0x302), (LoadStore::Unchanged, 0x300)] {
+            let mut machine = Machine::new(Quirks { load_store, ..Quirks::default() });
+            machine.set(&[(0, 1), (1, 2), (2, 3), (3, 4)]);
+            machine.set_i(0x300);
+            machine.run(0xF255).unwrap();
+            assert_eq!(machine.i(), i_after);
+            assert_eq!((machine.byte(0x300), machine.byte(0x302), machine.byte(0x303)), (1, 3, 0));
+
+            machine.set(&[(0, 0), (1, 0), (2, 0), (3, 0)]);
+            machine.set_i(0x300);
+            machine.run(0xF265).unwrap();
+            assert_eq!((machine.reg(0), machine.reg(2), machine.reg(3)), (1, 3, 0));
+            assert_eq!(machine.i(), i_after);
+        }
+    }
+
+    #[test]
+    fn test_superchip_instructions() {
+        let mut machine = Machine::with_platform(Platform::SuperChip);
+        machine.run(0x00FF).unwrap();
+        assert!(machine.framebuffer.hires());
+        machine.set_i(0);
+        machine.run(0xD011).unwrap();
+        machine.run(0x00C3).unwrap();
+        assert!(machine.framebuffer.pixel(0, 3));
+        machine.run(0x00FB).unwrap();
+        assert!(machine.framebuffer.pixel(4, 3));
+        machine.run(0x00FC).unwrap();
+        assert!(machine.framebuffer.pixel(0, 3));
+        machine.run(0x00FE).unwrap();
+        assert!(!machine.framebuffer.hires());
+
+        machine.set(&[(0, 0x12)]);
+        machine.run(0xF030).unwrap();
+        assert_eq!(machine.i(), BIG_FONT_START + 2 * 10);
+
+        machine.set(&[(0, 7), (1, 8), (2, 9)]);
+        machine.run(0xF175).unwrap();
+        machine.set(&[(0, 0), (1, 0), (2, 0)]);
+        machine.run(0xF285).unwrap();
+        assert_eq!((machine.reg(0), machine.reg(1), machine.reg(2)), (7, 8, 0));
+
+        machine.run(0x00FD).unwrap();
+        assert!(machine.cpu.halted());
+    }
+
+    #[test]
+    fn test_xochip_instructions() {
+        let mut machine = Machine::with_platform(Platform::XoChip);
+        machine.set(&[(2, 0xA), (3, 0xB), (4, 0xC)]);
+        machine.set_i(0x300);
+        machine.run(0x5242).unwrap();
+        assert_eq!((machine.byte(0x300), machine.byte(0x302)), (0xA, 0xC));
+        assert_eq!(machine.i(), 0x300);
+        // Counting down from x to y.
+        machine.run(0x5423).unwrap();
+        assert_eq!((machine.reg(4), machine.reg(3), machine.reg(2)), (0xA, 0xB, 0xC));
+
+        machine.memory.write_slice(&Word::new_from_full(0x206), &[0x12, 0x34]).unwrap();
+        machine.run(0xF000).unwrap();
+        assert_eq!((machine.i(), machine.pc()), (0x1234, 0x208));
+
+        machine.run(0xF201).unwrap();
+        assert_eq!(machine.framebuffer.planes(), 2);
+        machine.set_i(0);
+        machine.run(0xD015).unwrap();
+        assert_eq!(machine.framebuffer.pixel_value(0, 0), 2);
+        machine.run(0x00D1).unwrap();
+        assert_eq!(machine.framebuffer.pixel_value(0, 0), 2);
+        assert_eq!(machine.framebuffer.pixel_value(0, 4), 0);
+
+        machine.set_i(0x300);
+        machine.run(0xF002).unwrap();
+        machine.set(&[(0, 3)]);
+        machine.run(0xF03A).unwrap();
+        let pattern = machine.cpu.audio_pattern().unwrap();
+        assert_eq!((pattern.buffer[0], pattern.pitch), (0xA, 3));
+    }
+
+    #[test]
+    fn test_unknown_opcodes() {
+        let mut machine = Machine::new(Quirks::default());
+        assert_eq!(machine.run(0x8008), Err(EmulatorError::UnknownOpcode { pc: 0x200, opcode: 0x8008 }));
+        assert_eq!(machine.run(0x00FF), Err(EmulatorError::UnknownOpcode { pc: 0x202, opcode: 0x00FF }));
+    }
+}
@@ -0,0 +1,669 @@
+//! The interactive monitor behind `--debug`. It reads one command line at a
+//! time and leaves running the machine to the frontend, through a `step`
+//! callback that runs a single instruction and says whether to stop.
+
+use std::collections::BTreeMap;
+
+use emustate::Chip8State;
+use decoder::{self, Instruction};
+use error::EmulatorError;
+use memory::{Byte, Word, Watch, WatchHit, Access};
+use expr::Expr;
+
+const HELP: &str = "\
+break ADDR [if EXPR]  stop when PC reaches ADDR (b)
+delete ADDR (d)       remove a breakpoint
+watch ADDR [LEN]      stop when memory is written
+rwatch ADDR [LEN]     stop when memory is read
+awatch ADDR [LEN]     stop when memory is read or written
+unwatch ADDR          remove the watchpoints covering ADDR
+when EXPR             stop as soon as EXPR is true
+unwhen N              remove the Nth `when` condition
+breakpoints (bl)      list breakpoints, watchpoints and conditions
+print EXPR (p)        evaluate an expression, e.g. V3 == 0x10 && I > 0x300
+step [N] (s, n)       run N instructions (default 1)
+continue (c)          run until a breakpoint
+over (o)              step, running a CALL to the end
+out (f)               run until the current subroutine returns
+regs (r)              print the registers
+set REG VALUE         set V0-VF, I, PC, DT or ST
+dump ADDR [LEN] (x)   hex dump memory
+poke ADDR BYTE...     write bytes to memory
+stack (bt)            show the call stack
+list [ADDR] (l)       disassemble around PC or ADDR
+history               list earlier commands; !N runs one again
+quit (q, exit)        stop the emulator
+An empty line repeats the last command.
+";
+
+/// Instructions shown by `list` before and after the address.
+const LIST_CONTEXT: u16 = 4;
+const DEFAULT_DUMP_LENGTH: u16 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    V(Byte),
+    I,
+    Pc,
+    Dt,
+    St
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Break(u16, Option<Expr>),
+    Delete(u16),
+    Watch(Watch),
+    Unwatch(u16),
+    When(Expr),
+    Unwhen(usize),
+    Print(Expr),
+    Breakpoints,
+    Step(u32),
+    Continue,
+    Over,
+    Out,
+    Registers,
+    Set(Register, u16),
+    Dump(u16, u16),
+    Poke(u16, Vec<Byte>),
+    Stack,
+    List(Option<u16>),
+    History,
+    Help,
+    Quit
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or("");
+        let args: Vec<&str> = words.collect();
+        // Everything after the command name, for commands taking expressions.
+        let rest = line.trim()[name.len()..].trim();
+        let command = match (name, args.len()) {
+            ("break", 1) | ("b", 1) => Command::Break(address(args[0])?, None),
+            ("break", count) | ("b", count) if count > 2 && args[1] == "if" => {
+                let condition = rest[args[0].len()..].trim()[2..].trim();
+                Command::Break(address(args[0])?, Some(Expr::parse(condition)?))
+            },
+            ("delete", 1) | ("d", 1) => Command::Delete(address(args[0])?),
+            ("watch", 1) | ("watch", 2) => Command::Watch(watch(&args, false, true)?),
+            ("rwatch", 1) | ("rwatch", 2) => Command::Watch(watch(&args, true, false)?),
+            ("awatch", 1) | ("awatch", 2) => Command::Watch(watch(&args, true, true)?),
+            ("unwatch", 1) => Command::Unwatch(address(args[0])?),
+            ("when", count) if count > 0 => Command::When(Expr::parse(rest)?),
+            ("unwhen", 1) => Command::Unwhen(number(args[0])? as usize),
+            ("print", count) | ("p", count) if count > 0 => Command::Print(Expr::parse(rest)?),
+            ("breakpoints", 0) | ("bl", 0) => Command::Breakpoints,
+            ("step", 0) | ("s", 0) | ("n", 0) => Command::Step(1),
+            ("step", 1) | ("s", 1) | ("n", 1) => match number(args[0])? {
+                0 => return Err(String::from("the step count must be at least 1")),
+                count => Command::Step(count as u32)
+            },
+            ("continue", 0) | ("c", 0) => Command::Continue,
+            ("over", 0) | ("o", 0) => Command::Over,
+            ("out", 0) | ("f", 0) => Command::Out,
+            ("regs", 0) | ("r", 0) => Command::Registers,
+            ("set", 2) => Command::Set(register(args[0])?, number(args[1])?),
+            ("dump", 1) | ("x", 1) => Command::Dump(address(args[0])?, DEFAULT_DUMP_LENGTH),
+            ("dump", 2) | ("x", 2) => Command::Dump(address(args[0])?, number(args[1])?),
+            ("poke", count) if count >= 2 => {
+                let bytes = args[1..].iter().map(|arg| byte(arg)).collect::<Result<Vec<_>, _>>()?;
+                Command::Poke(address(args[0])?, bytes)
+            },
+            ("stack", 0) | ("bt", 0) => Command::Stack,
+            ("list", 0) | ("l", 0) => Command::List(None),
+            ("list", 1) | ("l", 1) => Command::List(Some(address(args[0])?)),
+            ("history", 0) => Command::History,
+            ("help", 0) | ("h", 0) | ("?", 0) => Command::Help,
+            ("quit", 0) | ("q", 0) | ("exit", 0) => Command::Quit,
+            _ => return Err(format!("Invalid command: `{}` (try `help`)", line.trim()))
+        };
+        Ok(command)
+    }
+}
+
+/// What the frontend should do after a command.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Reply {
+    pub output: String,
+    pub quit: bool
+}
+
+/// Why a run of instructions ended.
+enum Stop {
+    Done,
+    Breakpoint(u16),
+    Watchpoint(WatchHit, u16),
+    Condition(usize),
+    Finished,
+    Error(EmulatorError)
+}
+
+#[derive(Debug, Default)]
+pub struct Debugger {
+    breakpoints: BTreeMap<u16, Option<Expr>>,
+    conditions: Vec<Expr>,
+    history: Vec<String>
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    /// Breakpoint addresses, with the condition each one has, if any.
+    pub fn breakpoints(&self) -> &BTreeMap<u16, Option<Expr>> {
+        &self.breakpoints
+    }
+
+    /// Runs one line typed at the prompt. `step` runs a single instruction
+    /// and returns true once the frontend wants to stop, e.g. the window was
+    /// closed or the program exited.
+    pub fn execute(&mut self, line: &str, state: &mut Chip8State, step: &mut dyn FnMut(&mut Chip8State) -> Result<bool, EmulatorError>) -> Reply {
+        let line = match self.resolve(line.trim()) {
+            Ok(line) => line,
+            Err(message) => return Reply { output: message + "\n", quit: false }
+        };
+        if line.is_empty() {
+            return Reply::default();
+        }
+        if self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+
+        let mut reply = Reply::default();
+        match Command::parse(&line) {
+            Ok(command) => self.run(command, state, step, &mut reply),
+            Err(message) => reply.output = message + "\n"
+        }
+        reply
+    }
+
+    /// Expands an empty line or `!N` into the command it stands for.
+    fn resolve(&self, line: &str) -> Result<String, String> {
+        if line.is_empty() {
+            return Ok(self.history.last().cloned().unwrap_or_default());
+        }
+        if let Some(index) = line.strip_prefix('!') {
+            return index.parse::<usize>().ok()
+                .and_then(|index| self.history.get(index.wrapping_sub(1)))
+                .cloned()
+                .ok_or_else(|| format!("No command {} in the history", line));
+        }
+        Ok(line.to_string())
+    }
+
+    fn run(&mut self, command: Command, state: &mut Chip8State, step: &mut dyn FnMut(&mut Chip8State) -> Result<bool, EmulatorError>, reply: &mut Reply) {
+        let out = &mut reply.output;
+        match command {
+            Command::Break(address, condition) => {
+                match condition {
+                    Some(ref condition) => *out += &format!("Breakpoint at 0x{:03X} if {}\n", address, condition),
+                    None => *out += &format!("Breakpoint at 0x{:03X}\n", address)
+                }
+                self.breakpoints.insert(address, condition);
+            },
+            Command::Delete(address) => {
+                if self.breakpoints.remove(&address).is_some() {
+                    *out += &format!("Deleted breakpoint at 0x{:03X}\n", address);
+                } else {
+                    *out += &format!("No breakpoint at 0x{:03X}\n", address);
+                }
+            },
+            Command::Watch(watch) => {
+                *out += &format!("Watching {}\n", describe(&watch));
+                state.memory_mut().add_watch(watch);
+            },
+            Command::Unwatch(address) => {
+                match state.memory_mut().remove_watches(address) {
+                    0 => *out += &format!("No watchpoint covers 0x{:03X}\n", address),
+                    count => *out += &format!("Removed {} watchpoint(s)\n", count)
+                }
+            },
+            Command::When(condition) => {
+                *out += &format!("Condition {}: {}\n", self.conditions.len() + 1, condition);
+                self.conditions.push(condition);
+            },
+            Command::Unwhen(index) => {
+                if index >= 1 && index <= self.conditions.len() {
+                    let condition = self.conditions.remove(index - 1);
+                    *out += &format!("Removed condition {}\n", condition);
+                } else {
+                    *out += &format!("No condition {}\n", index);
+                }
+            },
+            Command::Print(expr) => {
+                let value = expr.evaluate(state);
+                *out += &format!("{} = {} (0x{:X})\n", expr, value, value);
+            },
+            Command::Breakpoints => {
+                let watches = state.memory().watches();
+                if self.breakpoints.is_empty() && watches.is_empty() && self.conditions.is_empty() {
+                    *out += "No breakpoints\n";
+                }
+                for (address, condition) in &self.breakpoints {
+                    match *condition {
+                        Some(ref condition) => *out += &format!("break 0x{:03X} if {}\n", address, condition),
+                        None => *out += &format!("break 0x{:03X}\n", address)
+                    }
+                }
+                for watch in watches {
+                    *out += &format!("watch {}\n", describe(watch));
+                }
+                for (index, condition) in self.conditions.iter().enumerate() {
+                    *out += &format!("when {}: {}\n", index + 1, condition);
+                }
+            },
+            Command::Step(count) => {
+                let stop = self.run_until(state, step, Some(count as u64), &|_| false);
+                self.report(stop, state, reply);
+            },
+            Command::Continue => {
+                let stop = self.run_until(state, step, None, &|_| false);
+                self.report(stop, state, reply);
+            },
+            Command::Over => {
+                let stop = match instruction_at(state, state.cpu().program_counter().full()) {
+                    Some(Instruction::CALL(_)) => {
+                        let depth = state.memory().stack().len();
+                        let next = state.cpu().program_counter().full().wrapping_add(2);
+                        self.run_until(state, step, None, &|state| {
+                            state.cpu().program_counter().full() == next && state.memory().stack().len() <= depth
+                        })
+                    },
+                    _ => self.run_until(state, step, Some(1), &|_| false)
+                };
+                self.report(stop, state, reply);
+            },
+            Command::Out => {
+                let depth = state.memory().stack().len();
+                if depth == 0 {
+                    *out += "Not in a subroutine\n";
+                    return;
+                }
+                let stop = self.run_until(state, step, None, &|state| state.memory().stack().len() < depth);
+                self.report(stop, state, reply);
+            },
+            Command::Registers => *out += &registers(state),
+            Command::Set(register, value) => {
+                if let Err(message) = set_register(state, register, value) {
+                    *out += &format!("{}\n", message);
+                } else {
+                    *out += &registers(state);
+                }
+            },
+            Command::Dump(address, length) => *out += &dump(state, address, length),
+            Command::Poke(address, ref bytes) => {
+                let result = state.memory_mut().write_slice(&Word::new_from_full(address), bytes);
+                match result {
+                    Ok(()) => *out += &format!("Wrote {} bytes at 0x{:03X}\n", bytes.len(), address),
+                    Err(_) => *out += &format!("0x{:03X} is outside memory\n", address)
+                }
+            },
+            Command::Stack => *out += &call_stack(state),
+            Command::List(address) => {
+                let address = address.unwrap_or_else(|| state.cpu().program_counter().full());
+                *out += &self.list(state, address);
+            },
+            Command::History => {
+                for (index, line) in self.history.iter().enumerate() {
+                    *out += &format!("{:4}  {}\n", index + 1, line);
+                }
+            },
+            Command::Help => *out += HELP,
+            Command::Quit => reply.quit = true
+        }
+    }
+
+    /// Steps until `done` is true, `limit` instructions have run, or a
+    /// breakpoint, watchpoint or condition triggers. At least one
+    /// instruction always runs, so continuing from a breakpoint moves past it.
+    fn run_until(&self, state: &mut Chip8State, step: &mut dyn FnMut(&mut Chip8State) -> Result<bool, EmulatorError>, limit: Option<u64>, done: &dyn Fn(&Chip8State) -> bool) -> Stop {
+        let mut count = 0;
+        // Forget accesses made from the prompt, e.g. by `poke`.
+        state.memory().take_hits();
+        loop {
+            let before = state.cpu().program_counter().full();
+            match step(state) {
+                Ok(true) => return Stop::Finished,
+                Ok(false) => {},
+                Err(error) => return Stop::Error(error)
+            }
+            count += 1;
+            if let Some(&hit) = state.memory().take_hits().first() {
+                return Stop::Watchpoint(hit, before);
+            }
+            if let Some(index) = self.conditions.iter().position(|condition| condition.is_true(state)) {
+                return Stop::Condition(index);
+            }
+            let pc = state.cpu().program_counter().full();
+            if done(state) || limit == Some(count) {
+                return Stop::Done;
+            }
+            match self.breakpoints.get(&pc) {
+                Some(&None) => return Stop::Breakpoint(pc),
+                Some(Some(condition)) if condition.is_true(state) => return Stop::Breakpoint(pc),
+                _ => {}
+            }
+        }
+    }
+
+    fn report(&self, stop: Stop, state: &Chip8State, reply: &mut Reply) {
+        match stop {
+            Stop::Done => {},
+            Stop::Breakpoint(address) => reply.output += &format!("Breakpoint at 0x{:03X}\n", address),
+            Stop::Watchpoint(hit, by) => {
+                let access = match hit.access {
+                    Access::Read => "read",
+                    Access::Write => "write"
+                };
+                reply.output += &format!("Watchpoint: {} of 0x{:03X} by the instruction at 0x{:03X}\n", access, hit.address, by);
+            },
+            Stop::Condition(index) => {
+                reply.output += &format!("Condition {} is true: {}\n", index + 1, self.conditions[index]);
+            },
+            Stop::Finished => {
+                reply.quit = true;
+                return;
+            },
+            Stop::Error(error) => reply.output += &format!("Stopped: {}\n", error)
+        }
+        let pc = state.cpu().program_counter().full();
+        reply.output += &self.line(state, pc, pc);
+    }
+
+    fn list(&self, state: &Chip8State, address: u16) -> String {
+        let start = address.saturating_sub(LIST_CONTEXT * 2);
+        (0..LIST_CONTEXT * 2 + 1)
+            .map(|row| start.wrapping_add(row * 2))
+            .filter(|&row| (row as usize) < state.memory().size())
+            .map(|row| self.line(state, row, state.cpu().program_counter().full()))
+            .collect()
+    }
+
+    /// One disassembled instruction, marked if it is at PC or a breakpoint.
+    fn line(&self, state: &Chip8State, address: u16, pc: u16) -> String {
+        let current = if address == pc { '>' } else { ' ' };
+        let breakpoint = if self.breakpoints.contains_key(&address) { '*' } else { ' ' };
+        match instruction_at(state, address) {
+            Some(instruction) => format!("{}{} 0x{:03X}  {}\n", current, breakpoint, address, instruction),
+            None => format!("{}{} 0x{:03X}  ??\n", current, breakpoint, address)
+        }
+    }
+}
+
+fn instruction_at(state: &Chip8State, address: u16) -> Option<Instruction> {
+    let memory = state.memory();
+    let high = memory.peek(&Word::new_from_full(address)).ok()?;
+    let low = memory.peek(&Word::new_from_full(address.wrapping_add(1))).ok()?;
+    Some(decoder::decode(Word::new_from_bytes(high, low), state.cpu().platform()))
+}
+
+fn registers(state: &Chip8State) -> String {
+    let cpu = state.cpu();
+    let mut text = String::new();
+    for register in 0..16 {
+        text += &format!("V{:X}={:02X}", register, cpu.get_reg(register));
+        text.push(if register % 8 == 7 { '\n' } else { ' ' });
+    }
+    text += &format!("I=0x{:03X} PC=0x{:03X} SP={} DT={:02X} ST={:02X}\n",
+                     cpu.i_register().full(), cpu.program_counter().full(),
+                     state.memory().stack().len(), cpu.delay_timer(), cpu.sound_timer());
+    text
+}
+
+fn set_register(state: &mut Chip8State, register: Register, value: u16) -> Result<(), String> {
+    let byte = || if value > 0xFF {
+        Err(format!("0x{:X} doesn't fit in a byte", value))
+    } else {
+        Ok(value as Byte)
+    };
+    let cpu = state.cpu_mut();
+    match register {
+        Register::V(index) => cpu.set_reg(index, byte()?),
+        Register::I => cpu.set_i_register(Word::new_from_full(value)),
+        Register::Pc => *cpu.program_counter_mut() = Word::new_from_full(value),
+        Register::Dt => cpu.set_delay_timer(byte()?),
+        Register::St => cpu.set_sound_timer(byte()?)
+    }
+    Ok(())
+}
+
+fn dump(state: &Chip8State, address: u16, length: u16) -> String {
+    let memory = state.memory();
+    let end = (address as usize + length as usize).min(memory.size());
+    let mut text = String::new();
+    for row in (address as usize..end).step_by(16) {
+        let bytes: Vec<String> = (row..end.min(row + 16))
+            .map(|byte| format!("{:02X}", memory.peek(&Word::new_from_full(byte as u16)).unwrap_or(0)))
+            .collect();
+        text += &format!("0x{:03X}: {}\n", row, bytes.join(" "));
+    }
+    if text.is_empty() {
+        text = format!("0x{:03X} is outside memory\n", address);
+    }
+    text
+}
+
+fn call_stack(state: &Chip8State) -> String {
+    let mut text = format!("#0  0x{:03X}\n", state.cpu().program_counter().full());
+    for (depth, address) in state.memory().stack().iter().rev().enumerate() {
+        text += &format!("#{}  0x{:03X}  called from 0x{:03X}\n", depth + 1, address.full(), address.full().wrapping_sub(2));
+    }
+    text
+}
+
+/// Numbers are decimal unless written with `0x` or `0b`, as in the assembler.
+fn number(text: &str) -> Result<u16, String> {
+    let lower = text.to_ascii_lowercase();
+    let parsed = if let Some(hex) = lower.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16)
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        u16::from_str_radix(binary, 2)
+    } else {
+        lower.parse()
+    };
+    parsed.map_err(|_| format!("`{}` is not a number", text))
+}
+
+fn watch(args: &[&str], read: bool, write: bool) -> Result<Watch, String> {
+    let start = address(args[0])?;
+    let length = match args.get(1) {
+        Some(length) => number(length)?.max(1),
+        None => 1
+    };
+    Ok(Watch { start, end: start.saturating_add(length - 1), read, write })
+}
+
+fn describe(watch: &Watch) -> String {
+    let access = match (watch.read, watch.write) {
+        (true, true) => "reads and writes",
+        (true, false) => "reads",
+        _ => "writes"
+    };
+    if watch.start == watch.end {
+        format!("0x{:03X} for {}", watch.start, access)
+    } else {
+        format!("0x{:03X}-0x{:03X} for {}", watch.start, watch.end, access)
+    }
+}
+
+fn address(text: &str) -> Result<u16, String> {
+    number(text)
+}
+
+fn byte(text: &str) -> Result<Byte, String> {
+    match number(text)? {
+        value if value > 0xFF => Err(format!("`{}` doesn't fit in a byte", text)),
+        value => Ok(value as Byte)
+    }
+}
+
+fn register(text: &str) -> Result<Register, String> {
+    let upper = text.to_ascii_uppercase();
+    match upper.as_str() {
+        "I" => Ok(Register::I),
+        "PC" => Ok(Register::Pc),
+        "DT" => Ok(Register::Dt),
+        "ST" => Ok(Register::St),
+        _ => upper.strip_prefix('V')
+            .filter(|index| index.len() == 1)
+            .and_then(|index| u8::from_str_radix(index, 16).ok())
+            .map(Register::V)
+            .ok_or_else(|| format!("`{}` is not a register", text))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use debugger::{Debugger, Command, Register};
+    use emustate::Chip8State;
+    use error::EmulatorError;
+
+    // 0x200: CALL 0x206; JP 0x204; (0x204) JP 0x204
+    // 0x206: ADD V0, 1; CALL 0x20C; RET
+    // 0x20C: ADD V1, 1; RET
+    const PROGRAM: [u8; 16] = [
+        0x22, 0x06, 0x12, 0x04, 0x12, 0x04,
+        0x70, 0x01, 0x22, 0x0C, 0x00, 0xEE,
+        0x71, 0x01, 0x00, 0xEE
+    ];
+
+    fn program() -> Chip8State {
+        let mut state = Chip8State::default();
+        state.load_instructions(PROGRAM.to_vec()).unwrap();
+        state
+    }
+
+    fn run(debugger: &mut Debugger, state: &mut Chip8State, line: &str) -> String {
+        let mut steps = 0;
+        let mut step = |state: &mut Chip8State| -> Result<bool, EmulatorError> {
+            state.run_next_cycle()?;
+            steps += 1;
+            Ok(steps > 1000)
+        };
+        debugger.execute(line, state, &mut step).output
+    }
+
+    fn pc(state: &Chip8State) -> u16 {
+        state.cpu().program_counter().full()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Command::parse("b 0x20A"), Ok(Command::Break(0x20A, None)));
+        assert_eq!(Command::parse("step 5"), Ok(Command::Step(5)));
+        assert_eq!(Command::parse("set vf 0x10"), Ok(Command::Set(Register::V(0xF), 0x10)));
+        assert_eq!(Command::parse("poke 0x300 1 0xFF"), Ok(Command::Poke(0x300, vec![1, 0xFF])));
+        assert!(Command::parse("set V10 1").is_err());
+        assert!(Command::parse("poke 0x300 256").is_err());
+        assert!(Command::parse("jump").is_err());
+    }
+
+    #[test]
+    fn test_breakpoints_and_continue() {
+        let mut debugger = Debugger::new();
+        let mut state = program();
+        run(&mut debugger, &mut state, "break 0x20C");
+        let output = run(&mut debugger, &mut state, "continue");
+
+        assert_eq!(pc(&state), 0x20C);
+        assert!(output.starts_with("Breakpoint at 0x20C\n>* 0x20C  ADD V1, 0x01"));
+        assert_eq!(run(&mut debugger, &mut state, "stack"),
+                   "#0  0x20C\n#1  0x20A  called from 0x208\n#2  0x202  called from 0x200\n");
+    }
+
+    #[test]
+    fn test_step_over_and_out() {
+        let mut debugger = Debugger::new();
+        let mut state = program();
+        run(&mut debugger, &mut state, "over");
+        assert_eq!(pc(&state), 0x202);
+        assert_eq!(state.cpu().get_reg(1), 1);
+
+        let mut state = program();
+        run(&mut debugger, &mut state, "step 3");
+        assert_eq!(pc(&state), 0x20C);
+        run(&mut debugger, &mut state, "out");
+        assert_eq!(pc(&state), 0x20A);
+        run(&mut debugger, &mut state, "out");
+        assert_eq!(pc(&state), 0x202);
+        assert_eq!(run(&mut debugger, &mut state, "out"), "Not in a subroutine\n");
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut debugger = Debugger::new();
+        let mut state = program();
+        run(&mut debugger, &mut state, "set V3 0x42");
+        run(&mut debugger, &mut state, "set I 0x300");
+        run(&mut debugger, &mut state, "poke 0x300 0xAB 0xCD");
+
+        assert_eq!(state.cpu().get_reg(3), 0x42);
+        assert_eq!(state.cpu().i_register().full(), 0x300);
+        assert_eq!(run(&mut debugger, &mut state, "x 0x2FE 4"), "0x2FE: 00 00 AB CD\n");
+        assert!(run(&mut debugger, &mut state, "set V0 256").contains("doesn't fit"));
+    }
+
+    #[test]
+    fn test_enter_repeats_and_history() {
+        let mut debugger = Debugger::new();
+        let mut state = program();
+        run(&mut debugger, &mut state, "s");
+        run(&mut debugger, &mut state, "");
+        assert_eq!(pc(&state), 0x208);
+
+        run(&mut debugger, &mut state, "r");
+        run(&mut debugger, &mut state, "!1");
+        assert_eq!(pc(&state), 0x20C);
+        assert_eq!(run(&mut debugger, &mut state, "history"), "   1  s\n   2  r\n   3  s\n   4  history\n");
+    }
+
+    #[test]
+    fn test_list_marks_pc() {
+        let mut debugger = Debugger::new();
+        let mut state = program();
+        let listing = run(&mut debugger, &mut state, "list");
+
+        assert!(listing.contains("\n>  0x200  CALL 0x206\n   0x202  JP 0x204\n"));
+        assert_eq!(listing.lines().count(), 9);
+    }
+
+    #[test]
+    fn test_watchpoints_see_every_access() {
+        // LD I, 0x300; LD V0, 123; LD B, V0; DRW V0, V0, 3; LD [I], V1; JP 0x20A
+        let mut state = Chip8State::default();
+        state.load_instructions(vec![0xA3, 0x00, 0x60, 0x7B, 0xF0, 0x33, 0xD0, 0x03, 0xF1, 0x55, 0x12, 0x0A]).unwrap();
+        let mut debugger = Debugger::new();
+
+        run(&mut debugger, &mut state, "awatch 0x302");
+        assert!(run(&mut debugger, &mut state, "c").starts_with("Watchpoint: write of 0x302 by the instruction at 0x204\n"));
+        assert!(run(&mut debugger, &mut state, "c").starts_with("Watchpoint: read of 0x302 by the instruction at 0x206\n"));
+
+        run(&mut debugger, &mut state, "unwatch 0x302");
+        run(&mut debugger, &mut state, "watch 0x2FF 2");
+        assert!(run(&mut debugger, &mut state, "c").starts_with("Watchpoint: write of 0x300 by the instruction at 0x208\n"));
+        assert_eq!(run(&mut debugger, &mut state, "bl"), "watch 0x2FF-0x300 for writes\n");
+    }
+
+    #[test]
+    fn test_conditions() {
+        // ADD V0, 1; JP 0x200
+        let mut state = Chip8State::default();
+        state.load_instructions(vec![0x70, 0x01, 0x12, 0x00]).unwrap();
+        let mut debugger = Debugger::new();
+
+        run(&mut debugger, &mut state, "b 0x202 if V0 == 5");
+        run(&mut debugger, &mut state, "c");
+        assert_eq!(state.cpu().get_reg(0), 5);
+
+        run(&mut debugger, &mut state, "d 0x202");
+        run(&mut debugger, &mut state, "when V0 == 9 && PC == 0x202");
+        assert!(run(&mut debugger, &mut state, "c").starts_with("Condition 1 is true: ((V0 == 0x9) && (PC == 0x202))\n"));
+        assert_eq!(state.cpu().get_reg(0), 9);
+        assert_eq!(run(&mut debugger, &mut state, "p V0 * 2"), "(V0 * 0x2) = 18 (0x12)\n");
+        assert!(run(&mut debugger, &mut state, "when V0 ==").contains("ends too early"));
+    }
+}
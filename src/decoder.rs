@@ -1,9 +1,14 @@
 use memory::Word;
 use memory::Byte;
+use platform::Platform;
 
-// These are in the order found at http://devernay.free.fr/hacks/chip8/C8TECH10.HTM
+use std::fmt;
 
-#[derive(Debug)]
+// These are in the order found at http://devernay.free.fr/hacks/chip8/C8TECH10.HTM,
+// followed by the SUPER-CHIP and then XO-CHIP additions.
+
+#[derive(Debug, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Instruction {
     Unknown(Word),          // Word is opcode
     CLS,
@@ -21,9 +26,9 @@ pub enum Instruction {
     XORR(RegisterRegister),
     ADDR(RegisterRegister),
     SUBR(RegisterRegister),
-    SHR(Byte),                  // Shift right
+    SHR(RegisterRegister),      // Shift right
     SUBNR(RegisterRegister),
-    SHL(Byte),
+    SHL(RegisterRegister),
     SNER(RegisterRegister),
     LDI(Word),
     JUMPV0(Word),
@@ -39,103 +44,202 @@ pub enum Instruction {
     LDFONT(Byte),
     LDBCD(Byte),
     STARR(Byte),
-    LDARR(Byte)
+    LDARR(Byte),
+    SCD(Byte),                  // Scroll down N lines
+    SCR,                        // Scroll right 4 pixels
+    SCL,
+    EXIT,
+    LOW,                        // Leave high resolution mode
+    HIGH,
+    LDHF(Byte),                 // Point I at the large font sprite for Vx
+    STRPL(Byte),                // Save V0-Vx to the RPL flags
+    LDRPL(Byte),
+    SCU(Byte),                  // Scroll up N lines
+    SAVERNG(RegisterRegister),  // Save Vx-Vy at I
+    LOADRNG(RegisterRegister),
+    LDILONG,                    // Load the following 16 bit word into I
+    PLANE(Byte),                // Select the bitplanes to draw to
+    AUDIO,                      // Load the audio pattern buffer from I
+    PITCH(Byte)
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct RegisterVal {
     pub register: Byte,
     pub value: Byte
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct RegisterRegister {
     pub first_reg: Byte,
     pub second_reg: Byte
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct RegisterRegisterNibble {
     pub first_reg: Byte,
     pub second_reg: Byte,
     pub nibble: Byte
 }
 
-pub fn decode(opcode: Word) -> Instruction {
+/// Cowgod-style assembly, e.g. `LD VA, 0x02`. Opcodes that don't decode are
+/// shown as data words so that a listing can be assembled again.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Unknown(ref opcode) => write!(f, "DW 0x{:04X}", opcode.full()),
+            Instruction::CLS => write!(f, "CLS"),
+            Instruction::RET => write!(f, "RET"),
+            Instruction::JUMP(ref address) => write!(f, "JP 0x{:03X}", address.full()),
+            Instruction::CALL(ref address) => write!(f, "CALL 0x{:03X}", address.full()),
+            Instruction::SE(ref rv) => write!(f, "SE V{:X}, 0x{:02X}", rv.register, rv.value),
+            Instruction::SNE(ref rv) => write!(f, "SNE V{:X}, 0x{:02X}", rv.register, rv.value),
+            Instruction::SER(ref rr) => write!(f, "SE V{:X}, V{:X}", rr.first_reg, rr.second_reg),
+            Instruction::LD(ref rv) => write!(f, "LD V{:X}, 0x{:02X}", rv.register, rv.value),
+            Instruction::ADD(ref rv) => write!(f, "ADD V{:X}, 0x{:02X}", rv.register, rv.value),
+            Instruction::LDR(ref rr) => write!(f, "LD V{:X}, V{:X}", rr.first_reg, rr.second_reg),
+            Instruction::ORR(ref rr) => write!(f, "OR V{:X}, V{:X}", rr.first_reg, rr.second_reg),
+            Instruction::ANDR(ref rr) => write!(f, "AND V{:X}, V{:X}", rr.first_reg, rr.second_reg),
+            Instruction::XORR(ref rr) => write!(f, "XOR V{:X}, V{:X}", rr.first_reg, rr.second_reg),
+            Instruction::ADDR(ref rr) => write!(f, "ADD V{:X}, V{:X}", rr.first_reg, rr.second_reg),
+            Instruction::SUBR(ref rr) => write!(f, "SUB V{:X}, V{:X}", rr.first_reg, rr.second_reg),
+            Instruction::SHR(ref rr) => write!(f, "SHR V{:X}, V{:X}", rr.first_reg, rr.second_reg),
+            Instruction::SUBNR(ref rr) => write!(f, "SUBN V{:X}, V{:X}", rr.first_reg, rr.second_reg),
+            Instruction::SHL(ref rr) => write!(f, "SHL V{:X}, V{:X}", rr.first_reg, rr.second_reg),
+            Instruction::SNER(ref rr) => write!(f, "SNE V{:X}, V{:X}", rr.first_reg, rr.second_reg),
+            Instruction::LDI(ref address) => write!(f, "LD I, 0x{:03X}", address.full()),
+            Instruction::JUMPV0(ref address) => write!(f, "JP V0, 0x{:03X}", address.full()),
+            Instruction::RND(ref rv) => write!(f, "RND V{:X}, 0x{:02X}", rv.register, rv.value),
+            Instruction::DRW(ref rrn) => write!(f, "DRW V{:X}, V{:X}, {}", rrn.first_reg, rrn.second_reg, rrn.nibble),
+            Instruction::SKP(reg) => write!(f, "SKP V{:X}", reg),
+            Instruction::SKNP(reg) => write!(f, "SKNP V{:X}", reg),
+            Instruction::LDVDT(reg) => write!(f, "LD V{:X}, DT", reg),
+            Instruction::LDK(reg) => write!(f, "LD V{:X}, K", reg),
+            Instruction::LDDTV(reg) => write!(f, "LD DT, V{:X}", reg),
+            Instruction::LDSTV(reg) => write!(f, "LD ST, V{:X}", reg),
+            Instruction::ADDI(reg) => write!(f, "ADD I, V{:X}", reg),
+            Instruction::LDFONT(reg) => write!(f, "LD F, V{:X}", reg),
+            Instruction::LDBCD(reg) => write!(f, "LD B, V{:X}", reg),
+            Instruction::STARR(reg) => write!(f, "LD [I], V{:X}", reg),
+            Instruction::LDARR(reg) => write!(f, "LD V{:X}, [I]", reg),
+            Instruction::SCD(lines) => write!(f, "SCD {}", lines),
+            Instruction::SCR => write!(f, "SCR"),
+            Instruction::SCL => write!(f, "SCL"),
+            Instruction::EXIT => write!(f, "EXIT"),
+            Instruction::LOW => write!(f, "LOW"),
+            Instruction::HIGH => write!(f, "HIGH"),
+            Instruction::LDHF(reg) => write!(f, "LD HF, V{:X}", reg),
+            Instruction::STRPL(reg) => write!(f, "LD R, V{:X}", reg),
+            Instruction::LDRPL(reg) => write!(f, "LD V{:X}, R", reg),
+            Instruction::SCU(lines) => write!(f, "SCU {}", lines),
+            Instruction::SAVERNG(ref rr) => write!(f, "SAVE V{:X}, V{:X}", rr.first_reg, rr.second_reg),
+            Instruction::LOADRNG(ref rr) => write!(f, "LOAD V{:X}, V{:X}", rr.first_reg, rr.second_reg),
+            Instruction::LDILONG => write!(f, "LD I, LONG"),
+            Instruction::PLANE(planes) => write!(f, "PLANE {}", planes),
+            Instruction::AUDIO => write!(f, "AUDIO"),
+            Instruction::PITCH(reg) => write!(f, "PITCH V{:X}", reg)
+        }
+    }
+}
+
+pub fn decode(opcode: Word, platform: Platform) -> Instruction {
     
-    return match opcode.high() {
-        0x00...0x0F => {
-            decode_flow(opcode)
+    match opcode.high() {
+        0x00..=0x0F => {
+            decode_flow(opcode, platform)
         },
-        0x10...0x1F => {
+        0x10..=0x1F => {
             let address = get_address(&opcode);
             Instruction::JUMP(address)
         },
-        0x20...0x2F => {
+        0x20..=0x2F => {
             let address = get_address(&opcode);
             Instruction::CALL(address)
         },
-        0x30...0x3F => {
+        0x30..=0x3F => {
             let register_val = get_register_val(&opcode);
             Instruction::SE(register_val)
         },
-        0x40...0x4F => {
+        0x40..=0x4F => {
             let register_val = get_register_val(&opcode);
             Instruction::SNE(register_val)
         },
-        0x50...0x5F => {
-            let registers = get_both_registers(&opcode);
-            Instruction::SER(registers)
+        0x50..=0x5F => {
+            decode_register_range(opcode, platform)
         }
-        0x60...0x6F => {
+        0x60..=0x6F => {
             let register_val = get_register_val(&opcode);
             Instruction::LD(register_val)
         },
-        0x70...0x7F => {
+        0x70..=0x7F => {
             let register_val = get_register_val(&opcode);
             Instruction::ADD(register_val)
         },
-        0x80...0x8F => {
+        0x80..=0x8F => {
             decode_operations(opcode)
         },
-        0x90...0x9F => {
+        0x90..=0x9F if opcode.low() & 0x0F == 0 => {
             let registers = get_both_registers(&opcode);
             Instruction::SNER(registers)
         },
-        0xA0...0xAF => {
+        0x90..=0x9F => {
+            Instruction::Unknown(opcode)
+        },
+        0xA0..=0xAF => {
             let address = get_address(&opcode);
             Instruction::LDI(address)
         },
-        0xB0...0xBF => {
+        0xB0..=0xBF => {
             let address = get_address(&opcode);
             Instruction::JUMPV0(address)
         },
-        0xC0...0xCF => {
+        0xC0..=0xCF => {
             let register_val = get_register_val(&opcode);
             Instruction::RND(register_val)
         },
-        0xD0...0xDF => {
+        0xD0..=0xDF => {
             let registers_nibble = get_both_registers_and_nibble(&opcode);
             Instruction::DRW(registers_nibble)
         },
-        0xE0...0xEF => {
+        0xE0..=0xEF => {
             decode_skip_keys(opcode)
         },
-        0xF0...0xFF => {
-            decode_register_ops(opcode)
+        0xF0..=0xFF => {
+            decode_register_ops(opcode, platform)
         }
-        _ => Instruction::Unknown(opcode)
     }
 }
 
-pub fn decode_flow(opcode: Word) -> Instruction {
-    return match opcode.full() {
+pub fn decode_flow(opcode: Word, platform: Platform) -> Instruction {
+    match opcode.full() {
         0x00E0 => {
             Instruction::CLS
         },
         0x00EE => {
             Instruction::RET
         },
+        _ if platform == Platform::Chip8 => Instruction::Unknown(opcode),
+        0x00C0..=0x00CF => {
+            Instruction::SCD(opcode.low() & 0x0F)
+        },
+        0x00D0..=0x00DF if platform == Platform::XoChip => {
+            Instruction::SCU(opcode.low() & 0x0F)
+        },
+        0x00FB => {
+            Instruction::SCR
+        },
+        0x00FC => {
+            Instruction::SCL
+        },
+        0x00FD => {
+            Instruction::EXIT
+        },
+        0x00FE => {
+            Instruction::LOW
+        },
+        0x00FF => {
+            Instruction::HIGH
+        },
         _ => Instruction::Unknown(opcode)
     }
 }
@@ -143,7 +247,7 @@ pub fn decode_flow(opcode: Word) -> Instruction {
 pub fn decode_operations(opcode: Word) -> Instruction {
     let registers = get_both_registers(&opcode);
 
-    return match opcode.low() & 0x0F {
+    match opcode.low() & 0x0F {
         0x0 => {
             Instruction::LDR(registers)
         },
@@ -163,21 +267,38 @@ pub fn decode_operations(opcode: Word) -> Instruction {
             Instruction::SUBR(registers)
         },
         0x6 => {
-            Instruction::SHR(registers.first_reg)
+            Instruction::SHR(registers)
         },
         0x7 => {
             Instruction::SUBNR(registers)
         },
         0xE => {
-            Instruction::SHL(registers.first_reg)
+            Instruction::SHL(registers)
         }
         _ => Instruction::Unknown(opcode)
     }
 }
 
+pub fn decode_register_range(opcode: Word, platform: Platform) -> Instruction {
+    let registers = get_both_registers(&opcode);
+
+    match opcode.low() & 0x0F {
+        0x0 => {
+            Instruction::SER(registers)
+        },
+        0x2 if platform == Platform::XoChip => {
+            Instruction::SAVERNG(registers)
+        },
+        0x3 if platform == Platform::XoChip => {
+            Instruction::LOADRNG(registers)
+        },
+        _ => Instruction::Unknown(opcode)
+    }
+}
+
 pub fn decode_skip_keys(opcode: Word) -> Instruction {
     let register = get_register(&opcode);
-    return match opcode.low() {
+    match opcode.low() {
         0x9E => {
             Instruction::SKP(register)
         },
@@ -188,9 +309,30 @@ pub fn decode_skip_keys(opcode: Word) -> Instruction {
     }
 }
 
-pub fn decode_register_ops(opcode: Word) -> Instruction {
+pub fn decode_register_ops(opcode: Word, platform: Platform) -> Instruction {
     let register = get_register(&opcode);
-    return match opcode.low() {
+    if platform != Platform::Chip8 {
+        match opcode.low() {
+            0x30 => return Instruction::LDHF(register),
+            0x75 => return Instruction::STRPL(register),
+            0x85 => return Instruction::LDRPL(register),
+            _ => {}
+        }
+    }
+    if platform == Platform::XoChip {
+        match opcode.full() {
+            0xF000 => return Instruction::LDILONG,
+            0xF002 => return Instruction::AUDIO,
+            _ => {}
+        }
+        match opcode.low() {
+            0x01 => return Instruction::PLANE(register),
+            0x3A => return Instruction::PITCH(register),
+            _ => {}
+        }
+    }
+
+    match opcode.low() {
         0x07 => {
             Instruction::LDVDT(register)
         },
@@ -0,0 +1,148 @@
+//! Turns a ROM back into a Cowgod-style listing without running it.
+
+use decoder::{self, Instruction};
+use emustate::PC_START;
+use memory::{Byte, Word};
+use platform::Platform;
+
+use std::collections::{BTreeMap, HashSet};
+
+/// One decoded instruction (or stray byte) from a ROM.
+#[derive(Debug)]
+pub struct Line {
+    pub address: u16,
+    pub bytes: Vec<Byte>,
+    pub instruction: Option<Instruction>
+}
+
+/// Linearly decodes `rom` as though it were loaded at the usual program start.
+/// A trailing odd byte is kept as data. Bytes that would load past 0xFFFF are
+/// left out.
+pub fn decode_rom(rom: &[Byte], platform: Platform) -> Vec<Line> {
+    let rom = &rom[..rom.len().min(max_rom_size())];
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < rom.len() {
+        let address = PC_START + offset as u16;
+        if offset + 1 == rom.len() {
+            lines.push(Line { address, bytes: vec![rom[offset]], instruction: None });
+            break;
+        }
+
+        let opcode = Word::new_from_bytes(rom[offset], rom[offset + 1]);
+        let instruction = decoder::decode(opcode, platform);
+        let length = match instruction {
+            Instruction::LDILONG if offset + 4 <= rom.len() => 4,
+            _ => 2
+        };
+        lines.push(Line {
+            address,
+            bytes: rom[offset..offset + length].to_vec(),
+            instruction: Some(instruction)
+        });
+        offset += length;
+    }
+    lines
+}
+
+/// The most a ROM can hold and still fit in a 16-bit address space above the
+/// program start.
+pub fn max_rom_size() -> usize {
+    0x10000 - PC_START as usize
+}
+
+/// Names every decoded address the ROM jumps to or calls, so the listing can
+/// refer to them by label. Call targets become `sub_`, everything else `loc_`.
+pub fn find_labels(lines: &[Line]) -> BTreeMap<u16, String> {
+    let starts: HashSet<u16> = lines.iter().map(|line| line.address).collect();
+    let mut labels = BTreeMap::new();
+    for line in lines {
+        match line.instruction {
+            Some(Instruction::CALL(ref address)) if starts.contains(&address.full()) => {
+                labels.insert(address.full(), format!("sub_{:03X}", address.full()));
+            },
+            Some(Instruction::JUMP(ref address)) if starts.contains(&address.full()) => {
+                labels.entry(address.full()).or_insert_with(|| format!("loc_{:03X}", address.full()));
+            },
+            _ => {}
+        }
+    }
+    labels
+}
+
+/// The full listing: one `address  opcode  mnemonic` row per instruction,
+/// with a label row before every jump or call target that lines up with one.
+pub fn disassemble(rom: &[Byte], platform: Platform) -> String {
+    let lines = decode_rom(rom, platform);
+    let labels = find_labels(&lines);
+
+    let mut listing = String::new();
+    for line in &lines {
+        if let Some(label) = labels.get(&line.address) {
+            listing.push_str(&format!("{}:\n", label));
+        }
+        let raw: String = line.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        listing.push_str(&format!("0x{:03X}  {:<4}  {}\n", line.address, raw, mnemonic(line, &labels)));
+    }
+    listing
+}
+
+fn mnemonic(line: &Line, labels: &BTreeMap<u16, String>) -> String {
+    match line.instruction {
+        None => format!("DB 0x{:02X}", line.bytes[0]),
+        Some(Instruction::JUMP(ref address)) if labels.contains_key(&address.full()) => {
+            format!("JP {}", labels[&address.full()])
+        },
+        Some(Instruction::CALL(ref address)) if labels.contains_key(&address.full()) => {
+            format!("CALL {}", labels[&address.full()])
+        },
+        Some(Instruction::LDILONG) if line.bytes.len() == 4 => {
+            format!("LD I, LONG 0x{:02X}{:02X}", line.bytes[2], line.bytes[3])
+        },
+        Some(ref instruction) => instruction.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use disasm;
+    use platform::Platform;
+
+    #[test]
+    fn test_listing_format() {
+        let listing = disasm::disassemble(&[0x6A, 0x02, 0xD0, 0x15, 0x00, 0xE0], Platform::Chip8);
+
+        assert_eq!(listing, "0x200  6A02  LD VA, 0x02\n0x202  D015  DRW V0, V1, 5\n0x204  00E0  CLS\n");
+    }
+
+    #[test]
+    fn test_jump_and_call_targets_are_labelled() {
+        // CALL 0x206; JP 0x200; RET; RET; JP 0x300
+        let listing = disasm::disassemble(&[0x22, 0x06, 0x12, 0x00, 0x00, 0xEE, 0x00, 0xEE, 0x13, 0x00], Platform::Chip8);
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(lines[0], "loc_200:");
+        assert_eq!(lines[1], "0x200  2206  CALL sub_206");
+        assert_eq!(lines[2], "0x202  1200  JP loc_200");
+        assert_eq!(lines[4], "sub_206:");
+        assert_eq!(lines[6], "0x208  1300  JP 0x300");
+    }
+
+    #[test]
+    fn test_data_and_long_loads() {
+        let listing = disasm::disassemble(&[0xF0, 0x00, 0x12, 0x34, 0xFF, 0xFF, 0xAB], Platform::XoChip);
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(lines[0], "0x200  F0001234  LD I, LONG 0x1234");
+        assert_eq!(lines[1], "0x204  FFFF  DW 0xFFFF");
+        assert_eq!(lines[2], "0x206  AB    DB 0xAB");
+    }
+
+    #[test]
+    fn test_stops_at_the_top_of_memory() {
+        let lines = disasm::decode_rom(&vec![0; 70000], Platform::XoChip);
+
+        assert_eq!(lines.len(), disasm::max_rom_size() / 2);
+        assert_eq!(lines.last().unwrap().address, 0xFFFE);
+    }
+}
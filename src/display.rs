@@ -1,165 +1,441 @@
-extern crate sdl2;
-use self::sdl2::pixels;
-use self::sdl2::rect;
-use self::sdl2::render::Canvas;
-use self::sdl2::video::Window;
-use self::sdl2::audio::{AudioCallback, AudioSpecDesired};
-
-const PIXEL_SIZE : u32 = 10;
-const CHIP8_WIDTH : usize = 64;
-const CHIP8_HEIGHT : usize = 32;
-
-
-pub struct Display {
-    canvas: Canvas<Window>,
-    sdl2_context: sdl2::Sdl,
-    vram: [[u8; CHIP8_WIDTH]; CHIP8_HEIGHT],
-    vram_changed: bool,
-    audio_device: sdl2::audio::AudioDevice<SquareWave>
-}
+use quirks::Quirks;
+use savestate::{StateWriter, StateReader, SaveStateError};
 
-#[derive(Debug)]
-struct Pixel {
-    colour: pixels::Color,
-    representation: rect::Rect
+pub const CHIP8_WIDTH : usize = 64;
+pub const CHIP8_HEIGHT : usize = 32;
+pub const SCHIP_WIDTH : usize = 128;
+pub const SCHIP_HEIGHT : usize = 64;
+
+/// Colours for each pixel value, as RGB. With a single plane pixels are 0 or
+/// 1; XO-CHIP's two bitplanes give values 0-3.
+pub const PALETTE : [[u8; 3]; 4] = [
+    [0, 0, 0],
+    [238, 66, 244],
+    [66, 212, 244],
+    [255, 255, 255]
+];
+
+/// The CHIP-8 screen contents and the semantics of `CLS`, `DRW` and the
+/// SUPER-CHIP and XO-CHIP scroll, resolution and plane instructions. This is
+/// machine state and lives in the core; a `Video` backend decides how (and
+/// whether) it ends up in front of a user.
+///
+/// `vram` is always big enough for high resolution mode. In low resolution
+/// mode only the top left 64x32 pixels are used. Each pixel holds one bit per
+/// bitplane; drawing, clearing and scrolling only touch the selected planes.
+pub struct Framebuffer {
+    vram: [[u8; SCHIP_WIDTH]; SCHIP_HEIGHT],
+    hires: bool,
+    planes: u8,
+    vram_changed: bool
 }
 
+/// Sprite data from memory: one byte per row, or two for 16 pixel wide
+/// SUPER-CHIP sprites. With several bitplanes selected the data for each
+/// plane follows on from the previous one.
 #[derive(Debug)]
 pub struct Sprite {
-    data: Vec<u8>
+    data: Vec<u8>,
+    width: usize
 }
 
-pub struct SquareWave {
-    phase_inc: f32,
-    phase: f32,
-    volume: f32 
+/// A sink for the CHIP-8 display. Implementors own a `Framebuffer` which the
+/// CPU draws into, and get asked to `present` it once it should be shown.
+pub trait Video {
+    fn framebuffer(&self) -> &Framebuffer;
+
+    fn framebuffer_mut(&mut self) -> &mut Framebuffer;
+
+    /// Shows the current framebuffer. Implementations should do nothing if
+    /// `vram_changed` is false, and call `mark_presented` once they are done.
+    fn present(&mut self);
+
+    fn clear(&mut self) {
+        self.framebuffer_mut().clear_screen();
+    }
+
+    fn draw_sprite(&mut self, x: u8, y: u8, sprite: &Sprite, quirks: &Quirks) -> u8 {
+        self.framebuffer_mut().draw_sprite(x, y, sprite, quirks)
+    }
 }
 
-impl AudioCallback for SquareWave {
-    type Channel = f32;
+impl Framebuffer {
+    pub fn new() -> Framebuffer {
+        Framebuffer {
+            vram: [[0u8; SCHIP_WIDTH]; SCHIP_HEIGHT],
+            hires: false,
+            planes: 1,
+            vram_changed: false
+        }
+    }
 
-    fn callback(&mut self, out: &mut [f32]) {
-        for x in out.iter_mut() {
-            *x = match self.phase {
-                0.0...0.5 => self.volume,
-                _ => -self.volume
-            };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
+    pub fn write_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.hires);
+        writer.u8(self.planes);
+        for row in self.vram.iter() {
+            writer.bytes(row);
         }
     }
-}
 
-impl Display {
-    pub fn new() -> Display {
-        let context = sdl2::init().unwrap();
-        let mut window_builder = context.video().unwrap().window(
-                                                        "Test window",
-                                                        CHIP8_WIDTH as u32 * PIXEL_SIZE, 
-                                                        CHIP8_HEIGHT as u32 * PIXEL_SIZE);
-        let mut window = window_builder.opengl().build().unwrap();
-        let mut canvas = window.into_canvas().present_vsync().build().unwrap();
-
-        let mut sound_system = context.audio().unwrap();
-        let desired_spec = AudioSpecDesired {
-            freq: Some(44100),
-            channels: Some(1),
-            samples: None
-        };
-
-        let device = sound_system.open_playback(None, &desired_spec, |spec| {
-            SquareWave {
-                phase_inc: 440.0 / spec.freq as f32,
-                phase: 0.0,
-                volume: 0.25 
+    /// The restored framebuffer is marked as changed so it gets presented.
+    pub fn read_state(reader: &mut StateReader) -> Result<Framebuffer, SaveStateError> {
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.hires = reader.bool()?;
+        framebuffer.planes = reader.u8()? & 0b11;
+        for row in framebuffer.vram.iter_mut() {
+            let bytes = reader.bytes()?;
+            if bytes.len() != row.len() {
+                return Err(SaveStateError::Invalid("framebuffer width"));
+            }
+            row.copy_from_slice(bytes);
+        }
+        framebuffer.vram_changed = true;
+        Ok(framebuffer)
+    }
+
+    pub fn width(&self) -> usize {
+        if self.hires { SCHIP_WIDTH } else { CHIP8_WIDTH }
+    }
+
+    pub fn height(&self) -> usize {
+        if self.hires { SCHIP_HEIGHT } else { CHIP8_HEIGHT }
+    }
+
+    pub fn hires(&self) -> bool {
+        self.hires
+    }
+
+    /// Switches between 64x32 and 128x64 mode, clearing every plane.
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.vram = [[0u8; SCHIP_WIDTH]; SCHIP_HEIGHT];
+        self.vram_changed = true;
+    }
+
+    /// The bitplanes drawn to, as a mask: 1 and 2 are the two planes.
+    pub fn planes(&self) -> u8 {
+        self.planes
+    }
+
+    pub fn select_planes(&mut self, planes: u8) {
+        self.planes = planes & 0b11;
+    }
+
+    pub fn selected_plane_count(&self) -> usize {
+        self.planes.count_ones() as usize
+    }
+
+    /// XORs the sprite onto the screen at (x, y). The starting position always
+    /// wraps onto the screen; pixels running off an edge wrap around or, with
+    /// the clipping quirk, are dropped. Returns 1 if any lit pixel was turned
+    /// off, 0 otherwise, or with the `collision_rows` quirk in hires mode the
+    /// number of rows that collided or were cut off at the bottom.
+    pub fn draw_sprite(&mut self, x: u8, y: u8, sprite: &Sprite, quirks: &Quirks) -> u8 {
+        let plane_count = self.selected_plane_count();
+        if plane_count == 0 {
+            return 0;
+        }
+
+        let plane_length = sprite.data.len() / plane_count;
+        let (mut collided, mut clipped) = (0, 0);
+        let mut data = sprite.data.chunks(plane_length.max(1));
+        for plane in &[1u8, 2u8] {
+            if self.planes & plane == 0 {
+                continue;
             }
-        }).unwrap();
-
-        canvas.set_draw_color(pixels::Color::RGB(0, 0, 0));
-        canvas.clear();
-        canvas.present();
-
-        Display {
-            canvas,
-            sdl2_context: context,
-            vram: [[0u8; CHIP8_WIDTH]; CHIP8_HEIGHT],
-            vram_changed: false,
-            audio_device: device
+            if let Some(plane_data) = data.next() {
+                let (plane_collided, plane_clipped) = self.draw_plane(x, y, plane_data, sprite.width, *plane, quirks);
+                collided += plane_collided;
+                clipped += plane_clipped;
+            }
+        }
+        self.vram_changed = true;
+        if quirks.collision_rows && self.hires {
+            (collided + clipped) as u8
+        } else {
+            (collided > 0) as u8
         }
     }
 
-    pub fn draw_sprite(&mut self, x: u8, mut y: u8, sprite: Sprite) -> u8 {
-        let mut collision = 0;
-        for mut row in sprite.data {
-            let mut mut_x = x;
-            for _ in 0..8 {
-                let bit = (row >> 7) & 1;
-                if bit != 0 {
-                    if self.vram[y as usize][mut_x as usize] == 1 {
-                        collision = 1;
+    /// Returns how many rows turned a lit pixel off, and how many were cut
+    /// off at the bottom of the screen.
+    fn draw_plane(&mut self, x: u8, y: u8, data: &[u8], sprite_width: usize, plane: u8, quirks: &Quirks) -> (usize, usize) {
+        let (width, height) = (self.width(), self.height());
+        let x = x as usize % width;
+        let y = y as usize % height;
+        let rows = data.chunks(sprite_width / 8);
+        let row_count = rows.len();
+        let mut collided = 0;
+        for (row_number, row) in rows.enumerate() {
+            let mut pixel_y = y + row_number;
+            if pixel_y >= height {
+                if quirks.clipping {
+                    return (collided, row_count - row_number);
+                }
+                pixel_y %= height;
+            }
+            let mut row_collided = false;
+            for column in 0..row.len() * 8 {
+                let mut pixel_x = x + column;
+                if pixel_x >= width {
+                    if quirks.clipping {
+                        break;
+                    }
+                    pixel_x %= width;
+                }
+                if (row[column / 8] >> (7 - column % 8)) & 1 != 0 {
+                    if self.vram[pixel_y][pixel_x] & plane != 0 {
+                        row_collided = true;
                     }
-                    self.vram[y as usize][mut_x as usize] ^= bit;
+                    self.vram[pixel_y][pixel_x] ^= plane;
                 }
-                row <<= 1;
-                mut_x = if (mut_x == (CHIP8_WIDTH - 1) as u8) {0} else {mut_x + 1};
             }
-            y = if (y == (CHIP8_HEIGHT - 1) as u8) {0} else {y + 1};
+            collided += row_collided as usize;
         }
-        self.vram_changed = true;
-        collision
+        (collided, 0)
+    }
+
+    /// Moves the picture down `lines` pixels, blanking the top.
+    pub fn scroll_down(&mut self, lines: usize) {
+        self.scroll(0, lines as isize);
+    }
+
+    /// Moves the picture up `lines` pixels, blanking the bottom.
+    pub fn scroll_up(&mut self, lines: usize) {
+        self.scroll(0, -(lines as isize));
+    }
+
+    /// Moves the picture right `pixels` pixels, blanking the left edge.
+    pub fn scroll_right(&mut self, pixels: usize) {
+        self.scroll(pixels as isize, 0);
     }
 
-    pub fn refresh_display(&mut self) {
-        self.canvas.clear();
-        for (y, row) in self.vram.iter().enumerate() {
-            for (x, bit) in row.iter().enumerate() {
-                let pixel = Pixel::new(x, y, *bit);
-                self.canvas.set_draw_color(pixel.colour);
-                self.canvas.fill_rect(pixel.representation).unwrap();
+    /// Moves the picture left `pixels` pixels, blanking the right edge.
+    pub fn scroll_left(&mut self, pixels: usize) {
+        self.scroll(-(pixels as isize), 0);
+    }
+
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let (width, height) = (self.width() as isize, self.height() as isize);
+        let old = self.vram;
+        for y in 0..height {
+            for x in 0..width {
+                let (from_x, from_y) = (x - dx, y - dy);
+                let moved = if from_x >= 0 && from_x < width && from_y >= 0 && from_y < height {
+                    old[from_y as usize][from_x as usize]
+                } else {
+                    0
+                };
+                let pixel = &mut self.vram[y as usize][x as usize];
+                *pixel = (*pixel & !self.planes) | (moved & self.planes);
             }
         }
-        self.canvas.present();
-        self.vram_changed = false;
+        self.vram_changed = true;
+    }
+
+    /// Whether the pixel at (x, y) in the current resolution is lit in any plane.
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.vram[y][x] != 0
+    }
+
+    /// The pixel's value with one bit per plane, indexing into `PALETTE`.
+    pub fn pixel_value(&self, x: usize, y: usize) -> u8 {
+        self.vram[y][x]
     }
 
     pub fn vram_changed(&self) -> bool {
         self.vram_changed
     }
 
-    pub fn get_context(&self) -> &sdl2::Sdl {
-        &self.sdl2_context
+    pub fn mark_presented(&mut self) {
+        self.vram_changed = false;
     }
 
+    /// Clears the selected planes.
     pub fn clear_screen(&mut self) {
-        self.vram = [[0u8; CHIP8_WIDTH]; CHIP8_HEIGHT];
+        for row in self.vram.iter_mut() {
+            for pixel in row.iter_mut() {
+                *pixel &= !self.planes;
+            }
+        }
         self.vram_changed = true;
     }
+}
+
+impl Default for Framebuffer {
+    fn default() -> Framebuffer {
+        Framebuffer::new()
+    }
+}
 
-    pub fn play_audio(&mut self) {
-        self.audio_device.resume();
+/// The in-memory backend: the framebuffer is the whole display, so presenting
+/// it only clears the changed flag. Used for headless runs and tests.
+impl Video for Framebuffer {
+    fn framebuffer(&self) -> &Framebuffer {
+        self
     }
 
-    pub fn pause_audio(&mut self) {
-        self.audio_device.pause();
+    fn framebuffer_mut(&mut self) -> &mut Framebuffer {
+        self
     }
-}
 
-impl Pixel {
-    fn new(xpos : usize, ypos : usize, colour: u8) -> Pixel {
-        Pixel {
-            colour: if colour == 1 { pixels::Color::RGB(238, 66, 244) } else { pixels::Color::RGB(0, 0, 0) },
-            representation: rect::Rect::new(
-                                (xpos as i32 * PIXEL_SIZE as i32), 
-                                (ypos as i32 * PIXEL_SIZE as i32),
-                                PIXEL_SIZE, 
-                                PIXEL_SIZE)
-        }
+    fn present(&mut self) {
+        self.mark_presented();
     }
 }
 
 impl Sprite {
     pub fn new(data: &[u8]) -> Sprite {
         Sprite {
-            data: data.to_vec()
+            data: data.to_vec(),
+            width: 8
         }
     }
-}
\ No newline at end of file
+
+    /// A 16x16 SUPER-CHIP sprite from 32 bytes of data.
+    pub fn wide(data: &[u8]) -> Sprite {
+        Sprite {
+            data: data.to_vec(),
+            width: 16
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use display::{Framebuffer, Sprite, Video};
+    use quirks::Quirks;
+
+    #[test]
+    fn test_draw_sets_pixels_and_collides() {
+        let mut video = Framebuffer::new();
+        let sprite = Sprite::new(&[0b11000000]);
+
+        assert_eq!(video.draw_sprite(0, 0, &sprite, &Quirks::default()), 0);
+        assert!(video.framebuffer().pixel(0, 0));
+        assert!(video.framebuffer().pixel(1, 0));
+        assert!(!video.framebuffer().pixel(2, 0));
+
+        assert_eq!(video.draw_sprite(1, 0, &sprite, &Quirks::default()), 1);
+        assert!(video.framebuffer().pixel(0, 0));
+        assert!(!video.framebuffer().pixel(1, 0));
+        assert!(video.framebuffer().pixel(2, 0));
+    }
+
+    #[test]
+    fn test_draw_wraps_around_edges() {
+        let mut video = Framebuffer::new();
+        let sprite = Sprite::new(&[0b11000000, 0b11000000]);
+
+        video.draw_sprite(63, 31, &sprite, &Quirks::default());
+
+        assert!(video.framebuffer().pixel(63, 31));
+        assert!(video.framebuffer().pixel(0, 31));
+        assert!(video.framebuffer().pixel(63, 0));
+        assert!(video.framebuffer().pixel(0, 0));
+    }
+
+    #[test]
+    fn test_clear_and_present() {
+        let mut video = Framebuffer::new();
+        video.draw_sprite(4, 4, &Sprite::new(&[0xFF]), &Quirks::default());
+        video.present();
+        assert!(!video.framebuffer().vram_changed());
+
+        video.clear();
+        assert!(video.framebuffer().vram_changed());
+        assert!(!video.framebuffer().pixel(4, 4));
+    }
+
+    #[test]
+    fn test_draw_clips_at_edges() {
+        let mut video = Framebuffer::new();
+        let sprite = Sprite::new(&[0b11000000, 0b11000000]);
+
+        video.draw_sprite(63, 31, &sprite, &Quirks::cosmac_vip());
+
+        assert!(video.framebuffer().pixel(63, 31));
+        assert!(!video.framebuffer().pixel(0, 31));
+        assert!(!video.framebuffer().pixel(63, 0));
+        assert!(!video.framebuffer().pixel(0, 0));
+    }
+
+    #[test]
+    fn test_draw_start_position_wraps() {
+        let mut video = Framebuffer::new();
+
+        video.draw_sprite(64 + 3, 32 + 2, &Sprite::new(&[0x80]), &Quirks::cosmac_vip());
+
+        assert!(video.framebuffer().pixel(3, 2));
+    }
+
+    #[test]
+    fn test_hires_wide_sprite() {
+        let mut video = Framebuffer::new();
+        video.framebuffer_mut().set_hires(true);
+        let mut data = vec![0u8; 32];
+        data[0] = 0x80;
+        data[31] = 0x01;
+
+        video.draw_sprite(100, 40, &Sprite::wide(&data), &Quirks::superchip());
+
+        assert!(video.framebuffer().pixel(100, 40));
+        assert!(video.framebuffer().pixel(115, 55));
+        assert_eq!(video.framebuffer().width(), 128);
+    }
+
+    #[test]
+    fn test_superchip_hires_counts_rows() {
+        let mut video = Framebuffer::new();
+        let sprite = Sprite::new(&[0x80, 0x80, 0x80]);
+
+        // Low resolution still sets VF to 1.
+        video.draw_sprite(0, 0, &sprite, &Quirks::superchip());
+        assert_eq!(video.draw_sprite(0, 0, &sprite, &Quirks::superchip()), 1);
+
+        video.framebuffer_mut().set_hires(true);
+        assert_eq!(video.draw_sprite(0, 62, &sprite, &Quirks::superchip()), 1);
+        assert_eq!(video.draw_sprite(0, 62, &sprite, &Quirks::superchip()), 3);
+        assert_eq!(video.draw_sprite(0, 62, &sprite, &Quirks::cosmac_vip()), 0);
+        assert_eq!(video.draw_sprite(0, 62, &sprite, &Quirks::cosmac_vip()), 1);
+
+        video.draw_sprite(8, 0, &Sprite::wide(&[0xFF; 32]), &Quirks::superchip());
+        assert_eq!(video.draw_sprite(8, 0, &Sprite::wide(&[0xFF; 32]), &Quirks::superchip()), 16);
+    }
+
+    #[test]
+    fn test_scrolling() {
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.draw_sprite(10, 10, &Sprite::new(&[0x80]), &Quirks::default());
+
+        framebuffer.scroll_down(3);
+        assert!(framebuffer.pixel(10, 13));
+        framebuffer.scroll_right(4);
+        assert!(framebuffer.pixel(14, 13));
+        framebuffer.scroll_left(4);
+        framebuffer.scroll_left(4);
+        assert!(framebuffer.pixel(6, 13));
+        assert!(!framebuffer.pixel(10, 13));
+
+        framebuffer.scroll_left(8);
+        assert!((0..64).all(|x| !framebuffer.pixel(x, 13)));
+    }
+
+    #[test]
+    fn test_planes_draw_and_clear_separately() {
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.select_planes(3);
+        framebuffer.draw_sprite(0, 0, &Sprite::new(&[0xC0, 0x80]), &Quirks::xochip());
+        assert_eq!(framebuffer.pixel_value(0, 0), 3);
+        assert_eq!(framebuffer.pixel_value(1, 0), 1);
+
+        framebuffer.select_planes(2);
+        assert_eq!(framebuffer.draw_sprite(0, 0, &Sprite::new(&[0x80]), &Quirks::xochip()), 1);
+        assert_eq!(framebuffer.pixel_value(0, 0), 1);
+
+        framebuffer.select_planes(1);
+        framebuffer.clear_screen();
+        assert!(!framebuffer.pixel(0, 0));
+        assert!(!framebuffer.pixel(1, 0));
+    }
+}
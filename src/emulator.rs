@@ -1,57 +1,392 @@
-use emustate::Chip8State;
-use memory::Byte;
+use chip8_emulator::Chip8State;
+#[cfg(feature = "sdl")]
+use chip8_emulator::EmulatorError;
+#[cfg(feature = "sdl")]
+use chip8_emulator::audio::{Audio, SilentAudio};
+use chip8_emulator::memory::Byte;
+use chip8_emulator::{Quirks, Platform};
+use chip8_emulator::rom;
+use chip8_emulator::movie::Movie;
+use chip8_emulator::trace::{self, Tracer};
+use chip8_emulator::headless::{self, Limit};
+use chip8_emulator::input::ScriptedInput;
+use chip8_emulator::screenshot;
+
+#[cfg(feature = "sdl")]
+use chip8_emulator::sdl::{self, SdlVideo, SdlAudio, SdlInput};
+#[cfg(feature = "sdl")]
+use chip8_emulator::input::{InputSource, FrontendEvent};
+#[cfg(feature = "sdl")]
+use chip8_emulator::movie::Playback;
+#[cfg(feature = "sdl")]
+use chip8_emulator::rng;
+#[cfg(feature = "sdl")]
+use chip8_emulator::scheduler::Scheduler;
+#[cfg(feature = "sdl")]
+use chip8_emulator::rewind::Rewind;
+#[cfg(feature = "sdl")]
+use chip8_emulator::debugger::Debugger;
+#[cfg(feature = "sdl")]
+use chip8_emulator::gdb::GdbStub;
 
 use std::error::Error;
-use std::fs::File;
+use std::fs::{self, File};
+#[cfg(feature = "sdl")]
 use std::io::prelude::*;
+use std::path::Path;
+use std::io::BufWriter;
+#[cfg(feature = "sdl")]
 use std::io;
-use std::thread;
-use std::time;
 
 #[derive(Debug)]
 pub struct Config {
     pub filename: String,
     pub verbose: bool,
-    pub debug: bool
+    pub debug: bool,
+    pub clock_hz: u32,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub seed: Option<u64>,
+    pub load_state: Option<String>,
+    pub rewind_budget: usize,
+    pub record: Option<String>,
+    pub replay: Option<String>,
+    pub verify: bool,
+    pub gdb: Option<u16>,
+    pub trace: Option<String>,
+    pub trace_format: trace::Format,
+    pub trace_ranges: Vec<(u16, u16)>,
+    pub headless: Option<Limit>,
+    pub screenshot: Option<String>,
+    pub ascii: bool,
+    pub input: Option<String>
 }
 
-pub fn run(config: Config) -> Result<(), Box<Error>> {
-    let mut rom_file = File::open(config.filename)?;
-    let mut bytes : Vec<Byte> = Vec::new(); 
-    rom_file.read_to_end(&mut bytes)?;
+pub fn run(mut config: Config) -> Result<(), Box<dyn Error>> {
+    let bytes = rom::read_rom(&config.filename)?;
 
     info!("Bytes size: {}", bytes.len());
 
-    let mut init_state = Chip8State::new();
-    init_state.load_instructions(bytes);
-    init_state.load_font();
+    let replay = match config.replay {
+        Some(ref filename) => Some(read_movie(filename, &bytes)?),
+        None => None
+    };
+    if let Some(ref movie) = replay {
+        config.platform = movie.platform;
+        config.quirks = movie.quirks;
+        config.clock_hz = movie.clock_hz;
+        if config.verify {
+            return verify_replay(bytes, movie, &config);
+        }
+    }
+
+    if let Some(limit) = config.headless {
+        return run_headless(bytes, limit, &config);
+    }
 
-    loop_emulation(init_state, config.debug)?;
+    loop_emulation(bytes, &config, replay)?;
 
     Ok(())
 }
 
-pub fn loop_emulation(mut state: Chip8State, debug: bool) -> Result<(), Box<Error>> {
-    let stdin = io::stdin();
+fn read_movie(filename: &str, rom: &[Byte]) -> Result<Movie, Box<dyn Error>> {
+    let movie = Movie::parse(&fs::read_to_string(filename)?)
+        .and_then(|movie| movie.check_rom(rom).map(|_| movie))
+        .map_err(|e| format!("{}: {}", filename, e))?;
+    Ok(movie)
+}
+
+/// Replays the movie as fast as possible without a window, and checks it
+/// ends in the state it was recorded ending in.
+fn verify_replay(rom: Vec<Byte>, movie: &Movie, config: &Config) -> Result<(), Box<dyn Error>> {
+    let mut state = Chip8State::default();
+    movie.setup(&mut state);
+    state.set_tracer(open_trace(config)?);
+    state.load_instructions(rom)?;
+    state.load_font();
+    movie.play(&mut state)?;
+    state.flush_trace()?;
+
+    let hash = state.state_hash();
+    match movie.final_hash {
+        Some(expected) if expected == hash => {
+            println!("Replay matches: state hash {:016x} after {} frames", hash, movie.frames.len());
+            Ok(())
+        },
+        Some(expected) => Err(From::from(format!("replay diverged: final state hash is {:016x}, recorded {:016x}", hash, expected))),
+        None => Err(From::from("the movie has no final hash to check against"))
+    }
+}
+
+/// Runs with no window until the limit, then prints a hash of the screen
+/// and writes it out as asked.
+fn run_headless(rom: Vec<Byte>, limit: Limit, config: &Config) -> Result<(), Box<dyn Error>> {
+    let screenshot_format = match config.screenshot {
+        Some(ref filename) => match Path::new(filename).extension().and_then(|extension| extension.to_str()) {
+            Some("png") => Some(screenshot::to_png as fn(&_) -> _),
+            Some("pbm") => Some(screenshot::to_pbm as fn(&_) -> _),
+            _ => return Err(From::from(format!("{}: screenshots can be .png or .pbm files", filename)))
+        },
+        None => None
+    };
+    let mut input = match config.input {
+        Some(ref filename) => ScriptedInput::from_file(filename)?,
+        None => ScriptedInput::new()
+    };
 
-    if debug {
+    let mut state = Chip8State::default();
+    state.set_platform(config.platform);
+    state.set_quirks(config.quirks);
+    state.set_tracer(open_trace(config)?);
+    if let Some(seed) = config.seed {
+        state.seed_rng(seed);
+    }
+    state.load_instructions(rom)?;
+    state.load_font();
+    if let Some(ref filename) = config.load_state {
+        state.load_state(&fs::read(filename)?).map_err(|e| format!("{}: {}", filename, e))?;
+    }
+
+    let finished = headless::run(&mut state, config.clock_hz, &mut input, limit)?;
+    state.flush_trace()?;
+
+    let framebuffer = state.framebuffer();
+    if config.ascii {
+        print!("{}", screenshot::to_ascii(framebuffer));
+    }
+    if let (Some(filename), Some(encode)) = (config.screenshot.as_ref(), screenshot_format) {
+        fs::write(filename, encode(framebuffer))?;
+    }
+    println!("Screen hash {:016x} after {} frames ({} instructions){}", screenshot::hash(framebuffer), finished.frames, finished.cycles,
+             if finished.stopped_early { ", stopped early" } else { "" });
+    Ok(())
+}
+
+/// Opens the `--trace` file, if one was asked for.
+fn open_trace(config: &Config) -> Result<Option<Tracer>, Box<dyn Error>> {
+    let filename = match config.trace {
+        Some(ref filename) => filename,
+        None => return Ok(None)
+    };
+    let file = File::create(filename).map_err(|e| format!("{}: {}", filename, e))?;
+    let mut tracer = Tracer::new(Box::new(BufWriter::new(file)), config.trace_format, config.platform)?;
+    for &(start, end) in &config.trace_ranges {
+        tracer.add_range(start, end);
+    }
+    Ok(Some(tracer))
+}
+
+#[cfg(feature = "sdl")]
+pub fn loop_emulation(rom: Vec<Byte>, config: &Config, replay: Option<Movie>) -> Result<(), Box<dyn Error>> {
+    let context = sdl::init();
+    let mut live_input = SdlInput::new(&context);
+    let audio : Box<dyn Audio> = match SdlAudio::new(&context) {
+        Ok(audio) => Box::new(audio),
+        Err(e) => {
+            warn!("Could not open an audio device, running without sound: {}", e);
+            Box::new(SilentAudio)
+        }
+    };
+
+    let mut state = Chip8State::new(Box::new(SdlVideo::new(&context)), audio);
+    state.set_platform(config.platform);
+    state.set_quirks(config.quirks);
+    state.set_tracer(open_trace(config)?);
+    let mut recording = config.record.as_ref()
+        .map(|_| Movie::new(config.platform, config.quirks, config.clock_hz, config.seed.unwrap_or_else(rng::random_seed), &rom));
+    let seed = recording.as_ref().or(replay.as_ref()).map(|movie| movie.seed).or(config.seed);
+    if let Some(seed) = seed {
+        state.seed_rng(seed);
+    }
+    state.load_instructions(rom)?;
+    state.load_font();
+    if let Some(ref filename) = config.load_state {
+        state.load_state(&fs::read(filename)?).map_err(|e| format!("{}: {}", filename, e))?;
+    }
+
+    // Going back in time would make the run impossible to reproduce.
+    let can_go_back = recording.is_none() && replay.is_none();
+    let mut playback;
+    let input: &mut dyn InputSource = match replay {
+        Some(ref movie) => {
+            playback = Playback::with_live_input(movie.frames.clone(), &mut live_input);
+            &mut playback
+        },
+        None => &mut live_input
+    };
+
+    let mut scheduler = Scheduler::with_clock_speed(config.clock_hz);
+    let mut events = Vec::new();
+    // A fault still ends with the trace and recording written, so the run
+    // that hit it can be looked at and replayed.
+    let mut failure = None;
+
+    if config.debug || config.gdb.is_some() {
+        let mut step = |state: &mut Chip8State| -> Result<bool, EmulatorError> {
+            // Events belong to the frame they were polled at, so only look
+            // at them once per frame however many steps it takes.
+            let new_frame = scheduler.at_frame_start();
+            scheduler.step(state, input, &mut events)?;
+            record_frame(&mut recording, &scheduler, state);
+            if new_frame {
+                handle_save_states(state, &events, &config.filename, can_go_back);
+            }
+            if scheduler.at_frame_start() {
+                scheduler.wait_for_next_frame();
+            }
+            Ok((new_frame && quit_requested(&events)) || program_exited(state))
+        };
+        if let Some(port) = config.gdb {
+            info!("Waiting for GDB to connect to port {}", port);
+            GdbStub::listen(port)?.serve(&mut state, &mut step)?;
+        } else {
+            debug_on_command_line(&mut state, &mut step)?;
+        }
+    } else {
+        let mut rewind = Rewind::new(config.rewind_budget);
         loop {
-            let mut input = String::new();
-            stdin.read_line(&mut input)?;
-            if input.trim() == "n" {
-                state.run_next_cycle()?;
-            } else if input.trim() == "exit" {
-                break;
+            scheduler.poll(&mut state, input, &mut events);
+            // Each frame with the key held goes back one instead of running.
+            if can_go_back && events.contains(&FrontendEvent::Rewind) {
+                scheduler.skip_frame();
+                if rewind.rewind(&mut state) {
+                    state.present();
+                }
             } else {
-                error!("Invalid command: {}", input);
-                continue;
+                if let Err(e) = scheduler.run_frame(&mut state, input, &mut events) {
+                    // Keep the keys of the frame that faulted so a replay gets there too.
+                    if let Some(ref mut movie) = recording {
+                        movie.frames.push(state.keypad());
+                    }
+                    failure = Some(halt(&state, e));
+                    break;
+                }
+                record_frame(&mut recording, &scheduler, &state);
+                if can_go_back {
+                    rewind.record(&state);
+                }
+            }
+            handle_save_states(&mut state, &events, &config.filename, can_go_back);
+            if quit_requested(&events) || program_exited(&state) {
+                break;
             }
+            scheduler.wait_for_next_frame();
         }
-    } else {
-        while let Ok(()) = state.run_next_cycle() {
-            thread::sleep(time::Duration::from_millis(2));
+    }
+
+    state.flush_trace()?;
+    if let (Some(mut movie), Some(ref filename)) = (recording, config.record.as_ref()) {
+        movie.final_hash = Some(state.state_hash());
+        fs::write(filename, movie.to_string())?;
+        info!("Recorded {} frames to {}", movie.frames.len(), filename);
+    }
+    if let Some(error) = failure {
+        return Err(error);
+    }
+    if let Some(movie) = replay {
+        if movie.final_hash == Some(state.state_hash()) {
+            info!("Replay finished in the recorded state");
+        } else {
+            warn!("Replay finished in a different state to the recording");
         }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Runs the `--debug` prompt until the user quits or the program ends.
+#[cfg(feature = "sdl")]
+fn debug_on_command_line(state: &mut Chip8State, step: &mut dyn FnMut(&mut Chip8State) -> Result<bool, EmulatorError>) -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    let mut debugger = Debugger::new();
+    loop {
+        print!("(chip8) ");
+        stdout.flush()?;
+        let mut input_line = String::new();
+        if stdin.read_line(&mut input_line)? == 0 {
+            return Ok(());
+        }
+        let reply = debugger.execute(&input_line, state, step);
+        print!("{}", reply.output);
+        if reply.quit {
+            return Ok(());
+        }
+    }
+}
+
+/// Adds the keypad state of a frame that has just finished to the movie.
+#[cfg(feature = "sdl")]
+fn record_frame(recording: &mut Option<Movie>, scheduler: &Scheduler, state: &Chip8State) {
+    if let Some(ref mut movie) = *recording {
+        if scheduler.frames() > movie.frames.len() as u64 {
+            movie.frames.push(state.keypad());
+        }
+    }
+}
+
+/// Logs the machine state after the ROM hit an error, for the bug report.
+#[cfg(feature = "sdl")]
+fn halt(state: &Chip8State, error: EmulatorError) -> Box<dyn Error> {
+    error!("Emulation stopped: {}", error);
+    error!("{:?}", state.cpu());
+    Box::new(error)
+}
+
+/// Saves to or loads from the numbered slots the user asked for. Slots are
+/// files next to the ROM, so a failure is reported but doesn't stop the game.
+#[cfg(feature = "sdl")]
+fn handle_save_states(state: &mut Chip8State, events: &[FrontendEvent], rom_filename: &str, can_load: bool) {
+    for event in events {
+        match *event {
+            FrontendEvent::SaveState(slot) => {
+                let path = slot_path(rom_filename, slot);
+                match fs::write(&path, state.save_state()) {
+                    Ok(()) => info!("Saved state to {}", path),
+                    Err(e) => warn!("Could not save state to {}: {}", path, e)
+                }
+            },
+            FrontendEvent::LoadState(_) if !can_load => {
+                warn!("Save states can't be loaded while recording or replaying");
+            },
+            FrontendEvent::LoadState(slot) => {
+                let path = slot_path(rom_filename, slot);
+                let result = fs::read(&path).map_err(|e| e.to_string())
+                    .and_then(|bytes| state.load_state(&bytes).map_err(|e| e.to_string()));
+                match result {
+                    Ok(()) => info!("Loaded state from {}", path),
+                    Err(e) => warn!("Could not load state from {}: {}", path, e)
+                }
+            },
+            FrontendEvent::Quit | FrontendEvent::Rewind => {}
+        }
+    }
+}
+
+#[cfg(feature = "sdl")]
+fn slot_path(rom_filename: &str, slot: u8) -> String {
+    format!("{}.state{}", rom_filename, slot)
+}
+
+#[cfg(feature = "sdl")]
+fn program_exited(state: &Chip8State) -> bool {
+    if state.cpu().halted() {
+        info!("Program exited");
+        return true;
+    }
+    false
+}
+
+#[cfg(feature = "sdl")]
+fn quit_requested(events: &[FrontendEvent]) -> bool {
+    if events.contains(&FrontendEvent::Quit) {
+        info!("User exited program!");
+        return true;
+    }
+    false
+}
+
+#[cfg(not(feature = "sdl"))]
+pub fn loop_emulation(_rom: Vec<Byte>, _config: &Config, _replay: Option<Movie>) -> Result<(), Box<dyn Error>> {
+    Err(From::from("this build has no frontend; rebuild with the `sdl` feature to open a window"))
+}
@@ -1,10 +1,19 @@
 use cpu::Cpu;
 use memory::*;
-use display::Display;
+use display::{Framebuffer, Video};
+use audio::{Audio, SilentAudio};
 use decoder;
-use decoder::Instruction;
-use input::Input;
-use font::FONTS;
+use hash;
+use error::EmulatorError;
+use font::{FONTS, BIG_FONTS};
+use input::Keypad;
+use quirks::Quirks;
+use platform::Platform;
+use rng::Rng;
+use savestate::{StateWriter, StateReader, SaveStateError};
+use trace::{Tracer, Record, Snapshot};
+
+use std::io;
 
 pub const PC_START : u16 = 0x200;
 pub const FONT_START : u16 = 0x0;
@@ -12,76 +21,501 @@ pub const FONT_START : u16 = 0x0;
 pub struct Chip8State {
     cpu: Cpu,
     memory: Memory,
-    display: Display,
-    input: Input
+    video: Box<dyn Video>,
+    audio: Box<dyn Audio>,
+    keypad: Keypad,
+    cycles: u64,
+    tracer: Option<Tracer>
 }
 
 impl Chip8State {
-    pub fn new() -> Chip8State {
-        let display = Display::new();
-        let input = Input::new(display.get_context());
+    pub fn new(video: Box<dyn Video>, audio: Box<dyn Audio>) -> Chip8State {
         Chip8State {
             cpu: Cpu::new(),
             memory: Memory::new(4096),
-            display,
-            input
+            video,
+            audio,
+            keypad: Keypad::new(),
+            cycles: 0,
+            tracer: None
         }
     }
 
-    pub fn load_instructions(&mut self, program: Vec<Byte>) {
-        let mut address = Word::new_from_full(PC_START);
-        for byte in program {
-            self.memory.write(&address, byte);
-            address += 1;
+    pub fn load_instructions(&mut self, program: Vec<Byte>) -> Result<(), EmulatorError> {
+        let capacity = self.memory.size() - PC_START as usize;
+        if program.len() > capacity {
+            return Err(EmulatorError::RomTooLarge { size: program.len(), capacity });
         }
+        self.memory.write_slice(&Word::new_from_full(PC_START), &program)
+            .map_err(|error| EmulatorError::from_memory(error, PC_START))?;
 
         self.memory.print_mem_section(PC_START, 0x300);
+        Ok(())
     }
 
     pub fn load_font(&mut self) {
-        let mut address = Word::new_from_full(FONT_START);
-        for byte in FONTS.iter() {
-            self.memory.write(&address, *byte);
-            address += 1;
-        }
+        self.memory.write_slice(&Word::new_from_full(FONT_START), &FONTS)
+            .expect("font fits in memory");
+        self.memory.write_slice(&Word::new_from_full(BIG_FONT_START), &BIG_FONTS)
+            .expect("font fits in memory");
 
-        self.memory.print_mem_section(FONT_START, 0x81);
+        self.memory.print_mem_section(FONT_START, BIG_FONT_START + BIG_FONTS.len() as u16);
     }
 
-    pub fn run_next_cycle(&mut self) -> Result<(), &'static str> {
-        let opcode = self.fetch_instruction();
-        let instruction = decoder::decode(opcode);
-        
-        if let Instruction::Unknown(op) = instruction {
-            error!("Fatal: {} is unknown opcode", op);
-            panic!();
-        } else {
-            debug!("{:04X} -- {:?}", self.cpu.program_counter().full(), instruction)
-        }
+    /// Switches instruction set. This also resizes memory, so must be done
+    /// before loading the font and program.
+    pub fn set_platform(&mut self, platform: Platform) {
+        self.cpu.set_platform(platform);
+        let mut memory = Memory::new(platform.memory_size());
+        self.memory.move_watches_to(&mut memory);
+        self.memory = memory;
+    }
 
-        let keys = self.input.poll()?;
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.cpu.seed_rng(seed);
+    }
+
+    pub fn set_rng(&mut self, rng: Rng) {
+        self.cpu.set_rng(rng);
+    }
 
-        self.cpu.run_instruction(instruction, &mut self.memory, &mut self.display, &keys);
+    /// A hash of everything `save_state` covers, for checking two runs ended
+    /// up in the same place.
+    pub fn state_hash(&self) -> u64 {
+        hash::fnv1a(&self.save_state())
+    }
 
-        if self.display.vram_changed() {
-            self.display.refresh_display();
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.cpu.set_quirks(quirks);
+    }
+
+    /// Sets the keypad state seen by the following instructions.
+    pub fn set_keypad(&mut self, keypad: Keypad) {
+        self.keypad = keypad;
+    }
+
+    pub fn keypad(&self) -> Keypad {
+        self.keypad
+    }
+
+    /// Fetches, decodes and runs one instruction. On error the program counter
+    /// is left pointing at the faulting instruction. Does nothing once the
+    /// program has exited.
+    pub fn run_next_cycle(&mut self) -> Result<(), EmulatorError> {
+        if self.cpu.halted() {
+            return Ok(());
         }
 
+        let pc = self.cpu.program_counter().clone();
+        let before = match self.tracer {
+            Some(ref tracer) if tracer.wants(pc.full()) => {
+                self.memory.log_writes();
+                Some(Snapshot::take(&self.cpu, &self.memory))
+            },
+            _ => None
+        };
+        let mut opcode = None;
+        let result = match self.fetch_instruction() {
+            Ok(fetched) => {
+                opcode = Some(fetched.full());
+                let instruction = decoder::decode(fetched, self.cpu.platform());
+                debug!("{:04X} -- {:?}", pc.full(), instruction);
+                self.cpu.run_instruction(instruction, &mut self.memory, &mut *self.video, &self.keypad)
+            },
+            Err(error) => Err(error)
+        };
+
+        if result.is_err() {
+            *self.cpu.program_counter_mut() = pc;
+            self.memory.take_writes();
+            return result;
+        }
         debug!("{:?}", self.cpu);
 
-        Ok(())
+        if let (Some(before), Some(opcode)) = (before, opcode) {
+            let record = Record {
+                cycle: self.cycles,
+                pc: pc.full(),
+                opcode,
+                changes: before.changes_to(&Snapshot::take(&self.cpu, &self.memory)),
+                writes: self.memory.take_writes()
+            };
+            self.trace(&record);
+        }
+        self.cycles += 1;
+        result
+    }
+
+    /// Number of instructions run so far.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Starts writing a trace of every instruction run, or stops if `None`.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn tracer_mut(&mut self) -> Option<&mut Tracer> {
+        self.tracer.as_mut()
     }
 
-    pub fn fetch_instruction(&mut self) -> Word {
-        let mut counter = self.cpu.program_counter_mut();
+    /// Writes out whatever the tracer has buffered.
+    pub fn flush_trace(&mut self) -> io::Result<()> {
+        match self.tracer {
+            Some(ref mut tracer) => tracer.flush(),
+            None => Ok(())
+        }
+    }
+
+    /// A trace that can't be written shouldn't stop the game, so it is
+    /// reported and tracing stops.
+    fn trace(&mut self, record: &Record) {
+        if let Some(ref mut tracer) = self.tracer {
+            if let Err(e) = tracer.record(record) {
+                warn!("Could not write the trace, stopping it: {}", e);
+                self.tracer = None;
+            }
+        }
+    }
+
+    pub fn fetch_instruction(&mut self) -> Result<Word, EmulatorError> {
+        let pc = self.cpu.program_counter().full();
+        let fault = |error| EmulatorError::from_memory(error, pc);
+        let counter = self.cpu.program_counter_mut();
 
-        let high_byte = self.memory.read(&counter);
-        counter += 1;
-        let low_byte = self.memory.read(&counter);
-        counter +=1;
+        let high_byte = self.memory.peek(counter).map_err(fault)?;
+        *counter += 1;
+        let low_byte = self.memory.peek(counter).map_err(fault)?;
+        *counter += 1;
 
         debug!("Program counter: {:?}", counter);
         debug!("Instruction fetched: {:02x}{:02x}", high_byte, low_byte);
-        Word::new_from_bytes(high_byte, low_byte)
-    }  
-}
\ No newline at end of file
+        Ok(Word::new_from_bytes(high_byte, low_byte))
+    }
+
+    /// Snapshots the whole machine: CPU, memory including the stack, and the
+    /// framebuffer. The keypad isn't included as it belongs to the frontend.
+    pub fn save_state(&self) -> Vec<Byte> {
+        let mut writer = StateWriter::new();
+        self.cpu.write_state(&mut writer);
+        self.memory.write_state(&mut writer);
+        self.video.framebuffer().write_state(&mut writer);
+        writer.finish()
+    }
+
+    /// Restores a snapshot from `save_state`. Nothing is changed if the
+    /// state can't be read.
+    pub fn load_state(&mut self, bytes: &[Byte]) -> Result<(), SaveStateError> {
+        let mut reader = StateReader::new(bytes)?;
+        let cpu = Cpu::read_state(&mut reader)?;
+        let mut memory = Memory::read_state(&mut reader)?;
+        let framebuffer = Framebuffer::read_state(&mut reader)?;
+        reader.finish()?;
+        if memory.size() != cpu.platform().memory_size() {
+            return Err(SaveStateError::Invalid("memory size"));
+        }
+
+        // Watchpoints belong to the debugger rather than the machine.
+        self.memory.move_watches_to(&mut memory);
+        self.cpu = cpu;
+        self.memory = memory;
+        *self.video.framebuffer_mut() = framebuffer;
+        Ok(())
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    pub fn framebuffer(&self) -> &Framebuffer {
+        self.video.framebuffer()
+    }
+
+    /// Hands the framebuffer to the video backend if anything was drawn since
+    /// the last call.
+    pub fn present(&mut self) {
+        self.video.present();
+    }
+
+    /// Sounds the buzzer for this tick if the sound timer is running, then
+    /// counts both timers down. Called at 60 Hz by the `Scheduler`.
+    pub fn tick_timers(&mut self) {
+        self.audio.update(self.cpu.sound_active(), self.cpu.audio_pattern().as_ref());
+        self.cpu.tick_timers();
+    }
+}
+
+/// A headless machine drawing into an in-memory `Framebuffer`, without sound.
+impl Default for Chip8State {
+    fn default() -> Chip8State {
+        Chip8State::new(Box::new(Framebuffer::new()), Box::new(SilentAudio))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use emustate::Chip8State;
+    use error::EmulatorError;
+    use quirks::Quirks;
+    use platform::Platform;
+    use memory::Word;
+    use savestate::SaveStateError;
+    use rng::Rng;
+
+    fn run_until_error(program: &[u8]) -> (Chip8State, EmulatorError) {
+        let mut state = Chip8State::default();
+        state.load_instructions(program.to_vec()).unwrap();
+        loop {
+            if let Err(error) = state.run_next_cycle() {
+                return (state, error);
+            }
+        }
+    }
+
+    #[test]
+    fn test_unknown_opcode_stops_at_instruction() {
+        let (state, error) = run_until_error(&[0x60, 0x01, 0xFF, 0xFF]);
+
+        assert_eq!(error, EmulatorError::UnknownOpcode { pc: 0x202, opcode: 0xFFFF });
+        assert_eq!(state.cpu().program_counter().full(), 0x202);
+    }
+
+    #[test]
+    fn test_return_with_empty_stack() {
+        let (_, error) = run_until_error(&[0x00, 0xEE]);
+
+        assert_eq!(error, EmulatorError::StackUnderflow { pc: 0x200 });
+    }
+
+    #[test]
+    fn test_recursion_overflows_stack() {
+        let (_, error) = run_until_error(&[0x22, 0x00]);
+
+        assert_eq!(error, EmulatorError::StackOverflow { pc: 0x200 });
+    }
+
+    #[test]
+    fn test_store_past_end_of_memory() {
+        // LD I, 0xFFE; LD [I], V3
+        let (_, error) = run_until_error(&[0xAF, 0xFE, 0xF3, 0x55]);
+
+        assert_eq!(error, EmulatorError::MemoryFault { pc: 0x202, address: 0x1000 });
+    }
+
+    #[test]
+    fn test_skip_on_key_outside_keypad() {
+        // LD V2, 0x10; SKP V2
+        let (_, error) = run_until_error(&[0x62, 0x10, 0xE2, 0x9E]);
+
+        assert_eq!(error, EmulatorError::InvalidKey { pc: 0x202, key: 0x10 });
+    }
+
+    #[test]
+    fn test_rom_too_large() {
+        let mut state = Chip8State::default();
+
+        let error = state.load_instructions(vec![0; 4096]).unwrap_err();
+        assert_eq!(error, EmulatorError::RomTooLarge { size: 4096, capacity: 0xE00 });
+    }
+
+    fn run_with_quirks(program: &[u8], quirks: Quirks, cycles: usize) -> Chip8State {
+        let mut state = Chip8State::default();
+        state.set_quirks(quirks);
+        state.load_instructions(program.to_vec()).unwrap();
+        for _ in 0..cycles {
+            state.run_next_cycle().unwrap();
+        }
+        state
+    }
+
+    #[test]
+    fn test_shift_quirk_chooses_source_register() {
+        // LD V1, 0x81; SHR V0, V1
+        let program = [0x61, 0x81, 0x80, 0x16];
+
+        let vip = run_with_quirks(&program, Quirks::cosmac_vip(), 2);
+        assert_eq!(vip.cpu().get_reg(0), 0x40);
+        assert_eq!(vip.cpu().get_reg(0xF), 1);
+
+        let schip = run_with_quirks(&program, Quirks::superchip(), 2);
+        assert_eq!(schip.cpu().get_reg(0), 0);
+        assert_eq!(schip.cpu().get_reg(0xF), 0);
+    }
+
+    #[test]
+    fn test_load_store_quirk_moves_i() {
+        // LD I, 0x300; LD [I], V2
+        let program = [0xA3, 0x00, 0xF2, 0x55];
+
+        assert_eq!(run_with_quirks(&program, Quirks::cosmac_vip(), 2).cpu().i_register().full(), 0x303);
+        assert_eq!(run_with_quirks(&program, Quirks::chip48(), 2).cpu().i_register().full(), 0x302);
+        assert_eq!(run_with_quirks(&program, Quirks::superchip(), 2).cpu().i_register().full(), 0x300);
+    }
+
+    #[test]
+    fn test_jump_quirk_uses_vx() {
+        // LD V0, 0x10; LD V3, 0x20; JP V0, 0x300
+        let program = [0x60, 0x10, 0x63, 0x20, 0xB3, 0x00];
+
+        assert_eq!(run_with_quirks(&program, Quirks::cosmac_vip(), 3).cpu().program_counter().full(), 0x310);
+        assert_eq!(run_with_quirks(&program, Quirks::chip48(), 3).cpu().program_counter().full(), 0x320);
+    }
+
+    #[test]
+    fn test_vf_reset_quirk() {
+        // LD VF, 0x05; OR V0, V1
+        let program = [0x6F, 0x05, 0x80, 0x11];
+
+        assert_eq!(run_with_quirks(&program, Quirks::cosmac_vip(), 2).cpu().get_reg(0xF), 0);
+        assert_eq!(run_with_quirks(&program, Quirks::superchip(), 2).cpu().get_reg(0xF), 5);
+    }
+
+    #[test]
+    fn test_superchip_instructions() {
+        // HIGH; LD V5, 0x07; LD HF, V5; LD R, V5; LD V5, 0; LD V5, R; EXIT; CLS
+        let program = [0x00, 0xFF, 0x65, 0x07, 0xF5, 0x30, 0xF5, 0x75, 0x65, 0x00, 0xF5, 0x85, 0x00, 0xFD, 0x00, 0xE0];
+        let mut state = Chip8State::default();
+        state.set_platform(Platform::SuperChip);
+        state.load_instructions(program.to_vec()).unwrap();
+        for _ in 0..8 {
+            state.run_next_cycle().unwrap();
+        }
+
+        assert_eq!(state.framebuffer().width(), 128);
+        assert_eq!(state.cpu().i_register().full(), 0x50 + 7 * 10);
+        assert_eq!(state.cpu().get_reg(5), 0x07);
+        assert!(state.cpu().halted());
+        assert_eq!(state.cpu().program_counter().full(), 0x20E);
+    }
+
+    #[test]
+    fn test_superchip_opcodes_unknown_on_chip8() {
+        let (_, error) = run_until_error(&[0x00, 0xFF]);
+
+        assert_eq!(error, EmulatorError::UnknownOpcode { pc: 0x200, opcode: 0x00FF });
+    }
+
+    fn run_xochip(program: &[u8], cycles: usize) -> Chip8State {
+        let mut state = Chip8State::default();
+        state.set_platform(Platform::XoChip);
+        state.load_instructions(program.to_vec()).unwrap();
+        for _ in 0..cycles {
+            state.run_next_cycle().unwrap();
+        }
+        state
+    }
+
+    #[test]
+    fn test_xochip_long_load_and_skip() {
+        // LD I, long 0x1234; LD V0, 0; SE V0, 0; LD I, long 0x0300; LD V1, 7
+        let program = [0xF0, 0x00, 0x12, 0x34, 0x60, 0x00, 0x30, 0x00, 0xF0, 0x00, 0x03, 0x00, 0x61, 0x07];
+        let state = run_xochip(&program, 4);
+
+        assert_eq!(state.memory().size(), 0x10000);
+        assert_eq!(state.cpu().i_register().full(), 0x1234);
+        assert_eq!(state.cpu().get_reg(1), 7);
+        assert_eq!(state.cpu().program_counter().full(), 0x20E);
+    }
+
+    #[test]
+    fn test_xochip_register_ranges() {
+        // LD V0, 1; LD V1, 2; LD V2, 3; LD I, 0x300; SAVE V0 - V2; LOAD V2 - V0
+        let program = [0x60, 0x01, 0x61, 0x02, 0x62, 0x03, 0xA3, 0x00, 0x50, 0x22, 0x52, 0x03];
+        let state = run_xochip(&program, 6);
+
+        assert_eq!(state.memory().read(&Word::new_from_full(0x302)), Ok(3));
+        assert_eq!(state.cpu().get_reg(0), 3);
+        assert_eq!(state.cpu().get_reg(2), 1);
+        assert_eq!(state.cpu().i_register().full(), 0x300);
+    }
+
+    #[test]
+    fn test_save_state_round_trip() {
+        // LD V0, 10; LD DT, V0; CALL 0x20A; JP 0x204; padding;
+        // 0x20A: LD F, V1; DRW V1, V1, 5; ADD V1, 1; RET
+        let program = [0x60, 0x0A, 0xF0, 0x15, 0x22, 0x0A, 0x12, 0x04, 0x00, 0x00,
+                       0xF1, 0x29, 0xD1, 0x15, 0x71, 0x01, 0x00, 0xEE];
+        let mut original = Chip8State::default();
+        original.load_instructions(program.to_vec()).unwrap();
+        original.load_font();
+        for _ in 0..7 {
+            original.run_next_cycle().unwrap();
+        }
+        original.tick_timers();
+        let saved = original.save_state();
+
+        let mut restored = Chip8State::default();
+        restored.load_state(&saved).unwrap();
+        assert_eq!(restored.save_state(), saved);
+
+        for _ in 0..50 {
+            original.run_next_cycle().unwrap();
+            restored.run_next_cycle().unwrap();
+            original.tick_timers();
+            restored.tick_timers();
+        }
+        assert_eq!(restored.save_state(), original.save_state());
+        assert_eq!(restored.cpu().delay_timer(), 0);
+        assert!(restored.framebuffer().pixel(3, 3) || restored.framebuffer().pixel(4, 4));
+    }
+
+    #[test]
+    fn test_bad_save_state_changes_nothing() {
+        let mut state = Chip8State::default();
+        state.set_platform(Platform::XoChip);
+        let mut saved = state.save_state();
+        saved.truncate(saved.len() - 1);
+
+        let mut other = Chip8State::default();
+        assert_eq!(other.load_state(&saved), Err(SaveStateError::Truncated));
+        assert_eq!(other.memory().size(), 0x1000);
+    }
+
+    #[test]
+    fn test_rnd_uses_injected_sequence() {
+        // RND V0, 0xFF; RND V1, 0x0F; RND V2, 0xFF
+        let mut state = Chip8State::default();
+        state.load_instructions(vec![0xC0, 0xFF, 0xC1, 0x0F, 0xC2, 0xFF]).unwrap();
+        state.set_rng(Rng::sequence(vec![0xAB, 0xCD]));
+        for _ in 0..3 {
+            state.run_next_cycle().unwrap();
+        }
+
+        assert_eq!(state.cpu().get_reg(0), 0xAB);
+        assert_eq!(state.cpu().get_reg(1), 0x0D);
+        assert_eq!(state.cpu().get_reg(2), 0xAB);
+    }
+
+    #[test]
+    fn test_save_state_keeps_random_sequence() {
+        // RND V0, 0xFF; JP 0x200
+        let program = vec![0xC0, 0xFF, 0x12, 0x00];
+        let mut original = Chip8State::default();
+        original.load_instructions(program.clone()).unwrap();
+        original.seed_rng(7);
+        original.run_next_cycle().unwrap();
+        let saved = original.save_state();
+
+        let mut restored = Chip8State::default();
+        restored.load_instructions(program).unwrap();
+        restored.load_state(&saved).unwrap();
+        for _ in 0..20 {
+            original.run_next_cycle().unwrap();
+            restored.run_next_cycle().unwrap();
+            assert_eq!(restored.cpu().get_reg(0), original.cpu().get_reg(0));
+        }
+    }
+}
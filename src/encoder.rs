@@ -0,0 +1,197 @@
+//! The inverse of `decoder::decode`: turns an `Instruction` back into the
+//! opcode it came from.
+
+use decoder::{Instruction, RegisterVal, RegisterRegister, RegisterRegisterNibble};
+use memory::Word;
+
+pub fn encode(instruction: &Instruction) -> Word {
+    let opcode = match *instruction {
+        Instruction::Unknown(ref opcode) => opcode.full(),
+        Instruction::CLS => 0x00E0,
+        Instruction::RET => 0x00EE,
+        Instruction::JUMP(ref address) => 0x1000 | address.full(),
+        Instruction::CALL(ref address) => 0x2000 | address.full(),
+        Instruction::SE(ref rv) => with_register_val(0x3000, rv),
+        Instruction::SNE(ref rv) => with_register_val(0x4000, rv),
+        Instruction::SER(ref rr) => with_both_registers(0x5000, rr),
+        Instruction::LD(ref rv) => with_register_val(0x6000, rv),
+        Instruction::ADD(ref rv) => with_register_val(0x7000, rv),
+        Instruction::LDR(ref rr) => with_both_registers(0x8000, rr),
+        Instruction::ORR(ref rr) => with_both_registers(0x8001, rr),
+        Instruction::ANDR(ref rr) => with_both_registers(0x8002, rr),
+        Instruction::XORR(ref rr) => with_both_registers(0x8003, rr),
+        Instruction::ADDR(ref rr) => with_both_registers(0x8004, rr),
+        Instruction::SUBR(ref rr) => with_both_registers(0x8005, rr),
+        Instruction::SHR(ref rr) => with_both_registers(0x8006, rr),
+        Instruction::SUBNR(ref rr) => with_both_registers(0x8007, rr),
+        Instruction::SHL(ref rr) => with_both_registers(0x800E, rr),
+        Instruction::SNER(ref rr) => with_both_registers(0x9000, rr),
+        Instruction::LDI(ref address) => 0xA000 | address.full(),
+        Instruction::JUMPV0(ref address) => 0xB000 | address.full(),
+        Instruction::RND(ref rv) => with_register_val(0xC000, rv),
+        Instruction::DRW(ref rrn) => with_both_registers_and_nibble(0xD000, rrn),
+        Instruction::SKP(reg) => with_register(0xE09E, reg),
+        Instruction::SKNP(reg) => with_register(0xE0A1, reg),
+        Instruction::LDVDT(reg) => with_register(0xF007, reg),
+        Instruction::LDK(reg) => with_register(0xF00A, reg),
+        Instruction::LDDTV(reg) => with_register(0xF015, reg),
+        Instruction::LDSTV(reg) => with_register(0xF018, reg),
+        Instruction::ADDI(reg) => with_register(0xF01E, reg),
+        Instruction::LDFONT(reg) => with_register(0xF029, reg),
+        Instruction::LDBCD(reg) => with_register(0xF033, reg),
+        Instruction::STARR(reg) => with_register(0xF055, reg),
+        Instruction::LDARR(reg) => with_register(0xF065, reg),
+        Instruction::SCD(lines) => 0x00C0 | (lines & 0x0F) as u16,
+        Instruction::SCR => 0x00FB,
+        Instruction::SCL => 0x00FC,
+        Instruction::EXIT => 0x00FD,
+        Instruction::LOW => 0x00FE,
+        Instruction::HIGH => 0x00FF,
+        Instruction::LDHF(reg) => with_register(0xF030, reg),
+        Instruction::STRPL(reg) => with_register(0xF075, reg),
+        Instruction::LDRPL(reg) => with_register(0xF085, reg),
+        Instruction::SCU(lines) => 0x00D0 | (lines & 0x0F) as u16,
+        Instruction::SAVERNG(ref rr) => with_both_registers(0x5002, rr),
+        Instruction::LOADRNG(ref rr) => with_both_registers(0x5003, rr),
+        Instruction::LDILONG => 0xF000,
+        Instruction::PLANE(planes) => with_register(0xF001, planes),
+        Instruction::AUDIO => 0xF002,
+        Instruction::PITCH(reg) => with_register(0xF03A, reg)
+    };
+    Word::new_from_full(opcode)
+}
+
+fn with_register(base: u16, register: u8) -> u16 {
+    base | ((register & 0x0F) as u16) << 8
+}
+
+fn with_register_val(base: u16, register_val: &RegisterVal) -> u16 {
+    with_register(base, register_val.register) | register_val.value as u16
+}
+
+fn with_both_registers(base: u16, registers: &RegisterRegister) -> u16 {
+    with_register(base, registers.first_reg) | ((registers.second_reg & 0x0F) as u16) << 4
+}
+
+fn with_both_registers_and_nibble(base: u16, registers_nibble: &RegisterRegisterNibble) -> u16 {
+    let registers = RegisterRegister {
+        first_reg: registers_nibble.first_reg,
+        second_reg: registers_nibble.second_reg
+    };
+    with_both_registers(base, &registers) | (registers_nibble.nibble & 0x0F) as u16
+}
+
+#[cfg(test)]
+mod test {
+    use decoder::{self, Instruction};
+    use encoder;
+    use memory::Word;
+    use platform::Platform;
+
+    // One opcode for every instruction the decoder knows about.
+    const OPCODES: [u16; 52] = [
+        0x00E0, 0x00EE, 0x1234, 0x2345, 0x3A12, 0x4B34, 0x5120, 0x6A02, 0x7F01,
+        0x8120, 0x8121, 0x8122, 0x8123, 0x8124, 0x8125, 0x8126, 0x8127, 0x812E,
+        0x9120, 0xA123, 0xB456, 0xC7FF, 0xD125, 0xE39E, 0xE4A1, 0xF507, 0xF60A,
+        0xF715, 0xF818, 0xF91E, 0xFA29, 0xFB33, 0xFC55, 0xFD65, 0x00C3, 0x00FB,
+        0x00FC, 0x00FD, 0x00FE, 0x00FF, 0xF130, 0xF275, 0xF385, 0x00D4, 0x5122,
+        0x5213, 0xF000, 0xF201, 0xF002, 0xF43A, 0x0123, 0xFFFF
+    ];
+
+    #[test]
+    fn test_encode_inverts_decode() {
+        for &opcode in OPCODES.iter() {
+            let instruction = decoder::decode(Word::new_from_full(opcode), Platform::XoChip);
+            assert_eq!(encoder::encode(&instruction).full(), opcode, "{}", instruction);
+        }
+    }
+
+    #[test]
+    fn test_decode_inverts_encode() {
+        for &opcode in OPCODES.iter() {
+            let instruction = decoder::decode(Word::new_from_full(opcode), Platform::XoChip);
+            let again = decoder::decode(encoder::encode(&instruction), Platform::XoChip);
+            assert_eq!(again, instruction);
+        }
+    }
+
+    /// The opcodes each platform defines, written out from the instruction
+    /// set references rather than the decoder: an opcode is defined if
+    /// `opcode & mask == pattern` for a row whose platform is the same or older.
+    const SPEC: &[(u16, u16, Platform)] = &[
+        (0xFFFF, 0x00E0, Platform::Chip8),
+        (0xFFFF, 0x00EE, Platform::Chip8),
+        (0xF000, 0x1000, Platform::Chip8),
+        (0xF000, 0x2000, Platform::Chip8),
+        (0xF000, 0x3000, Platform::Chip8),
+        (0xF000, 0x4000, Platform::Chip8),
+        (0xF00F, 0x5000, Platform::Chip8),
+        (0xF000, 0x6000, Platform::Chip8),
+        (0xF000, 0x7000, Platform::Chip8),
+        (0xF00F, 0x8000, Platform::Chip8),
+        (0xF00F, 0x8001, Platform::Chip8),
+        (0xF00F, 0x8002, Platform::Chip8),
+        (0xF00F, 0x8003, Platform::Chip8),
+        (0xF00F, 0x8004, Platform::Chip8),
+        (0xF00F, 0x8005, Platform::Chip8),
+        (0xF00F, 0x8006, Platform::Chip8),
+        (0xF00F, 0x8007, Platform::Chip8),
+        (0xF00F, 0x800E, Platform::Chip8),
+        (0xF00F, 0x9000, Platform::Chip8),
+        (0xF000, 0xA000, Platform::Chip8),
+        (0xF000, 0xB000, Platform::Chip8),
+        (0xF000, 0xC000, Platform::Chip8),
+        (0xF000, 0xD000, Platform::Chip8),
+        (0xF0FF, 0xE09E, Platform::Chip8),
+        (0xF0FF, 0xE0A1, Platform::Chip8),
+        (0xF0FF, 0xF007, Platform::Chip8),
+        (0xF0FF, 0xF00A, Platform::Chip8),
+        (0xF0FF, 0xF015, Platform::Chip8),
+        (0xF0FF, 0xF018, Platform::Chip8),
+        (0xF0FF, 0xF01E, Platform::Chip8),
+        (0xF0FF, 0xF029, Platform::Chip8),
+        (0xF0FF, 0xF033, Platform::Chip8),
+        (0xF0FF, 0xF055, Platform::Chip8),
+        (0xF0FF, 0xF065, Platform::Chip8),
+        (0xFFF0, 0x00C0, Platform::SuperChip),
+        (0xFFFF, 0x00FB, Platform::SuperChip),
+        (0xFFFF, 0x00FC, Platform::SuperChip),
+        (0xFFFF, 0x00FD, Platform::SuperChip),
+        (0xFFFF, 0x00FE, Platform::SuperChip),
+        (0xFFFF, 0x00FF, Platform::SuperChip),
+        (0xF0FF, 0xF030, Platform::SuperChip),
+        (0xF0FF, 0xF075, Platform::SuperChip),
+        (0xF0FF, 0xF085, Platform::SuperChip),
+        (0xFFF0, 0x00D0, Platform::XoChip),
+        (0xF00F, 0x5002, Platform::XoChip),
+        (0xF00F, 0x5003, Platform::XoChip),
+        (0xFFFF, 0xF000, Platform::XoChip),
+        (0xF0FF, 0xF001, Platform::XoChip),
+        (0xFFFF, 0xF002, Platform::XoChip),
+        (0xF0FF, 0xF03A, Platform::XoChip)
+    ];
+
+    fn defined(opcode: u16, platform: Platform) -> bool {
+        let age = |platform| [Platform::Chip8, Platform::SuperChip, Platform::XoChip].iter().position(|&p| p == platform);
+        SPEC.iter().any(|&(mask, pattern, since)| opcode & mask == pattern && age(since) <= age(platform))
+    }
+
+    #[test]
+    fn test_every_opcode_on_every_platform() {
+        for &platform in &[Platform::Chip8, Platform::SuperChip, Platform::XoChip] {
+            for opcode in 0..=0xFFFF {
+                let instruction = decoder::decode(Word::new_from_full(opcode), platform);
+                match instruction {
+                    Instruction::Unknown(ref word) => {
+                        assert_eq!(word.full(), opcode);
+                        assert!(!defined(opcode, platform), "{:04X} should decode on {:?}", opcode, platform);
+                    },
+                    _ => {
+                        assert!(defined(opcode, platform), "{:04X} decodes as `{}` on {:?} but isn't defined there", opcode, instruction, platform);
+                        assert_eq!(encoder::encode(&instruction).full(), opcode, "`{}` on {:?}", instruction, platform);
+                    }
+                }
+            }
+        }
+    }
+}
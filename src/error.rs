@@ -0,0 +1,59 @@
+use std::error::Error;
+use std::fmt;
+
+use memory::MemoryError;
+
+/// Everything that can stop the emulated machine. `pc` is always the address
+/// of the instruction that caused the error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EmulatorError {
+    UnknownOpcode { pc: u16, opcode: u16 },
+    StackUnderflow { pc: u16 },
+    StackOverflow { pc: u16 },
+    MemoryFault { pc: u16, address: usize },
+    InvalidKey { pc: u16, key: u8 },
+    RomTooLarge { size: usize, capacity: usize }
+}
+
+impl EmulatorError {
+    pub fn from_memory(error: MemoryError, pc: u16) -> EmulatorError {
+        match error {
+            MemoryError::OutOfRange(address) => EmulatorError::MemoryFault { pc, address },
+            MemoryError::StackOverflow => EmulatorError::StackOverflow { pc },
+            MemoryError::StackUnderflow => EmulatorError::StackUnderflow { pc }
+        }
+    }
+
+    /// The address of the faulting instruction, if the error came from one.
+    pub fn pc(&self) -> Option<u16> {
+        match *self {
+            EmulatorError::UnknownOpcode { pc, .. } |
+            EmulatorError::StackUnderflow { pc } |
+            EmulatorError::StackOverflow { pc } |
+            EmulatorError::MemoryFault { pc, .. } |
+            EmulatorError::InvalidKey { pc, .. } => Some(pc),
+            EmulatorError::RomTooLarge { .. } => None
+        }
+    }
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EmulatorError::UnknownOpcode { pc, opcode } =>
+                write!(f, "unknown opcode {:04X} at {:04X}", opcode, pc),
+            EmulatorError::StackUnderflow { pc } =>
+                write!(f, "return with an empty call stack at {:04X}", pc),
+            EmulatorError::StackOverflow { pc } =>
+                write!(f, "call stack overflow at {:04X}", pc),
+            EmulatorError::MemoryFault { pc, address } =>
+                write!(f, "memory access out of range at address {:04X} by instruction at {:04X}", address, pc),
+            EmulatorError::InvalidKey { pc, key } =>
+                write!(f, "key {:02X} is not on the keypad, tested at {:04X}", key, pc),
+            EmulatorError::RomTooLarge { size, capacity } =>
+                write!(f, "ROM is {} bytes but only {} bytes of program memory are available", size, capacity)
+        }
+    }
+}
+
+impl Error for EmulatorError {}
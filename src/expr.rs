@@ -0,0 +1,292 @@
+//! Expressions over the machine state for the debugger, e.g.
+//! `V3 == 0x10 && I > 0x300`. Operators and precedence follow C; `[addr]`
+//! reads a byte of memory. Values are integers and anything non-zero is true.
+
+use std::fmt;
+
+use emustate::Chip8State;
+use memory::Word;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    V(u8),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(i64),
+    Register(Operand),
+    Memory(Box<Expr>),
+    Unary(char, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>)
+}
+
+/// Binary operators from loosest to tightest binding.
+const PRECEDENCE: [&[&str]; 10] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<=", ">=", "<", ">"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"]
+];
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens: &tokens, position: 0 };
+        let expr = parser.binary(0)?;
+        match parser.tokens.get(parser.position) {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected `{}`", token))
+        }
+    }
+
+    /// Memory outside the address space reads as 0; division by zero gives 0.
+    pub fn evaluate(&self, state: &Chip8State) -> i64 {
+        match *self {
+            Expr::Number(value) => value,
+            Expr::Register(operand) => {
+                let cpu = state.cpu();
+                match operand {
+                    Operand::V(index) => cpu.get_reg(index) as i64,
+                    Operand::I => cpu.i_register().full() as i64,
+                    Operand::Pc => cpu.program_counter().full() as i64,
+                    Operand::Sp => state.memory().stack().len() as i64,
+                    Operand::Dt => cpu.delay_timer() as i64,
+                    Operand::St => cpu.sound_timer() as i64
+                }
+            },
+            Expr::Memory(ref address) => {
+                let address = address.evaluate(state);
+                if !(0..=0xFFFF).contains(&address) {
+                    return 0;
+                }
+                state.memory().peek(&Word::new_from_full(address as u16)).unwrap_or(0) as i64
+            },
+            Expr::Unary(operator, ref operand) => {
+                let value = operand.evaluate(state);
+                match operator {
+                    '-' => value.wrapping_neg(),
+                    '~' => !value,
+                    _ => (value == 0) as i64
+                }
+            },
+            Expr::Binary(operator, ref left, ref right) => {
+                let left = left.evaluate(state);
+                // Like C, the logical operators don't look at the right side
+                // once the answer is known.
+                match operator {
+                    "&&" => return (left != 0 && right.evaluate(state) != 0) as i64,
+                    "||" => return (left != 0 || right.evaluate(state) != 0) as i64,
+                    _ => {}
+                }
+                let right = right.evaluate(state);
+                match operator {
+                    "|" => left | right,
+                    "^" => left ^ right,
+                    "&" => left & right,
+                    "==" => (left == right) as i64,
+                    "!=" => (left != right) as i64,
+                    "<=" => (left <= right) as i64,
+                    ">=" => (left >= right) as i64,
+                    "<" => (left < right) as i64,
+                    ">" => (left > right) as i64,
+                    "<<" => left.wrapping_shl(right as u32),
+                    ">>" => left.wrapping_shr(right as u32),
+                    "+" => left.wrapping_add(right),
+                    "-" => left.wrapping_sub(right),
+                    "*" => left.wrapping_mul(right),
+                    "/" => left.checked_div(right).unwrap_or(0),
+                    _ => left.checked_rem(right).unwrap_or(0)
+                }
+            }
+        }
+    }
+
+    pub fn is_true(&self, state: &Chip8State) -> bool {
+        self.evaluate(state) != 0
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expr::Number(value) => write!(f, "0x{:X}", value),
+            Expr::Register(Operand::V(index)) => write!(f, "V{:X}", index),
+            Expr::Register(Operand::I) => write!(f, "I"),
+            Expr::Register(Operand::Pc) => write!(f, "PC"),
+            Expr::Register(Operand::Sp) => write!(f, "SP"),
+            Expr::Register(Operand::Dt) => write!(f, "DT"),
+            Expr::Register(Operand::St) => write!(f, "ST"),
+            Expr::Memory(ref address) => write!(f, "[{}]", address),
+            Expr::Unary(operator, ref operand) => write!(f, "{}{}", operator, operand),
+            Expr::Binary(operator, ref left, ref right) => write!(f, "({} {} {})", left, operator, right)
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(word);
+        } else {
+            chars.next();
+            let pair: String = [c, chars.peek().cloned().unwrap_or(' ')].iter().collect();
+            if PRECEDENCE.iter().any(|level| level.contains(&pair.as_str())) {
+                chars.next();
+                tokens.push(pair);
+            } else if "+-*/%&|^<>!~()[]".contains(c) {
+                tokens.push(c.to_string());
+            } else {
+                return Err(format!("unexpected `{}`", c));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [String],
+    position: usize
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> Option<&'a str> {
+        let token = self.tokens.get(self.position)?;
+        self.position += 1;
+        Some(token)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(format!("expected `{}` but found `{}`", expected, token)),
+            None => Err(format!("expected `{}` at the end", expected))
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Some(&operator) = self.tokens.get(self.position)
+            .and_then(|token| PRECEDENCE[level].iter().find(|operator| **operator == token.as_str())) {
+            self.position += 1;
+            let right = self.binary(level + 1)?;
+            left = Expr::Binary(operator, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some("-") => Ok(Expr::Unary('-', Box::new(self.unary()?))),
+            Some("~") => Ok(Expr::Unary('~', Box::new(self.unary()?))),
+            Some("!") => Ok(Expr::Unary('!', Box::new(self.unary()?))),
+            Some("(") => {
+                let expr = self.binary(0)?;
+                self.expect(")")?;
+                Ok(expr)
+            },
+            Some("[") => {
+                let address = self.binary(0)?;
+                self.expect("]")?;
+                Ok(Expr::Memory(Box::new(address)))
+            },
+            Some(token) => operand(token),
+            None => Err(String::from("expression ends too early"))
+        }
+    }
+}
+
+fn operand(token: &str) -> Result<Expr, String> {
+    let upper = token.to_ascii_uppercase();
+    let register = match upper.as_str() {
+        "I" => Some(Operand::I),
+        "PC" => Some(Operand::Pc),
+        "SP" => Some(Operand::Sp),
+        "DT" => Some(Operand::Dt),
+        "ST" => Some(Operand::St),
+        _ if upper.len() == 2 && upper.starts_with('V') => u8::from_str_radix(&upper[1..], 16).ok().map(Operand::V),
+        _ => None
+    };
+    if let Some(register) = register {
+        return Ok(Expr::Register(register));
+    }
+
+    let parsed = if let Some(hex) = upper.strip_prefix("0X") {
+        i64::from_str_radix(hex, 16)
+    } else if let Some(binary) = upper.strip_prefix("0B") {
+        i64::from_str_radix(binary, 2)
+    } else {
+        upper.parse()
+    };
+    parsed.map(Expr::Number).map_err(|_| format!("`{}` is not a number or register", token))
+}
+
+#[cfg(test)]
+mod test {
+    use expr::Expr;
+    use emustate::Chip8State;
+    use memory::Word;
+
+    fn evaluate(text: &str, state: &Chip8State) -> i64 {
+        Expr::parse(text).unwrap().evaluate(state)
+    }
+
+    #[test]
+    fn test_precedence() {
+        let state = Chip8State::default();
+        assert_eq!(evaluate("1 + 2 * 3", &state), 7);
+        assert_eq!(evaluate("(1 + 2) * 3", &state), 9);
+        assert_eq!(evaluate("1 << 4 | 1", &state), 0x11);
+        assert_eq!(evaluate("-2 < 1 && !0", &state), 1);
+        assert_eq!(evaluate("7 / 0 + 7 % 0", &state), 0);
+        assert_eq!(Expr::parse("1 + 2 == 3").unwrap().to_string(), "((0x1 + 0x2) == 0x3)");
+    }
+
+    #[test]
+    fn test_reads_machine_state() {
+        let mut state = Chip8State::default();
+        state.cpu_mut().set_reg(3, 0x10);
+        state.cpu_mut().set_i_register(Word::new_from_full(0x301));
+        state.memory_mut().write(&Word::new_from_full(0x301), 0xAB).unwrap();
+
+        assert!(Expr::parse("V3 == 0x10 && I > 0x300").unwrap().is_true(&state));
+        assert!(!Expr::parse("v3 == 0x10 && i > 0x301").unwrap().is_true(&state));
+        assert_eq!(evaluate("[I] + [0xFFFF]", &state), 0xAB);
+        assert_eq!(evaluate("PC", &state), 0x200);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(Expr::parse("V3 ==").unwrap_err(), "expression ends too early");
+        assert_eq!(Expr::parse("(1").unwrap_err(), "expected `)` at the end");
+        assert_eq!(Expr::parse("1 2").unwrap_err(), "unexpected `2`");
+        assert_eq!(Expr::parse("VG").unwrap_err(), "`VG` is not a number or register");
+        assert_eq!(Expr::parse("1 $ 2").unwrap_err(), "unexpected `$`");
+    }
+}
@@ -94,4 +94,184 @@ pub const FONTS : [u8; 80]= [
     0b11110000,
     0b10000000,
     0b10000000
-];
\ No newline at end of file
+];
+
+/// SUPER-CHIP's 8x10 digits, used by `LD HF, Vx`. SUPER-CHIP 1.1 only has
+/// 0-9; A-F follow the same style so every nibble has a sprite.
+pub const BIG_FONTS : [u8; 160] = [
+    0b00111100,     // 0
+    0b01111110,
+    0b11100111,
+    0b11000011,
+    0b11000011,
+    0b11000011,
+    0b11000011,
+    0b11100111,
+    0b01111110,
+    0b00111100,
+
+    0b00011000,     // 1
+    0b00111000,
+    0b01011000,
+    0b00011000,
+    0b00011000,
+    0b00011000,
+    0b00011000,
+    0b00011000,
+    0b00011000,
+    0b00111100,
+
+    0b00111110,     // 2
+    0b01111111,
+    0b11000011,
+    0b00000110,
+    0b00001100,
+    0b00011000,
+    0b00110000,
+    0b01100000,
+    0b11111111,
+    0b11111111,
+
+    0b00111100,     // 3
+    0b01111110,
+    0b11000011,
+    0b00000011,
+    0b00001110,
+    0b00001110,
+    0b00000011,
+    0b11000011,
+    0b01111110,
+    0b00111100,
+
+    0b00000110,     // 4
+    0b00001110,
+    0b00011110,
+    0b00110110,
+    0b01100110,
+    0b11000110,
+    0b11111111,
+    0b11111111,
+    0b00000110,
+    0b00000110,
+
+    0b11111111,     // 5
+    0b11111111,
+    0b11000000,
+    0b11000000,
+    0b11111100,
+    0b11111110,
+    0b00000011,
+    0b11000011,
+    0b01111110,
+    0b00111100,
+
+    0b00111110,     // 6
+    0b01111100,
+    0b11000000,
+    0b11000000,
+    0b11111100,
+    0b11111110,
+    0b11000011,
+    0b11000011,
+    0b01111110,
+    0b00111100,
+
+    0b11111111,     // 7
+    0b11111111,
+    0b00000011,
+    0b00000110,
+    0b00001100,
+    0b00011000,
+    0b00110000,
+    0b01100000,
+    0b01100000,
+    0b01100000,
+
+    0b00111100,     // 8
+    0b01111110,
+    0b11000011,
+    0b11000011,
+    0b01111110,
+    0b01111110,
+    0b11000011,
+    0b11000011,
+    0b01111110,
+    0b00111100,
+
+    0b00111100,     // 9
+    0b01111110,
+    0b11000011,
+    0b11000011,
+    0b01111111,
+    0b00111111,
+    0b00000011,
+    0b00000011,
+    0b00111110,
+    0b01111100,
+
+    0b00111100,     // A
+    0b01111110,
+    0b11000011,
+    0b11000011,
+    0b11111111,
+    0b11111111,
+    0b11000011,
+    0b11000011,
+    0b11000011,
+    0b11000011,
+
+    0b11111100,     // B
+    0b11111110,
+    0b11000011,
+    0b11000011,
+    0b11111110,
+    0b11111110,
+    0b11000011,
+    0b11000011,
+    0b11111110,
+    0b11111100,
+
+    0b00111100,     // C
+    0b01111110,
+    0b11000011,
+    0b11000000,
+    0b11000000,
+    0b11000000,
+    0b11000000,
+    0b11000011,
+    0b01111110,
+    0b00111100,
+
+    0b11111100,     // D
+    0b11111110,
+    0b11000011,
+    0b11000011,
+    0b11000011,
+    0b11000011,
+    0b11000011,
+    0b11000011,
+    0b11111110,
+    0b11111100,
+
+    0b11111111,     // E
+    0b11111111,
+    0b11000000,
+    0b11000000,
+    0b11111111,
+    0b11111111,
+    0b11000000,
+    0b11000000,
+    0b11111111,
+    0b11111111,
+
+    0b11111111,     // F
+    0b11111111,
+    0b11000000,
+    0b11000000,
+    0b11111111,
+    0b11111111,
+    0b11000000,
+    0b11000000,
+    0b11000000,
+    0b11000000
+];
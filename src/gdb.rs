@@ -0,0 +1,492 @@
+//! A GDB remote serial protocol stub, so GDB (or anything else speaking
+//! RSP) can debug a ROM over TCP. Like the command line debugger it leaves
+//! running the machine to the frontend through a `step` callback.
+//!
+//! Registers are numbered V0-VF (0-15), I (16), PC (17), SP (18), DT (19)
+//! and ST (20). I and PC are 16 bits and sent little endian, the others are
+//! one byte. SP is the call stack depth and can't be written.
+
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use emustate::Chip8State;
+use error::EmulatorError;
+use memory::{Byte, Word, Watch, Access};
+
+const TARGET_XML: &str = "<?xml version=\"1.0\"?>\
+<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+<target version=\"1.0\"><feature name=\"org.chip8.cpu\">\
+<reg name=\"v0\" bitsize=\"8\" regnum=\"0\"/><reg name=\"v1\" bitsize=\"8\"/>\
+<reg name=\"v2\" bitsize=\"8\"/><reg name=\"v3\" bitsize=\"8\"/>\
+<reg name=\"v4\" bitsize=\"8\"/><reg name=\"v5\" bitsize=\"8\"/>\
+<reg name=\"v6\" bitsize=\"8\"/><reg name=\"v7\" bitsize=\"8\"/>\
+<reg name=\"v8\" bitsize=\"8\"/><reg name=\"v9\" bitsize=\"8\"/>\
+<reg name=\"va\" bitsize=\"8\"/><reg name=\"vb\" bitsize=\"8\"/>\
+<reg name=\"vc\" bitsize=\"8\"/><reg name=\"vd\" bitsize=\"8\"/>\
+<reg name=\"ve\" bitsize=\"8\"/><reg name=\"vf\" bitsize=\"8\"/>\
+<reg name=\"i\" bitsize=\"16\" type=\"data_ptr\"/>\
+<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>\
+<reg name=\"sp\" bitsize=\"8\"/><reg name=\"dt\" bitsize=\"8\"/>\
+<reg name=\"st\" bitsize=\"8\"/></feature></target>";
+
+const REGISTER_COUNT: usize = 21;
+
+/// How often, in instructions, a running target checks for GDB's interrupt.
+const INTERRUPT_CHECK: u32 = 1000;
+
+/// Signals used in stop replies.
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+pub struct GdbStub {
+    stream: TcpStream,
+    breakpoints: BTreeSet<u16>
+}
+
+impl GdbStub {
+    /// Waits on localhost for GDB to connect, e.g. with `target remote :PORT`.
+    pub fn listen(port: u16) -> io::Result<GdbStub> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        let (stream, address) = listener.accept()?;
+        info!("GDB connected from {}", address);
+        Ok(GdbStub::new(stream))
+    }
+
+    pub fn new(stream: TcpStream) -> GdbStub {
+        // Packets are small and each waits for an answer, so don't batch them.
+        if let Err(e) = stream.set_nodelay(true) {
+            warn!("Could not turn off Nagle's algorithm for GDB: {}", e);
+        }
+        GdbStub {
+            stream,
+            breakpoints: BTreeSet::new()
+        }
+    }
+
+    /// Answers packets until GDB detaches, kills the target or hangs up, or
+    /// the frontend wants to stop. `step` runs one instruction and returns
+    /// true once the frontend wants to stop.
+    pub fn serve(&mut self, state: &mut Chip8State, step: &mut dyn FnMut(&mut Chip8State) -> Result<bool, EmulatorError>) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            debug!("GDB <- {}", packet);
+            let reply = match self.handle(&packet, state, step)? {
+                Some(reply) => reply,
+                None => return Ok(())
+            };
+            debug!("GDB -> {}", reply);
+            self.write_packet(&reply)?;
+            if reply.starts_with('W') {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// The reply to a packet, or None to end the session.
+    fn handle(&mut self, packet: &str, state: &mut Chip8State, step: &mut dyn FnMut(&mut Chip8State) -> Result<bool, EmulatorError>) -> io::Result<Option<String>> {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => stop_reply(SIGTRAP),
+            "g" => (0..REGISTER_COUNT).map(|register| read_register(state, register)).collect(),
+            "G" => ok_or_error(write_registers(state, args)),
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(register) if register < REGISTER_COUNT => read_register(state, register),
+                _ => error(1)
+            },
+            "P" => ok_or_error(args.split_once('=')
+                .and_then(|(register, value)| Some((usize::from_str_radix(register, 16).ok()?, decode_hex(value)?)))
+                .and_then(|(register, value)| write_register(state, register, &value))),
+            "m" => match parse_range(args) {
+                Some((address, length)) => read_memory(state, address, length),
+                None => error(1)
+            },
+            "M" => ok_or_error(args.split_once(':')
+                .and_then(|(range, data)| Some((parse_range(range)?, decode_hex(data)?)))
+                .and_then(|((address, length), data)| {
+                    if data.len() != length {
+                        return None;
+                    }
+                    state.memory_mut().write_slice(&Word::new_from_full(address), &data).ok()
+                })),
+            "Z" | "z" => self.breakpoint(command == "Z", args, state),
+            "s" | "c" => {
+                if !args.is_empty() {
+                    match u16::from_str_radix(args, 16) {
+                        Ok(address) => *state.cpu_mut().program_counter_mut() = Word::new_from_full(address),
+                        Err(_) => return Ok(Some(error(1)))
+                    }
+                }
+                match self.resume(state, step, command == "s")? {
+                    Some(reply) => reply,
+                    None => return Ok(None)
+                }
+            },
+            "H" => String::from("OK"),
+            "D" => {
+                self.write_packet("OK")?;
+                return Ok(None);
+            },
+            "k" => return Ok(None),
+            "q" => query(args),
+            _ => String::new()
+        };
+        Ok(Some(reply))
+    }
+
+    fn breakpoint(&mut self, insert: bool, args: &str, state: &mut Chip8State) -> String {
+        let mut fields = args.split(',');
+        let kind = fields.next();
+        let address = fields.next().and_then(|address| u16::from_str_radix(address, 16).ok());
+        let length = fields.next().and_then(|length| u16::from_str_radix(length, 16).ok()).unwrap_or(1).max(1);
+        let address = match address {
+            Some(address) => address,
+            None => return error(1)
+        };
+        let (read, write) = match kind {
+            Some("0") | Some("1") => {
+                if insert {
+                    self.breakpoints.insert(address);
+                } else {
+                    self.breakpoints.remove(&address);
+                }
+                return String::from("OK");
+            },
+            Some("2") => (false, true),
+            Some("3") => (true, false),
+            Some("4") => (true, true),
+            _ => return String::new()
+        };
+        if insert {
+            let end = address.saturating_add(length - 1);
+            state.memory_mut().add_watch(Watch { start: address, end, read, write });
+        } else {
+            state.memory_mut().remove_watches(address);
+        }
+        String::from("OK")
+    }
+
+    /// Runs one instruction, or until something stops the target, and
+    /// returns the stop reply. None means GDB went away while it ran.
+    fn resume(&mut self, state: &mut Chip8State, step: &mut dyn FnMut(&mut Chip8State) -> Result<bool, EmulatorError>, single: bool) -> io::Result<Option<String>> {
+        state.memory().take_hits();
+        let mut count: u32 = 0;
+        loop {
+            match step(state) {
+                Ok(true) => return Ok(Some(String::from("W00"))),
+                Ok(false) => {},
+                Err(error) => {
+                    warn!("Stopped: {}", error);
+                    let signal = match error {
+                        EmulatorError::UnknownOpcode { .. } => SIGILL,
+                        _ => SIGSEGV
+                    };
+                    return Ok(Some(stop_reply(signal)));
+                }
+            }
+            if let Some(&hit) = state.memory().take_hits().first() {
+                let watched_both = state.memory().watches().iter()
+                    .any(|watch| watch.read && watch.write && watch.start <= hit.address && hit.address <= watch.end);
+                let kind = match hit.access {
+                    _ if watched_both => "awatch",
+                    Access::Read => "rwatch",
+                    Access::Write => "watch"
+                };
+                return Ok(Some(format!("T{:02x}{}:{:x};", SIGTRAP, kind, hit.address)));
+            }
+            if single || self.breakpoints.contains(&state.cpu().program_counter().full()) {
+                return Ok(Some(stop_reply(SIGTRAP)));
+            }
+            count = count.wrapping_add(1);
+            if count.is_multiple_of(INTERRUPT_CHECK) {
+                match self.poll_interrupt()? {
+                    Some(true) => return Ok(Some(stop_reply(SIGINT))),
+                    Some(false) => {},
+                    None => return Ok(None)
+                }
+            }
+        }
+    }
+
+    /// Checks, without waiting, whether GDB sent Ctrl-C. None if it hung up.
+    fn poll_interrupt(&mut self) -> io::Result<Option<bool>> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(byte[0] == 0x03)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Some(false)),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Reads the next packet, acknowledging it. None once GDB hangs up.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {},
+                // Acks and interrupts while stopped need no answer.
+                Some(_) => continue
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte)
+                }
+            }
+            let checksum = match (self.read_byte()?, self.read_byte()?) {
+                (Some(high), Some(low)) => decode_hex(&String::from_utf8_lossy(&[high, low])),
+                _ => return Ok(None)
+            };
+            if checksum == Some(vec![checksum_of(&data)]) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            warn!("GDB packet with a bad checksum, asking again");
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<Byte>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0]))
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())?;
+        self.stream.flush()
+    }
+}
+
+fn query(args: &str) -> String {
+    if args.starts_with("Supported") {
+        return String::from("PacketSize=1000;qXfer:features:read+");
+    }
+    if args == "Attached" {
+        return String::from("1");
+    }
+    if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+        return match parse_range(range) {
+            Some((offset, length)) => {
+                let start = (offset as usize).min(TARGET_XML.len());
+                let end = (start + length).min(TARGET_XML.len());
+                let more = if end < TARGET_XML.len() { 'm' } else { 'l' };
+                format!("{}{}", more, &TARGET_XML[start..end])
+            },
+            None => error(1)
+        };
+    }
+    String::new()
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+fn error(code: u8) -> String {
+    format!("E{:02x}", code)
+}
+
+fn ok_or_error(result: Option<()>) -> String {
+    match result {
+        Some(()) => String::from("OK"),
+        None => error(1)
+    }
+}
+
+/// The register's value as GDB expects it: hex bytes, least significant first.
+fn read_register(state: &Chip8State, register: usize) -> String {
+    let cpu = state.cpu();
+    let bytes = match register {
+        0..=15 => vec![cpu.get_reg(register as Byte)],
+        16 => little_endian(cpu.i_register().full()),
+        17 => little_endian(cpu.program_counter().full()),
+        18 => vec![state.memory().stack().len() as Byte],
+        19 => vec![cpu.delay_timer()],
+        _ => vec![cpu.sound_timer()]
+    };
+    encode_hex(&bytes)
+}
+
+fn write_register(state: &mut Chip8State, register: usize, value: &[Byte]) -> Option<()> {
+    let cpu = state.cpu_mut();
+    let word = || match *value {
+        [low, high] => Some(Word::new_from_bytes(high, low)),
+        _ => None
+    };
+    let byte = || match *value {
+        [byte] => Some(byte),
+        _ => None
+    };
+    match register {
+        0..=15 => cpu.set_reg(register as Byte, byte()?),
+        16 => cpu.set_i_register(word()?),
+        17 => *cpu.program_counter_mut() = word()?,
+        18 => {
+            byte()?;
+        },
+        19 => cpu.set_delay_timer(byte()?),
+        20 => cpu.set_sound_timer(byte()?),
+        _ => return None
+    }
+    Some(())
+}
+
+fn write_registers(state: &mut Chip8State, hex: &str) -> Option<()> {
+    let bytes = decode_hex(hex)?;
+    let mut rest = &bytes[..];
+    for register in 0..REGISTER_COUNT {
+        let size = if register == 16 || register == 17 { 2 } else { 1 };
+        if rest.len() < size {
+            return None;
+        }
+        write_register(state, register, &rest[..size])?;
+        rest = &rest[size..];
+    }
+    Some(())
+}
+
+fn read_memory(state: &Chip8State, address: u16, length: usize) -> String {
+    let memory = state.memory();
+    if length > 0 && address as usize >= memory.size() {
+        return error(1);
+    }
+    // GDB asks again for whatever is missing from a short read.
+    let end = (address as usize + length).min(memory.size());
+    let bytes: Vec<Byte> = (address as usize..end)
+        .map(|address| memory.peek(&Word::new_from_full(address as u16)).unwrap_or(0))
+        .collect();
+    encode_hex(&bytes)
+}
+
+/// `ADDR,LENGTH` in hex.
+fn parse_range(text: &str) -> Option<(u16, usize)> {
+    let (address, length) = text.split_once(',')?;
+    Some((u16::from_str_radix(address, 16).ok()?, usize::from_str_radix(length, 16).ok()?))
+}
+
+fn little_endian(value: u16) -> Vec<Byte> {
+    vec![value as Byte, (value >> 8) as Byte]
+}
+
+fn checksum_of(data: &[Byte]) -> Byte {
+    data.iter().fold(0, |sum: Byte, byte| sum.wrapping_add(*byte))
+}
+
+fn encode_hex(bytes: &[Byte]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<Byte>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|index| u8::from_str_radix(&text[index..index + 2], 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use gdb::{GdbStub, checksum_of};
+    use emustate::Chip8State;
+    use error::EmulatorError;
+
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    /// Sends each packet in turn and collects the replies, like GDB would.
+    fn client(port: u16, packets: Vec<&'static str>) -> thread::JoinHandle<Vec<String>> {
+        thread::spawn(move || {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let mut replies = Vec::new();
+            for packet in packets {
+                write!(stream, "${}#{:02x}", packet, checksum_of(packet.as_bytes())).unwrap();
+                let mut ack = [0];
+                stream.read_exact(&mut ack).unwrap();
+                assert_eq!(ack[0], b'+');
+                if packet == "k" {
+                    break;
+                }
+
+                let mut reply = Vec::new();
+                let mut byte = [0];
+                loop {
+                    stream.read_exact(&mut byte).unwrap();
+                    if byte[0] == b'#' {
+                        break;
+                    }
+                    reply.push(byte[0]);
+                }
+                let mut checksum = [0; 2];
+                stream.read_exact(&mut checksum).unwrap();
+                assert_eq!(reply[0], b'$');
+                assert_eq!(String::from_utf8_lossy(&checksum), format!("{:02x}", checksum_of(&reply[1..])));
+                stream.write_all(b"+").unwrap();
+                replies.push(String::from_utf8(reply[1..].to_vec()).unwrap());
+            }
+            replies
+        })
+    }
+
+    fn session(program: &[u8], packets: Vec<&'static str>) -> (Chip8State, Vec<String>) {
+        let mut state = Chip8State::default();
+        state.load_instructions(program.to_vec()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = client(listener.local_addr().unwrap().port(), packets);
+
+        let mut stub = GdbStub::new(listener.accept().unwrap().0);
+        let mut steps = 0;
+        let mut step = |state: &mut Chip8State| -> Result<bool, EmulatorError> {
+            state.run_next_cycle()?;
+            steps += 1;
+            Ok(steps > 10_000)
+        };
+        stub.serve(&mut state, &mut step).unwrap();
+        (state, client.join().unwrap())
+    }
+
+    // ADD V0, 1; CALL 0x206; JP 0x200; (0x206) LD [I], V0; RET
+    const PROGRAM: [u8; 10] = [0x70, 0x01, 0x22, 0x06, 0x12, 0x00, 0xF0, 0x55, 0x00, 0xEE];
+
+    #[test]
+    fn test_registers_and_stepping() {
+        let (state, replies) = session(&PROGRAM, vec![
+            "qSupported:multiprocess+", "?", "s", "s", "p11", "p12", "P3=2a", "P10=0003", "g", "D"
+        ]);
+
+        assert_eq!(replies, vec![
+            "PacketSize=1000;qXfer:features:read+", "S05", "S05", "S05", "0602", "01", "OK", "OK",
+            "0100002a00000000000000000000000000030602010000", "OK"
+        ]);
+        assert_eq!(state.cpu().get_reg(3), 0x2A);
+    }
+
+    #[test]
+    fn test_breakpoints_and_memory() {
+        let (state, replies) = session(&PROGRAM, vec![
+            "Z0,206,2", "c", "p0", "z0,206,2", "Z2,0,1", "c", "m200,4", "M300,2:abcd", "m300,2", "k"
+        ]);
+
+        assert_eq!(replies, vec!["OK", "S05", "01", "OK", "OK", "T05watch:0;", "70012206", "OK", "abcd"]);
+        assert_eq!(state.memory().read(&::memory::Word::new_from_full(0)).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_target_description_and_unknown_packets() {
+        let (_, replies) = session(&PROGRAM, vec!["qXfer:features:read:target.xml:0,10", "vMustReplyEmpty", "k"]);
+
+        assert_eq!(replies, vec!["m<?xml version=\"1", ""]);
+    }
+}
@@ -0,0 +1,16 @@
+/// 64 bit FNV-1a. It's stable across builds and platforms, so hashes can be
+/// stored in files and compared later.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3))
+}
+
+#[cfg(test)]
+mod test {
+    use hash;
+
+    #[test]
+    fn test_known_values() {
+        assert_eq!(hash::fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash::fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+}
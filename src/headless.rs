@@ -0,0 +1,90 @@
+//! Runs a machine as fast as possible with no window, sound or real time
+//! clock, for scripted checks and tests.
+
+use emustate::Chip8State;
+use error::EmulatorError;
+use input::{InputSource, FrontendEvent};
+use scheduler::Scheduler;
+
+/// How long to run for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Frames(u64),
+    Cycles(u64)
+}
+
+/// How far a headless run got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Finished {
+    pub frames: u64,
+    pub cycles: u64,
+    /// True if the program exited or the input asked to quit before the limit.
+    pub stopped_early: bool
+}
+
+/// Runs `state` at `clock_hz` until the limit is reached, keeping the same
+/// frame timing as a windowed run but without waiting between frames.
+pub fn run(state: &mut Chip8State, clock_hz: u32, input: &mut dyn InputSource, limit: Limit) -> Result<Finished, EmulatorError> {
+    let mut scheduler = Scheduler::with_clock_speed(clock_hz);
+    let mut events = Vec::new();
+    let mut cycles = 0;
+    let stopped = |state: &Chip8State, events: &[FrontendEvent]| state.cpu().halted() || events.contains(&FrontendEvent::Quit);
+
+    match limit {
+        Limit::Frames(frames) => {
+            while scheduler.frames() < frames && !stopped(state, &events) {
+                let start = state.cycles();
+                scheduler.run_frame(state, input, &mut events)?;
+                cycles += state.cycles() - start;
+            }
+        },
+        Limit::Cycles(limit) => {
+            while cycles < limit && !stopped(state, &events) {
+                scheduler.step(state, input, &mut events)?;
+                cycles += 1;
+            }
+        }
+    }
+
+    Ok(Finished {
+        frames: scheduler.frames(),
+        cycles,
+        stopped_early: stopped(state, &events)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use headless::{self, Limit, Finished};
+    use emustate::Chip8State;
+    use input::ScriptedInput;
+    use screenshot;
+
+    #[test]
+    fn test_runs_to_the_limit() {
+        // LD I, font 0; DRW V0, V0, 5; JP 0x204
+        let mut state = Chip8State::default();
+        state.load_instructions(vec![0xF0, 0x29, 0xD0, 0x05, 0x12, 0x04]).unwrap();
+        state.load_font();
+
+        let finished = headless::run(&mut state, 600, &mut ScriptedInput::new(), Limit::Frames(3)).unwrap();
+        assert_eq!(finished, Finished { frames: 3, cycles: 30, stopped_early: false });
+        let ascii = screenshot::to_ascii(state.framebuffer());
+        let lines: Vec<&str> = ascii.lines().collect();
+        assert!(lines[0].starts_with("####."));
+        assert!(lines[1].starts_with("#..#."));
+
+        let finished = headless::run(&mut state, 600, &mut ScriptedInput::new(), Limit::Cycles(25)).unwrap();
+        assert_eq!(finished, Finished { frames: 2, cycles: 25, stopped_early: false });
+    }
+
+    #[test]
+    fn test_stops_when_the_input_quits() {
+        let mut state = Chip8State::default();
+        state.load_instructions(vec![0x12, 0x00]).unwrap();
+
+        let mut input = ScriptedInput::new().quit_at(4);
+        let finished = headless::run(&mut state, 600, &mut input, Limit::Frames(100)).unwrap();
+        assert_eq!(finished, Finished { frames: 5, cycles: 50, stopped_early: true });
+    }
+}
@@ -1,83 +1,208 @@
-extern crate sdl2;
-use self::sdl2::event::Event;
-use self::sdl2::keyboard::Scancode;
+use std::fs::File;
+use std::io::prelude::*;
 
-pub struct Input {
-    input_driver : sdl2::EventPump
+pub const KEY_COUNT : usize = 16;
+
+/// The state of the 16 key hex keypad, indexed by key value 0x0-0xF.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Keypad {
+    keys: [bool; KEY_COUNT]
+}
+
+/// Things a frontend wants the emulation loop to do, as opposed to keypad
+/// state which goes to the emulated machine.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrontendEvent {
+    Quit,
+    /// Save the machine to the numbered slot.
+    SaveState(u8),
+    LoadState(u8),
+    /// Sent on every poll while the rewind key is held.
+    Rewind
 }
 
-impl Input {
-    pub fn new(context: &sdl2::Sdl) -> Input {
-        Input {
-            input_driver: context.event_pump().unwrap()
+/// Somewhere keypad state comes from: the keyboard, a script, a test.
+pub trait InputSource {
+    /// Returns the keypad state right now, pushing any frontend events which
+    /// happened since the last poll onto `events`.
+    fn poll(&mut self, events: &mut Vec<FrontendEvent>) -> Keypad;
+}
+
+impl Keypad {
+    pub fn new() -> Keypad {
+        Keypad {
+            keys: [false; KEY_COUNT]
         }
     }
 
-    pub fn poll(&mut self) -> Result<Vec<bool>, &'static str> {
-        let mut events = vec![false; 16];
+    pub fn with_pressed(pressed: &[u8]) -> Keypad {
+        let mut keypad = Keypad::new();
+        for key in pressed {
+            keypad.set(*key, true);
+        }
+        keypad
+    }
 
-        for event in self.input_driver.poll_iter() {
-            if let Event::Quit {..} = event {
-               return Err("User exited program!")
-            }
-        };
+    pub fn is_pressed(&self, key: u8) -> bool {
+        self.keys[key as usize]
+    }
+
+    pub fn set(&mut self, key: u8, pressed: bool) {
+        self.keys[key as usize] = pressed;
+    }
 
-        let state : Vec<sdl2::keyboard::Scancode> = self.input_driver.keyboard_state().pressed_scancodes().collect();
+    /// The keypad as a bit mask, bit n set when key n is held.
+    pub fn bits(&self) -> u16 {
+        self.keys.iter().enumerate().fold(0, |bits, (key, &held)| bits | (held as u16) << key)
+    }
 
-        for key in state {
-            match key {
-                Scancode::B => {
-                    events[0x0] = true;
-                }
-                Scancode::Num4 => {
-                    events[0x1] = true;
-                },
-                Scancode::Num5 => {
-                    events[0x2] = true;
-                },
-                Scancode::Num6 => {
-                    events[0x3] = true;
-                },
-                Scancode::R => {
-                    events[0x4] = true;
-                },
-                Scancode::T => {
-                    events[0x5] = true;
-                },
-                Scancode::Y => {
-                    events[0x6] = true;
-                },
-                Scancode::F => {
-                    events[0x7] = true;
-                },
-                Scancode::G => {
-                    events[0x8] = true;
-                },
-                Scancode::H => {
-                    events[0x9] = true;
-                },
-                Scancode::V => {
-                    events[0xA] = true;
-                },
-                Scancode::N => {
-                    events[0xB] = true;
-                },
-                Scancode::Num7 => {
-                    events[0xC] = true;
-                },
-                Scancode::U => {
-                    events[0xD] = true;
-                },
-                Scancode::J => {
-                    events[0xE] = true;
-                },
-                Scancode::M => {
-                    events[0xF] = true;
+    pub fn from_bits(bits: u16) -> Keypad {
+        let mut keypad = Keypad::new();
+        for key in 0..KEY_COUNT {
+            keypad.keys[key] = bits & (1 << key) != 0;
+        }
+        keypad
+    }
+
+    /// The lowest numbered key currently held, as used by `LD Vx, K`.
+    pub fn first_pressed(&self) -> Option<u8> {
+        self.keys.iter().position(|key| *key).map(|key| key as u8)
+    }
+}
+
+/// Replays a fixed sequence of keypad states. Each step gives the poll number
+/// from which a keypad state applies; it stays held until the next step.
+///
+/// Scripts are text, one step per line: the poll number followed by the keys
+/// held in hex, `-` for none, or `quit` to send `FrontendEvent::Quit`. Blank
+/// lines and anything after `#` are ignored.
+///
+/// ```text
+/// 0   -
+/// 30  5 6     # hold 5 and 6 from the 30th poll
+/// 45  -
+/// 120 quit
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct ScriptedInput {
+    steps: Vec<(u64, Keypad)>,
+    quit_at: Option<u64>,
+    polls: u64,
+    current: Keypad
+}
+
+impl ScriptedInput {
+    pub fn new() -> ScriptedInput {
+        ScriptedInput {
+            steps: Vec::new(),
+            quit_at: None,
+            polls: 0,
+            current: Keypad::new()
+        }
+    }
+
+    /// Holds `keys` from poll number `poll` onwards. Steps must be added in order.
+    pub fn press_at(mut self, poll: u64, keys: Keypad) -> ScriptedInput {
+        self.steps.push((poll, keys));
+        self
+    }
+
+    pub fn quit_at(mut self, poll: u64) -> ScriptedInput {
+        self.quit_at = Some(poll);
+        self
+    }
+
+    pub fn parse(script: &str) -> Result<ScriptedInput, String> {
+        let mut input = ScriptedInput::new();
+        for (number, line) in script.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut words = line.split_whitespace();
+            let poll = match words.next() {
+                Some(word) => word.parse::<u64>()
+                    .map_err(|_| format!("line {}: bad poll number `{}`", number + 1, word))?,
+                None => continue
+            };
+
+            let mut keys = Keypad::new();
+            for word in words {
+                match word {
+                    "-" => {},
+                    "quit" => {
+                        input = input.quit_at(poll);
+                    },
+                    _ => {
+                        let key = u8::from_str_radix(word, 16).ok().filter(|key| (*key as usize) < KEY_COUNT)
+                            .ok_or_else(|| format!("line {}: bad key `{}`", number + 1, word))?;
+                        keys.set(key, true);
+                    }
                 }
-                _ => continue
             }
+            input = input.press_at(poll, keys);
         }
+        Ok(input)
+    }
 
-        Ok(events)
+    pub fn from_file(filename: &str) -> Result<ScriptedInput, String> {
+        let mut script = String::new();
+        File::open(filename)
+            .and_then(|mut file| file.read_to_string(&mut script))
+            .map_err(|e| format!("{}: {}", filename, e))?;
+        ScriptedInput::parse(&script)
     }
-}
\ No newline at end of file
+}
+
+impl InputSource for ScriptedInput {
+    fn poll(&mut self, events: &mut Vec<FrontendEvent>) -> Keypad {
+        for &(poll, keys) in &self.steps {
+            if poll == self.polls {
+                self.current = keys;
+            }
+        }
+        if self.quit_at == Some(self.polls) {
+            events.push(FrontendEvent::Quit);
+        }
+
+        self.polls += 1;
+        self.current
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use input::{InputSource, Keypad, FrontendEvent, ScriptedInput};
+
+    #[test]
+    fn test_keypad_first_pressed() {
+        assert_eq!(Keypad::new().first_pressed(), None);
+        assert_eq!(Keypad::with_pressed(&[0xC, 0x3]).first_pressed(), Some(0x3));
+    }
+
+    #[test]
+    fn test_keypad_bits() {
+        let keypad = Keypad::with_pressed(&[0x0, 0x5, 0xF]);
+
+        assert_eq!(keypad.bits(), 0x8021);
+        assert_eq!(Keypad::from_bits(0x8021), keypad);
+    }
+
+    #[test]
+    fn test_script_holds_keys_until_next_step() {
+        let mut input = ScriptedInput::parse("# comment\n1 a\n\n3 - \n4 quit").unwrap();
+        let mut events = Vec::new();
+
+        assert_eq!(input.poll(&mut events), Keypad::new());
+        assert_eq!(input.poll(&mut events), Keypad::with_pressed(&[0xA]));
+        assert_eq!(input.poll(&mut events), Keypad::with_pressed(&[0xA]));
+        assert_eq!(input.poll(&mut events), Keypad::new());
+        assert!(events.is_empty());
+
+        input.poll(&mut events);
+        assert_eq!(events, vec![FrontendEvent::Quit]);
+    }
+
+    #[test]
+    fn test_script_errors_name_the_line() {
+        let error = ScriptedInput::parse("0 1\n5 G").unwrap_err();
+        assert_eq!(error, "line 2: bad key `G`");
+    }
+}
@@ -0,0 +1,51 @@
+//! Core of the CHIP-8 emulator. Nothing in here needs a window or audio
+//! device; the SDL2 frontend lives in `sdl` behind the `sdl` cargo feature.
+
+#[macro_use]
+extern crate log;
+extern crate rand;
+
+#[cfg(feature = "sdl")]
+extern crate sdl2;
+
+pub mod memory;
+pub mod cpu;
+pub mod emustate;
+pub mod decoder;
+pub mod display;
+pub mod audio;
+pub mod input;
+pub mod scheduler;
+pub mod font;
+pub mod error;
+pub mod quirks;
+pub mod platform;
+pub mod disasm;
+pub mod encoder;
+pub mod asm;
+pub mod octo;
+pub mod rom;
+pub mod savestate;
+pub mod rewind;
+pub mod rng;
+pub mod hash;
+pub mod movie;
+pub mod debugger;
+pub mod expr;
+pub mod gdb;
+pub mod trace;
+pub mod tracediff;
+pub mod headless;
+pub mod screenshot;
+pub mod conformance;
+mod util;
+
+#[cfg(feature = "sdl")]
+pub mod sdl;
+
+pub use cpu::Cpu;
+pub use emustate::Chip8State;
+pub use memory::Memory;
+pub use error::EmulatorError;
+pub use quirks::Quirks;
+pub use platform::Platform;
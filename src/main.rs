@@ -5,28 +5,103 @@ extern crate clap;
 extern crate log;
 extern crate fern;
 
-use clap::App;
+extern crate chip8_emulator;
+
+use clap::{App, ArgMatches};
+
+use chip8_emulator::{scheduler, rewind, trace};
+use chip8_emulator::headless::Limit;
+use chip8_emulator::{Quirks, Platform};
 
 use std::process;
 
-mod memory;
-mod cpu;
-mod emustate;
 mod emulator;
-mod decoder;
-mod util;
-mod display;
-mod input;
-mod font;
+mod tools;
 
 fn main() {
     let yaml = load_yaml!("chip8.yml");
     let matches = App::from_yaml(yaml).get_matches();
 
+    if let Some(submatches) = matches.subcommand_matches("disasm") {
+        let platform = platform_of(&matches, submatches);
+        exit_on_error(tools::disassemble(submatches.value_of("ROM").unwrap(), platform));
+        return;
+    }
+    if let Some(submatches) = matches.subcommand_matches("asm") {
+        exit_on_error(tools::assemble(submatches.value_of("SOURCE").unwrap(), submatches.value_of("output")));
+        return;
+    }
+    if let Some(submatches) = matches.subcommand_matches("conformance") {
+        exit_on_error(tools::conformance(submatches.value_of("DIRECTORY").unwrap_or("tests/conformance"), submatches.is_present("bless")));
+        return;
+    }
+    if let Some(submatches) = matches.subcommand_matches("tracediff") {
+        let platform = platform_of(&matches, submatches);
+        let first = submatches.value_of("FIRST").unwrap();
+        let context = submatches.value_of("context").map_or(5, |context| parse_size("context", context));
+        if !submatches.is_present("lockstep") {
+            exit_on_error(tools::tracediff(first, submatches.value_of("SECOND").unwrap(), platform, context));
+            return;
+        }
+        let quirks = |name| submatches.value_of(name).and_then(Quirks::from_name).unwrap_or_else(|| platform.default_quirks());
+        exit_on_error(tools::lockstep(&tools::Lockstep {
+            roms: (first.to_string(), submatches.value_of("SECOND").unwrap_or(first).to_string()),
+            platform,
+            quirks: (quirks("quirks-a"), quirks("quirks-b")),
+            clock_hz: submatches.value_of("clock").map_or(scheduler::DEFAULT_INSTRUCTIONS_PER_FRAME * scheduler::FRAME_RATE, |clock| parse_number("clock", clock)),
+            cycles: submatches.value_of("cycles").map_or(1_000_000, |cycles| parse_count("cycles", cycles)),
+            input: submatches.value_of("input").map(String::from),
+            seed: submatches.value_of("seed").map_or(0, |seed| parse_count("seed", seed)),
+            context
+        }));
+        return;
+    }
+
+    let clock_hz = match (matches.value_of("ipf"), matches.value_of("clock")) {
+        (Some(ipf), _) => match parse_number("ipf", ipf).checked_mul(scheduler::FRAME_RATE) {
+            Some(clock_hz) => clock_hz,
+            None => {
+                eprintln!("--ipf is too large");
+                process::exit(1);
+            }
+        },
+        (None, Some(clock)) => parse_number("clock", clock),
+        (None, None) => scheduler::DEFAULT_INSTRUCTIONS_PER_FRAME * scheduler::FRAME_RATE
+    };
+
+    let platform = matches.value_of("platform").and_then(Platform::from_name).unwrap_or_default();
+
+    let headless = match (matches.value_of("frames"), matches.value_of("cycles")) {
+        (Some(frames), _) => Some(Limit::Frames(parse_count("frames", frames))),
+        (None, Some(cycles)) => Some(Limit::Cycles(parse_count("cycles", cycles))),
+        (None, None) if matches.is_present("headless") => {
+            eprintln!("--headless needs --frames or --cycles to know when to stop");
+            process::exit(1);
+        },
+        (None, None) => None
+    };
+
     let config = emulator::Config {
         filename: matches.value_of("ROM").unwrap().to_string(),
         verbose: matches.is_present("verbose"),
-        debug: matches.is_present("debug")
+        debug: matches.is_present("debug"),
+        clock_hz,
+        platform,
+        quirks: matches.value_of("quirks").and_then(Quirks::from_name).unwrap_or_else(|| platform.default_quirks()),
+        seed: matches.value_of("seed").map(|seed| parse_count("seed", seed)),
+        load_state: matches.value_of("load-state").map(String::from),
+        rewind_budget: matches.value_of("rewind").map_or(rewind::DEFAULT_BUDGET, |mib| parse_size("rewind", mib).saturating_mul(1024 * 1024)),
+        record: matches.value_of("record").map(String::from),
+        replay: matches.value_of("replay").map(String::from),
+        verify: matches.is_present("verify"),
+        gdb: matches.value_of("gdb").map(|port| parse_port("gdb", port)),
+        trace: matches.value_of("trace").map(String::from),
+        trace_format: matches.value_of("trace-format").and_then(trace::Format::from_name).unwrap_or(trace::Format::Text),
+        trace_ranges: matches.values_of("trace-range").map_or(Vec::new(), |ranges| ranges.map(|range| parse_range("trace-range", range)).collect()),
+        headless,
+        screenshot: matches.value_of("screenshot").map(String::from),
+        ascii: matches.is_present("ascii"),
+        input: matches.value_of("input").map(String::from)
     };
 
     if let Err(e) = setup_logging(&config) {
@@ -40,13 +115,90 @@ fn main() {
     }
 }
 
+/// `--platform` may be given before or after the subcommand name.
+fn platform_of(matches: &ArgMatches, submatches: &ArgMatches) -> Platform {
+    submatches.value_of("platform")
+        .or_else(|| matches.value_of("platform"))
+        .and_then(Platform::from_name)
+        .unwrap_or_default()
+}
+
+/// Reports a failed subcommand; these run before logging is set up.
+fn exit_on_error(result: Result<(), Box<dyn std::error::Error>>) {
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+/// Like `parse_number`, but zero is allowed.
+fn parse_size(name: &str, value: &str) -> usize {
+    match value.parse::<usize>() {
+        Ok(number) => number,
+        Err(_) => {
+            eprintln!("--{} expects a whole number, got `{}`", name, value);
+            process::exit(1);
+        }
+    }
+}
+
+/// A whole number that may not fit in 32 bits, like a seed, frame or cycle count.
+fn parse_count(name: &str, value: &str) -> u64 {
+    match value.parse::<u64>() {
+        Ok(number) => number,
+        Err(_) => {
+            eprintln!("--{} expects a whole number, got `{}`", name, value);
+            process::exit(1);
+        }
+    }
+}
+
+/// An address range in hex, `START-END`, or a single address.
+fn parse_range(name: &str, value: &str) -> (u16, u16) {
+    let address = |text: &str| u16::from_str_radix(text.trim_start_matches("0x").trim_start_matches("0X"), 16).ok();
+    let range = match value.split_once('-') {
+        Some((start, end)) => address(start).zip(address(end)),
+        None => address(value).map(|address| (address, address))
+    };
+    match range {
+        Some((start, end)) if start <= end => (start, end),
+        _ => {
+            eprintln!("--{} expects a range of hex addresses like 200-2FF, got `{}`", name, value);
+            process::exit(1);
+        }
+    }
+}
+
+fn parse_port(name: &str, value: &str) -> u16 {
+    match value.parse::<u16>() {
+        Ok(port) if port > 0 => port,
+        _ => {
+            eprintln!("--{} expects a port number, got `{}`", name, value);
+            process::exit(1);
+        }
+    }
+}
+
+fn parse_number(name: &str, value: &str) -> u32 {
+    match value.parse::<u32>() {
+        Ok(number) if number > 0 => number,
+        _ => {
+            eprintln!("--{} expects a positive whole number, got `{}`", name, value);
+            process::exit(1);
+        }
+    }
+}
+
 pub fn setup_logging(config: &emulator::Config) -> Result<(), fern::InitError> {
     
+    // The debugger prints the state when asked, so --debug no longer turns
+    // on tracing every instruction.
     let mut level = log::LogLevelFilter::Info;
     if config.verbose {
         level = log::LogLevelFilter::Debug;
-    } else if config.debug {
-        level = log::LogLevelFilter::Trace;
+    } else if config.headless.is_some() {
+        // Headless output is meant for scripts, so keep stdout to the results.
+        level = log::LogLevelFilter::Warn;
     }
     
     fern::Dispatch::new()
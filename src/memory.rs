@@ -1,9 +1,17 @@
 use std::ops;
 use std::fmt;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use savestate::{StateWriter, StateReader, SaveStateError};
 
 const FONT_SIZE : u8 = 5;
+const BIG_FONT_SIZE : u8 = 10;
+
+/// Where the SUPER-CHIP font is loaded, straight after the small font.
+pub const BIG_FONT_START : u16 = 0x50;
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub struct Word {
     full: u16,
     high: Byte,
@@ -24,21 +32,21 @@ impl Word {
     pub fn new_from_bytes(high: Byte, low: Byte) -> Word {
         Word {
             full: ((high as u16) << 8) + low as u16,
-            high: high,
-            low: low
+            high,
+            low
         }
     }
 
     pub fn full(&self) -> u16 {
-        self.full.clone()
+        self.full
     }
 
     pub fn high(&self) -> u8 {
-        self.high.clone()
+        self.high
     }
 
     pub fn low(&self) -> u8 {
-        self.low.clone()
+        self.low
     }
 }
 
@@ -54,11 +62,11 @@ impl fmt::Debug for Word {
     }
 }
 
-impl<'a> ops::Add<u16> for &'a mut Word {
+impl ops::Add<u16> for &mut Word {
     type Output = Word;
 
     fn add(self, other: u16) -> Word {
-        Word::new_from_full(self.full + other)
+        Word::new_from_full(self.full.wrapping_add(other))
     }
 }
 
@@ -66,13 +74,13 @@ impl ops::Add<u16> for Word {
     type Output = Word;
 
     fn add(self, other: u16) -> Word {
-        Word::new_from_full(self.full + other)
+        Word::new_from_full(self.full.wrapping_add(other))
     }
 }
 
 impl ops::SubAssign<u16> for Word {
     fn sub_assign(&mut self, val: u16) {
-        self.full = self.full - val;
+        self.full = self.full.wrapping_sub(val);
         self.high = (self.full >> 8) as Byte;
         self.low = (self.full & 0x00FF) as Byte;
     }
@@ -80,100 +88,287 @@ impl ops::SubAssign<u16> for Word {
 
 impl ops::AddAssign<u16> for Word {
     fn add_assign(&mut self, val: u16) {
-        self.full = self.full + val;
+        self.full = self.full.wrapping_add(val);
         self.high = (self.full >> 8) as Byte;
         self.low = (self.full & 0x00FF) as Byte;
     }
 }
 
-impl<'a> ops::AddAssign<u16> for &'a mut Word {
+impl ops::AddAssign<u16> for &mut Word {
     fn add_assign(&mut self, val: u16) {
-        self.full = self.full + val;
+        self.full = self.full.wrapping_add(val);
         self.high = (self.full >> 8) as Byte;
         self.low = (self.full & 0x00FF) as Byte;
     }
 }
 
+pub const STACK_DEPTH : usize = 16;
+
+/// Size of the space a 16-bit I can address. A memory this big wraps back
+/// to 0x0000 at the top, as in Octo; smaller ones fault past their end.
+const ADDRESS_SPACE : usize = 0x10000;
+
+/// Why a memory or stack access failed. The CPU turns these into an
+/// `EmulatorError` carrying the address of the faulting instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryError {
+    OutOfRange(usize),
+    StackOverflow,
+    StackUnderflow
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write
+}
+
+/// Which accesses to an address range (`start` to `end` inclusive) to report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watch {
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool
+}
+
+impl Watch {
+    fn matches(&self, access: Access, start: usize, length: usize) -> bool {
+        let wanted = match access {
+            Access::Read => self.read,
+            Access::Write => self.write
+        };
+        wanted && length > 0 && start <= self.end as usize && start + length > self.start as usize
+    }
+}
+
+/// A watched access: the first watched address it touched, and how.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub address: u16,
+    pub access: Access
+}
+
 #[derive(Debug)]
 pub struct Memory {
     memory: Vec<Byte>,
     stack: Vec<Word>,
-    memory_size: usize
+    memory_size: usize,
+    watches: Vec<Watch>,
+    hits: RefCell<Vec<WatchHit>>,
+    write_log: Option<Vec<(u16, Byte)>>
 }
 
 impl Memory {
     pub fn new(memory_size: usize) -> Memory {
         Memory {
             memory: vec![0; memory_size],
-            stack: Vec::with_capacity(16),
-            memory_size
+            stack: Vec::with_capacity(STACK_DEPTH),
+            memory_size,
+            watches: Vec::new(),
+            hits: RefCell::new(Vec::new()),
+            write_log: None
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.memory_size
+    }
+
+    pub fn write_state(&self, writer: &mut StateWriter) {
+        writer.bytes(&self.memory);
+        writer.u8(self.stack.len() as u8);
+        for address in &self.stack {
+            writer.word(address);
         }
     }
 
-    pub fn read(&self, address: &Word) -> Byte {
-        if address.full >= self.memory_size as u16 {
-            panic!(format!("Fatal: tried to read out of memory range: {:04X}", address.full));
+    pub fn read_state(reader: &mut StateReader) -> Result<Memory, SaveStateError> {
+        let mut memory = Memory::new(0);
+        memory.memory = reader.bytes()?.to_vec();
+        memory.memory_size = memory.memory.len();
+        let depth = reader.u8()? as usize;
+        if depth > STACK_DEPTH {
+            return Err(SaveStateError::Invalid("stack depth"));
+        }
+        for _ in 0..depth {
+            memory.stack.push(reader.word()?);
+        }
+        Ok(memory)
+    }
+
+    pub fn watches(&self) -> &[Watch] {
+        &self.watches
+    }
+
+    pub fn add_watch(&mut self, watch: Watch) {
+        self.watches.push(watch);
+    }
+
+    /// Removes the watches covering `address`, returning how many there were.
+    pub fn remove_watches(&mut self, address: u16) -> usize {
+        let before = self.watches.len();
+        self.watches.retain(|watch| !(watch.start <= address && address <= watch.end));
+        before - self.watches.len()
+    }
+
+    /// Moves the watches to another memory, e.g. one loaded from a save state.
+    pub fn move_watches_to(&mut self, other: &mut Memory) {
+        other.watches.append(&mut self.watches);
+    }
+
+    /// The watched accesses made since the last call.
+    pub fn take_hits(&self) -> Vec<WatchHit> {
+        self.hits.replace(Vec::new())
+    }
+
+    /// Every access made by the program goes through here, so watchpoints
+    /// see single bytes, sprites and register dumps alike.
+    fn check(&self, access: Access, start: usize, length: usize) {
+        for watch in &self.watches {
+            if watch.matches(access, start, length) {
+                let address = start.max(watch.start as usize) as u16;
+                self.hits.borrow_mut().push(WatchHit { address, access });
+                return;
+            }
         }
-        self.memory[address.full as usize]
     }
 
-    pub fn write(&mut self, address: &Word, value: Byte) {
-        if address.full >= self.memory_size as u16 {
-            panic!(format!("Fatal: tried to write out of memory range: {:04X}", address.full));
+    /// Starts keeping a list of every byte written, for tracing.
+    pub fn log_writes(&mut self) {
+        self.write_log = Some(Vec::new());
+    }
+
+    /// The bytes written since `log_writes`, as addresses and values. Stops
+    /// keeping the list.
+    pub fn take_writes(&mut self) -> Vec<(u16, Byte)> {
+        self.write_log.take().unwrap_or_default()
+    }
+
+    fn log(&mut self, start: usize, data: &[Byte]) {
+        if let Some(ref mut log) = self.write_log {
+            log.extend(data.iter().enumerate().map(|(offset, &value)| ((start + offset) as u16, value)));
         }
-        self.memory[address.full as usize] = value;
     }
 
-    pub fn read_slice(&self, address: &Word, length: Byte) -> &[Byte] {
-        &self.memory[address.full as usize..(address.full + length as u16) as usize]
+    /// Reads a byte without triggering watchpoints, for instruction fetches
+    /// and the debugger.
+    pub fn peek(&self, address: &Word) -> Result<Byte, MemoryError> {
+        self.memory.get(address.full as usize).cloned().ok_or(MemoryError::OutOfRange(address.full as usize))
     }
 
-    pub fn pop_stack(&mut self) -> Option<Word> {
-        self.stack.pop()
+    pub fn read(&self, address: &Word) -> Result<Byte, MemoryError> {
+        self.check(Access::Read, address.full as usize, 1);
+        self.peek(address)
     }
 
-    pub fn push_stack(&mut self, value: Word) {
+    pub fn write(&mut self, address: &Word, value: Byte) -> Result<(), MemoryError> {
+        self.check(Access::Write, address.full as usize, 1);
+        match self.memory.get_mut(address.full as usize) {
+            Some(byte) => {
+                *byte = value;
+                self.log(address.full as usize, &[value]);
+                Ok(())
+            },
+            None => Err(MemoryError::OutOfRange(address.full as usize))
+        }
+    }
+
+    pub fn write_slice(&mut self, address: &Word, data: &[Byte]) -> Result<(), MemoryError> {
+        let (first, second) = self.span(address, data.len())?;
+        let (data_first, data_second) = data.split_at(first.len());
+        for (range, data) in [(first, data_first), (second, data_second)].iter().cloned() {
+            self.check(Access::Write, range.start, range.len());
+            self.log(range.start, data);
+            self.memory[range].copy_from_slice(data);
+        }
+        Ok(())
+    }
+
+    /// Reads `length` bytes from `address`, copying them only if they wrap
+    /// past the top of memory.
+    pub fn read_slice(&self, address: &Word, length: Byte) -> Result<Cow<'_, [Byte]>, MemoryError> {
+        let (first, second) = self.span(address, length as usize)?;
+        self.check(Access::Read, first.start, first.len());
+        self.check(Access::Read, second.start, second.len());
+        if second.is_empty() {
+            Ok(Cow::Borrowed(&self.memory[first]))
+        } else {
+            Ok(Cow::Owned([&self.memory[first], &self.memory[second]].concat()))
+        }
+    }
+
+    /// The ranges covered by `length` bytes from `address`: the part up to
+    /// the top of memory, then the part that wrapped round to 0x0000.
+    fn span(&self, address: &Word, length: usize) -> Result<(ops::Range<usize>, ops::Range<usize>), MemoryError> {
+        let start = address.full as usize;
+        let end = start + length;
+        if end <= self.memory_size {
+            Ok((start..end, 0..0))
+        } else if self.memory_size == ADDRESS_SPACE && length <= ADDRESS_SPACE {
+            Ok((start..ADDRESS_SPACE, 0..end - ADDRESS_SPACE))
+        } else {
+            Err(MemoryError::OutOfRange(self.memory_size.max(start)))
+        }
+    }
+
+    /// Return addresses of the subroutines being run, outermost first.
+    pub fn stack(&self) -> &[Word] {
+        &self.stack
+    }
+
+    pub fn pop_stack(&mut self) -> Result<Word, MemoryError> {
+        self.stack.pop().ok_or(MemoryError::StackUnderflow)
+    }
+
+    pub fn push_stack(&mut self, value: Word) -> Result<(), MemoryError> {
+        if self.stack.len() >= STACK_DEPTH {
+            return Err(MemoryError::StackOverflow);
+        }
         self.stack.push(value);
         trace!("{:?}", self.stack);
+        Ok(())
     }
 
     pub fn print_mem_section(&self, start: u16, end: u16) {
-        for (address, data) in self.memory.iter().enumerate() {
-            if address as u16 >= start && address as u16 <= end {
-                trace!("{:04X} : {:02x}", address, data);
-            }
+        let end = (end as usize).min(self.memory_size - 1);
+        for address in start as usize..end + 1 {
+            trace!("{:04X} : {:02x}", address, self.memory[address]);
         }
     }
 
+    /// Address of the small font sprite for the low nibble of `character`.
     pub fn get_font(&self, character: Byte) -> Word {
-        Word::new_from_full((character * FONT_SIZE) as u16)
+        Word::new_from_full((character & 0x0F) as u16 * FONT_SIZE as u16)
+    }
+
+    /// Address of the large SUPER-CHIP font sprite for the low nibble of `character`.
+    pub fn get_big_font(&self, character: Byte) -> Word {
+        Word::new_from_full(BIG_FONT_START + (character & 0x0F) as u16 * BIG_FONT_SIZE as u16)
     }
 }
 
 #[cfg(test)]
 mod test {
     use memory;
-    use std::panic;
+    use memory::{MemoryError, Watch, WatchHit, Access};
 
     #[test]
     fn test_read_out_of_range() {
         let memory = memory::Memory::new(16);
         let address = memory::Word::new_from_full(18);
 
-        let result = panic::catch_unwind(|| {
-            memory.read(&address);
-        });
+        let result = memory.read(&address);
 
-        assert!(result.is_err());
+        assert_eq!(result, Err(MemoryError::OutOfRange(18)));
     }
 
     #[test]
-    #[should_panic]
     fn test_write_out_of_range() {
         let mut memory = memory::Memory::new(16);
         let address = memory::Word::new_from_full(27);
 
-        memory.write(&address, 14);
+        assert_eq!(memory.write(&address, 14), Err(MemoryError::OutOfRange(27)));
     }
 
     #[test]
@@ -181,8 +376,57 @@ mod test {
         let mut memory = memory::Memory::new(16);
         let address = memory::Word::new_from_full(12);
 
-        memory.write(&address, 128);
+        memory.write(&address, 128).unwrap();
 
-        assert_eq!(memory.read(&address), 128);
+        assert_eq!(memory.read(&address), Ok(128));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_read_slice_past_end() {
+        let memory = memory::Memory::new(16);
+
+        assert!(memory.read_slice(&memory::Word::new_from_full(12), 4).is_ok());
+        assert_eq!(memory.read_slice(&memory::Word::new_from_full(13), 4), Err(MemoryError::OutOfRange(16)));
+    }
+
+    #[test]
+    fn test_slices_wrap_in_a_full_address_space() {
+        let mut memory = memory::Memory::new(0x10000);
+        memory.add_watch(Watch { start: 0x0001, end: 0x0001, read: true, write: true });
+        memory.log_writes();
+
+        memory.write_slice(&memory::Word::new_from_full(0xFFFE), &[1, 2, 3, 4]).unwrap();
+
+        assert_eq!(&*memory.read_slice(&memory::Word::new_from_full(0xFFFE), 4).unwrap(), &[1, 2, 3, 4]);
+        assert_eq!(memory.take_writes(), vec![(0xFFFE, 1), (0xFFFF, 2), (0x0000, 3), (0x0001, 4)]);
+        assert_eq!(memory.take_hits(), vec![
+            WatchHit { address: 0x0001, access: Access::Write },
+            WatchHit { address: 0x0001, access: Access::Read }
+        ]);
+    }
+
+    #[test]
+    fn test_stack_limits() {
+        let mut memory = memory::Memory::new(16);
+
+        assert_eq!(memory.pop_stack(), Err(MemoryError::StackUnderflow));
+        for _ in 0..memory::STACK_DEPTH {
+            memory.push_stack(memory::Word::new_from_full(0x200)).unwrap();
+        }
+        assert_eq!(memory.push_stack(memory::Word::new_from_full(0x200)), Err(MemoryError::StackOverflow));
+    }
+
+    #[test]
+    fn test_watches_see_slices_but_not_peeks() {
+        let mut memory = memory::Memory::new(4096);
+        memory.add_watch(Watch { start: 0x304, end: 0x305, read: true, write: false });
+
+        memory.write_slice(&memory::Word::new_from_full(0x300), &[1; 8]).unwrap();
+        memory.peek(&memory::Word::new_from_full(0x304)).unwrap();
+        memory.read_slice(&memory::Word::new_from_full(0x303), 2).unwrap();
+        memory.read(&memory::Word::new_from_full(0x306)).unwrap();
+
+        assert_eq!(memory.take_hits(), vec![WatchHit { address: 0x304, access: Access::Read }]);
+        assert!(memory.take_hits().is_empty());
+    }
+}
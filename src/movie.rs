@@ -0,0 +1,343 @@
+//! Movies: everything needed to replay a run exactly. Given the same ROM,
+//! settings and RNG seed, feeding the same keypad state into each frame
+//! always ends in the same machine state.
+//!
+//! Movie files are text. The header gives the settings, then each line
+//! after `frames` is a keypad bit mask in hex and how many frames in a row
+//! it was held for:
+//!
+//! ```text
+//! version 1
+//! platform chip8
+//! quirks shift=1 load_store=unchanged jump=0 vf_reset=0 clipping=0 collision_rows=0 display_wait=0
+//! clock 600
+//! seed 1234
+//! rom 4a3c6b1e2f9d8c70
+//! hash 9ad0c1e35f0b2a64
+//! frames
+//! 0000 120
+//! 0020 4
+//! ```
+
+use emustate::Chip8State;
+use error::EmulatorError;
+use hash;
+use input::{InputSource, Keypad, FrontendEvent};
+use memory::Byte;
+use platform::Platform;
+use quirks::{Quirks, LoadStore};
+use scheduler::Scheduler;
+
+use std::fmt;
+
+pub const MOVIE_VERSION : u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub clock_hz: u32,
+    pub seed: u64,
+    pub rom_hash: u64,
+    /// The keypad state polled at the start of each frame.
+    pub frames: Vec<Keypad>,
+    /// `Chip8State::state_hash` once the last frame has run.
+    pub final_hash: Option<u64>
+}
+
+impl Movie {
+    /// An empty movie for recording a run of `rom` with these settings.
+    pub fn new(platform: Platform, quirks: Quirks, clock_hz: u32, seed: u64, rom: &[Byte]) -> Movie {
+        Movie {
+            platform,
+            quirks,
+            clock_hz,
+            seed,
+            rom_hash: hash::fnv1a(rom),
+            frames: Vec::new(),
+            final_hash: None
+        }
+    }
+
+    /// Applies the recorded settings. Call this before loading the ROM, as
+    /// changing platform clears memory.
+    pub fn setup(&self, state: &mut Chip8State) {
+        state.set_platform(self.platform);
+        state.set_quirks(self.quirks);
+        state.seed_rng(self.seed);
+    }
+
+    pub fn check_rom(&self, rom: &[Byte]) -> Result<(), String> {
+        if hash::fnv1a(rom) != self.rom_hash {
+            return Err("this movie was recorded with a different ROM".to_string());
+        }
+        Ok(())
+    }
+
+    /// Runs every frame of the movie as fast as possible, on a machine
+    /// already `setup` with the ROM loaded.
+    pub fn play(&self, state: &mut Chip8State) -> Result<(), EmulatorError> {
+        let mut scheduler = Scheduler::with_clock_speed(self.clock_hz);
+        let mut input = Playback::new(self.frames.clone());
+        let mut events = Vec::new();
+        for _ in 0..self.frames.len() {
+            scheduler.run_frame(state, &mut input, &mut events)?;
+        }
+        Ok(())
+    }
+
+    pub fn parse(text: &str) -> Result<Movie, String> {
+        let mut movie = Movie::new(Platform::Chip8, Quirks::default(), 0, 0, &[]);
+        let mut version = None;
+        let mut in_frames = false;
+
+        for (index, line) in text.lines().enumerate() {
+            let error = |message: String| format!("line {}: {}", index + 1, message);
+            let line = line.split('#').next().unwrap().trim();
+            let mut words = line.split_whitespace();
+            let keyword = match words.next() {
+                Some(keyword) => keyword,
+                None => continue
+            };
+            let rest: Vec<&str> = words.collect();
+
+            if in_frames {
+                let keys = u16::from_str_radix(keyword, 16).map_err(|_| error(format!("bad keypad `{}`", keyword)))?;
+                let count = match rest.first() {
+                    Some(count) => count.parse::<usize>().map_err(|_| error(format!("bad frame count `{}`", count)))?,
+                    None => 1
+                };
+                movie.frames.extend(std::iter::repeat_n(Keypad::from_bits(keys), count));
+                continue;
+            }
+
+            let value = || rest.first().cloned().ok_or_else(|| error(format!("`{}` needs a value", keyword)));
+            match keyword {
+                "version" => {
+                    let number = value()?.parse::<u32>().map_err(|_| error("bad version".to_string()))?;
+                    if number != MOVIE_VERSION {
+                        return Err(error(format!("movie version {} is not supported", number)));
+                    }
+                    version = Some(number);
+                },
+                "platform" => {
+                    movie.platform = Platform::from_name(value()?).ok_or_else(|| error(format!("unknown platform `{}`", rest[0])))?;
+                },
+                "quirks" => movie.quirks = parse_quirks(&rest).map_err(error)?,
+                "clock" => movie.clock_hz = value()?.parse().map_err(|_| error("bad clock speed".to_string()))?,
+                "seed" => movie.seed = value()?.parse().map_err(|_| error("bad seed".to_string()))?,
+                "rom" => movie.rom_hash = parse_hash(value()?).map_err(error)?,
+                "hash" => movie.final_hash = Some(parse_hash(value()?).map_err(error)?),
+                "frames" => in_frames = true,
+                _ => return Err(error(format!("unknown setting `{}`", keyword)))
+            }
+        }
+
+        if version.is_none() {
+            return Err("not a movie file: no version line".to_string());
+        }
+        if movie.clock_hz == 0 {
+            return Err("movie has no clock speed".to_string());
+        }
+        Ok(movie)
+    }
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "version {}", MOVIE_VERSION)?;
+        writeln!(f, "platform {}", self.platform.name())?;
+        let quirks = &self.quirks;
+        writeln!(f, "quirks shift={} load_store={} jump={} vf_reset={} clipping={} collision_rows={} display_wait={}",
+                 quirks.shift as u8, load_store_name(quirks.load_store), quirks.jump as u8,
+                 quirks.vf_reset as u8, quirks.clipping as u8, quirks.collision_rows as u8, quirks.display_wait as u8)?;
+        writeln!(f, "clock {}", self.clock_hz)?;
+        writeln!(f, "seed {}", self.seed)?;
+        writeln!(f, "rom {:016x}", self.rom_hash)?;
+        if let Some(hash) = self.final_hash {
+            writeln!(f, "hash {:016x}", hash)?;
+        }
+        writeln!(f, "frames")?;
+
+        let mut index = 0;
+        while index < self.frames.len() {
+            let keys = self.frames[index];
+            let run = self.frames[index..].iter().take_while(|&&frame| frame == keys).count();
+            writeln!(f, "{:04x} {}", keys.bits(), run)?;
+            index += run;
+        }
+        Ok(())
+    }
+}
+
+fn load_store_name(load_store: LoadStore) -> &'static str {
+    match load_store {
+        LoadStore::Increment => "increment",
+        LoadStore::IncrementByX => "increment_by_x",
+        LoadStore::Unchanged => "unchanged"
+    }
+}
+
+fn parse_quirks(settings: &[&str]) -> Result<Quirks, String> {
+    let mut quirks = Quirks::default();
+    for setting in settings {
+        let mut parts = setting.splitn(2, '=');
+        let (name, value) = (parts.next().unwrap(), parts.next().unwrap_or(""));
+        let flag = || match value {
+            "0" => Ok(false),
+            "1" => Ok(true),
+            _ => Err(format!("bad value `{}` for quirk `{}`", value, name))
+        };
+        match name {
+            "shift" => quirks.shift = flag()?,
+            "jump" => quirks.jump = flag()?,
+            "vf_reset" => quirks.vf_reset = flag()?,
+            "clipping" => quirks.clipping = flag()?,
+            "collision_rows" => quirks.collision_rows = flag()?,
+            "display_wait" => quirks.display_wait = flag()?,
+            "load_store" => {
+                quirks.load_store = match value {
+                    "increment" => LoadStore::Increment,
+                    "increment_by_x" => LoadStore::IncrementByX,
+                    "unchanged" => LoadStore::Unchanged,
+                    _ => return Err(format!("bad value `{}` for quirk `load_store`", value))
+                };
+            },
+            _ => return Err(format!("unknown quirk `{}`", name))
+        }
+    }
+    Ok(quirks)
+}
+
+fn parse_hash(text: &str) -> Result<u64, String> {
+    u64::from_str_radix(text, 16).map_err(|_| format!("bad hash `{}`", text))
+}
+
+/// Feeds a movie's keypad states back in, one per poll, and asks to quit
+/// on the last frame just as the recording did. A live input source can be
+/// attached so the window still responds; only its quit requests are used.
+pub struct Playback<'a> {
+    frames: Vec<Keypad>,
+    position: usize,
+    live: Option<&'a mut dyn InputSource>
+}
+
+impl<'a> Playback<'a> {
+    pub fn new(frames: Vec<Keypad>) -> Playback<'a> {
+        Playback {
+            frames,
+            position: 0,
+            live: None
+        }
+    }
+
+    pub fn with_live_input(frames: Vec<Keypad>, live: &'a mut dyn InputSource) -> Playback<'a> {
+        Playback {
+            live: Some(live),
+            ..Playback::new(frames)
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.position >= self.frames.len()
+    }
+}
+
+impl<'a> InputSource for Playback<'a> {
+    fn poll(&mut self, events: &mut Vec<FrontendEvent>) -> Keypad {
+        if let Some(ref mut live) = self.live {
+            let mut live_events = Vec::new();
+            live.poll(&mut live_events);
+            events.extend(live_events.into_iter().filter(|event| *event == FrontendEvent::Quit));
+        }
+
+        let keys = self.frames.get(self.position).cloned().unwrap_or_default();
+        self.position += 1;
+        if self.finished() {
+            events.push(FrontendEvent::Quit);
+        }
+        keys
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use movie::{Movie, Playback};
+    use emustate::Chip8State;
+    use input::{InputSource, Keypad, FrontendEvent};
+    use platform::Platform;
+    use quirks::Quirks;
+    use scheduler::Scheduler;
+
+    // Waits for a key, then draws random sprites forever:
+    // LD V0, K; RND V1, 0x3F; RND V2, 0x1F; LD F, V0; DRW V1, V2, 5; JP 0x202
+    const ROM: [u8; 12] = [0xF0, 0x0A, 0xC1, 0x3F, 0xC2, 0x1F, 0xF0, 0x29, 0xD1, 0x25, 0x12, 0x02];
+
+    fn machine(movie: &Movie) -> Chip8State {
+        let mut state = Chip8State::default();
+        movie.setup(&mut state);
+        state.load_instructions(ROM.to_vec()).unwrap();
+        state.load_font();
+        state
+    }
+
+    fn record() -> Movie {
+        let mut movie = Movie::new(Platform::Chip8, Quirks::cosmac_vip(), 300, 99, &ROM);
+        let mut state = machine(&movie);
+        let mut scheduler = Scheduler::with_clock_speed(movie.clock_hz);
+        let mut events = Vec::new();
+        for frame in 0..40 {
+            let keys = if (10..12).contains(&frame) { Keypad::with_pressed(&[0x7]) } else { Keypad::new() };
+            let mut input = Playback::new(vec![keys]);
+            scheduler.run_frame(&mut state, &mut input, &mut events).unwrap();
+            movie.frames.push(state.keypad());
+        }
+        movie.final_hash = Some(state.state_hash());
+        movie
+    }
+
+    #[test]
+    fn test_replay_reaches_recorded_state() {
+        let movie = record();
+        let mut state = machine(&movie);
+        movie.play(&mut state).unwrap();
+
+        assert_eq!(Some(state.state_hash()), movie.final_hash);
+    }
+
+    #[test]
+    fn test_text_round_trip() {
+        let movie = record();
+        let text = movie.to_string();
+
+        assert!(text.contains("\nframes\n0000 10\n0080 2\n0000 28\n"));
+        assert_eq!(Movie::parse(&text), Ok(movie));
+    }
+
+    #[test]
+    fn test_different_seed_diverges() {
+        let mut movie = record();
+        movie.seed += 1;
+        let mut state = machine(&movie);
+        movie.play(&mut state).unwrap();
+
+        assert_ne!(Some(state.state_hash()), movie.final_hash);
+    }
+
+    #[test]
+    fn test_playback_quits_on_last_frame() {
+        let mut input = Playback::new(vec![Keypad::new(), Keypad::with_pressed(&[1])]);
+        let mut events = Vec::new();
+
+        assert_eq!(input.poll(&mut events), Keypad::new());
+        assert!(events.is_empty());
+        assert_eq!(input.poll(&mut events), Keypad::with_pressed(&[1]));
+        assert_eq!(events, vec![FrontendEvent::Quit]);
+    }
+
+    #[test]
+    fn test_parse_errors_name_the_line() {
+        assert_eq!(Movie::parse("version 1\nclock fast").unwrap_err(), "line 2: bad clock speed");
+        assert_eq!(Movie::parse("version 1\nquirks wrap=1").unwrap_err(), "line 2: unknown quirk `wrap`");
+    }
+}
@@ -0,0 +1,855 @@
+//! Compiler for the Octo assembly language.
+//!
+//! Supports the instruction statements, labels, `:alias`, `:const`, `:calc`,
+//! `:byte`, `:next` and `:macro`, plus the `loop`/`while`/`again` and
+//! `if ... then` / `if ... begin ... else ... end` control structures. As in
+//! Octo, `:calc` expressions have no operator precedence and are evaluated
+//! right to left, so use brackets to group.
+//!
+//! The result starts at 0x200. If a `main` label is defined anywhere other
+//! than the very start, the program begins with a jump to it.
+
+use decoder::{Instruction, RegisterVal, RegisterRegister, RegisterRegisterNibble};
+use emustate::PC_START;
+use encoder;
+use memory::{Byte, Word};
+
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+
+/// A compile error, with the source line it was found on.
+#[derive(Debug, PartialEq, Eq)]
+pub struct OctoError {
+    pub line: usize,
+    pub message: String
+}
+
+impl fmt::Display for OctoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for OctoError {}
+
+/// How deep macro calls may nest before we assume a macro calls itself.
+const MAX_MACRO_DEPTH: usize = 64;
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    /// How many macro expansions it came out of.
+    depth: usize
+}
+
+#[derive(Debug)]
+struct Macro {
+    parameters: Vec<String>,
+    body: Vec<Token>
+}
+
+/// A jump or address whose label hadn't been defined when it was emitted.
+#[derive(Debug)]
+struct Fixup {
+    offset: usize,
+    label: String,
+    line: usize,
+    long: bool
+}
+
+/// An open `loop`, and the `while` exits that jump past its `again`.
+#[derive(Debug)]
+struct Loop {
+    start: u16,
+    exits: Vec<usize>,
+    line: usize
+}
+
+/// An open `if ... begin`, and the jump to patch at its `else` or `end`.
+#[derive(Debug)]
+struct Branch {
+    jump: usize,
+    line: usize
+}
+
+struct Compiler {
+    tokens: VecDeque<Token>,
+    rom: Vec<Byte>,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, i64>,
+    aliases: HashMap<String, Byte>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    loops: Vec<Loop>,
+    branches: Vec<Branch>,
+    line: usize
+}
+
+type Result<T> = ::std::result::Result<T, OctoError>;
+
+pub fn compile(source: &str) -> Result<Vec<Byte>> {
+    let mut compiler = Compiler {
+        tokens: tokenize(source),
+        rom: Vec::new(),
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        fixups: Vec::new(),
+        loops: Vec::new(),
+        branches: Vec::new(),
+        line: 1
+    };
+    compiler.compile()?;
+    Ok(compiler.rom)
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (index, text) in source.lines().enumerate() {
+        let code = text.split('#').next().unwrap();
+        for word in code.split_whitespace() {
+            tokens.push_back(Token { text: word.to_string(), line: index + 1, depth: 0 });
+        }
+    }
+    tokens
+}
+
+impl Compiler {
+    fn compile(&mut self) -> Result<()> {
+        if self.jumps_to_main() {
+            self.emit_address(Instruction::JUMP(Word::new_from_full(0)), "main", 1);
+        }
+        while !self.tokens.is_empty() {
+            self.statement()?;
+            if self.rom.len() > 0x10000 - PC_START as usize {
+                return Err(self.error("program does not fit in memory"));
+            }
+        }
+
+        if let Some(open) = self.loops.last() {
+            return Err(OctoError { line: open.line, message: "`loop` without a matching `again`".to_string() });
+        }
+        if let Some(open) = self.branches.last() {
+            return Err(OctoError { line: open.line, message: "`begin` without a matching `end`".to_string() });
+        }
+        for fixup in &self.fixups {
+            let address = match self.labels.get(&fixup.label) {
+                Some(&address) => address,
+                None => return Err(OctoError { line: fixup.line, message: format!("undefined label `{}`", fixup.label) })
+            };
+            if fixup.long {
+                self.rom[fixup.offset + 2] = (address >> 8) as Byte;
+                self.rom[fixup.offset + 3] = address as Byte;
+            } else if address > 0xFFF {
+                return Err(OctoError { line: fixup.line, message: format!("label `{}` is past 0xFFF", fixup.label) });
+            } else {
+                patch(&mut self.rom, fixup.offset, address);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a `main` label is defined somewhere after the start.
+    fn jumps_to_main(&self) -> bool {
+        let tokens: Vec<&str> = self.tokens.iter().map(|token| token.text.as_str()).collect();
+        let defines_main = tokens.windows(2).any(|pair| pair == [":", "main"]);
+        defines_main && tokens[..2] != [":", "main"]
+    }
+
+    fn statement(&mut self) -> Result<()> {
+        let token = self.next()?;
+        match token.text.as_str() {
+            ":" => {
+                let name = self.name()?;
+                let here = self.here();
+                self.define_label(name, here)
+            },
+            ":next" => {
+                let name = self.name()?;
+                let here = self.here() + 1;
+                self.define_label(name, here)
+            },
+            ":alias" => {
+                let name = self.name()?;
+                let register = self.next()?;
+                let register = self.register(&register)?;
+                self.aliases.insert(name, register);
+                Ok(())
+            },
+            ":const" => {
+                let name = self.name()?;
+                let value = self.next()?;
+                let value = self.value(&value)?;
+                self.constants.insert(name, value);
+                Ok(())
+            },
+            ":calc" => {
+                let name = self.name()?;
+                let value = self.calc_block()?;
+                self.constants.insert(name, value);
+                Ok(())
+            },
+            ":byte" => {
+                let value = if self.peek_is("{") {
+                    self.calc_block()?
+                } else {
+                    let token = self.next()?;
+                    self.value(&token)?
+                };
+                self.emit_byte(value)
+            },
+            ":macro" => self.define_macro(),
+            "loop" => {
+                let start = self.here();
+                self.loops.push(Loop { start, exits: Vec::new(), line: token.line });
+                Ok(())
+            },
+            "while" => {
+                let (skip_if_true, _) = self.condition()?;
+                self.emit(skip_if_true);
+                let jump = self.emit_placeholder_jump();
+                match self.loops.last_mut() {
+                    Some(open) => open.exits.push(jump),
+                    None => return Err(self.error("`while` outside of a loop"))
+                }
+                Ok(())
+            },
+            "again" => {
+                let open = self.loops.pop().ok_or_else(|| self.error("`again` without a `loop`"))?;
+                self.emit(Instruction::JUMP(Word::new_from_full(open.start)));
+                let here = self.here();
+                for exit in open.exits {
+                    patch(&mut self.rom, exit, here);
+                }
+                Ok(())
+            },
+            "if" => self.if_statement(),
+            "else" => {
+                let open = self.branches.pop().ok_or_else(|| self.error("`else` without an `if ... begin`"))?;
+                let jump = self.emit_placeholder_jump();
+                let here = self.here();
+                patch(&mut self.rom, open.jump, here);
+                self.branches.push(Branch { jump, line: open.line });
+                Ok(())
+            },
+            "end" => {
+                let open = self.branches.pop().ok_or_else(|| self.error("`end` without an `if ... begin`"))?;
+                let here = self.here();
+                patch(&mut self.rom, open.jump, here);
+                Ok(())
+            },
+            "clear" => self.emit_ok(Instruction::CLS),
+            "return" | ";" => self.emit_ok(Instruction::RET),
+            "exit" => self.emit_ok(Instruction::EXIT),
+            "lores" => self.emit_ok(Instruction::LOW),
+            "hires" => self.emit_ok(Instruction::HIGH),
+            "scroll-right" => self.emit_ok(Instruction::SCR),
+            "scroll-left" => self.emit_ok(Instruction::SCL),
+            "audio" => self.emit_ok(Instruction::AUDIO),
+            "scroll-down" => {
+                let lines = self.sized_value(0xF)?;
+                self.emit_ok(Instruction::SCD(lines as Byte))
+            },
+            "scroll-up" => {
+                let lines = self.sized_value(0xF)?;
+                self.emit_ok(Instruction::SCU(lines as Byte))
+            },
+            "plane" => {
+                let planes = self.sized_value(0x3)?;
+                self.emit_ok(Instruction::PLANE(planes as Byte))
+            },
+            "hex" => {
+                let x = self.next_register()?;
+                self.emit_ok(Instruction::LDFONT(x))
+            },
+            "bighex" => {
+                let x = self.next_register()?;
+                self.emit_ok(Instruction::LDHF(x))
+            },
+            "bcd" => {
+                let x = self.next_register()?;
+                self.emit_ok(Instruction::LDBCD(x))
+            },
+            "saveflags" => {
+                let x = self.next_register()?;
+                self.emit_ok(Instruction::STRPL(x))
+            },
+            "loadflags" => {
+                let x = self.next_register()?;
+                self.emit_ok(Instruction::LDRPL(x))
+            },
+            "save" | "load" => {
+                let x = self.next_register()?;
+                let instruction = if self.peek_is("-") {
+                    self.next()?;
+                    let registers = RegisterRegister { first_reg: x, second_reg: self.next_register()? };
+                    if token.text == "save" { Instruction::SAVERNG(registers) } else { Instruction::LOADRNG(registers) }
+                } else if token.text == "save" {
+                    Instruction::STARR(x)
+                } else {
+                    Instruction::LDARR(x)
+                };
+                self.emit_ok(instruction)
+            },
+            "sprite" => {
+                let x = self.next_register()?;
+                let y = self.next_register()?;
+                let nibble = self.sized_value(0xF)? as Byte;
+                self.emit_ok(Instruction::DRW(RegisterRegisterNibble { first_reg: x, second_reg: y, nibble }))
+            },
+            "jump" => {
+                let target = self.next()?;
+                self.emit_target(Instruction::JUMP(Word::new_from_full(0)), &target)
+            },
+            "jump0" => {
+                let target = self.next()?;
+                self.emit_target(Instruction::JUMPV0(Word::new_from_full(0)), &target)
+            },
+            "native" => {
+                let target = self.next()?;
+                self.emit_target(Instruction::Unknown(Word::new_from_full(0)), &target)
+            },
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.next_register()?;
+                let instruction = match token.text.as_str() {
+                    "delay" => Instruction::LDDTV(x),
+                    "buzzer" => Instruction::LDSTV(x),
+                    _ => Instruction::PITCH(x)
+                };
+                self.emit_ok(instruction)
+            },
+            "i" => self.i_statement(),
+            _ if self.is_register(&token.text) => self.register_statement(&token),
+            _ if self.macros.contains_key(&token.text) => self.expand_macro(&token),
+            _ if parse_number(&token.text).is_some() || self.constants.contains_key(&token.text) => {
+                let value = self.value(&token)?;
+                self.emit_byte(value)
+            },
+            _ if is_name(&token.text) => {
+                self.emit_target(Instruction::CALL(Word::new_from_full(0)), &token)
+            },
+            _ => Err(self.error(&format!("unexpected `{}`", token.text)))
+        }
+    }
+
+    fn if_statement(&mut self) -> Result<()> {
+        let line = self.line;
+        let (skip_if_true, skip_if_false) = self.condition()?;
+        let keyword = self.next()?;
+        match keyword.text.as_str() {
+            "then" => {
+                self.emit(skip_if_false);
+                Ok(())
+            },
+            "begin" => {
+                self.emit(skip_if_true);
+                let jump = self.emit_placeholder_jump();
+                self.branches.push(Branch { jump, line });
+                Ok(())
+            },
+            other => Err(self.error(&format!("expected `then` or `begin` after the condition, found `{}`", other)))
+        }
+    }
+
+    /// Parses `vx == n`, `vx != vy`, `vx key`, `vx -key` and so on into the
+    /// instruction that skips when the condition holds, and the one that skips
+    /// when it doesn't.
+    fn condition(&mut self) -> Result<(Instruction, Instruction)> {
+        let x = self.next_register()?;
+        let operator = self.next()?;
+        match operator.text.as_str() {
+            "key" => Ok((Instruction::SKP(x), Instruction::SKNP(x))),
+            "-key" => Ok((Instruction::SKNP(x), Instruction::SKP(x))),
+            "==" | "!=" => {
+                let operand = self.next()?;
+                let (equal, not_equal) = if self.is_register(&operand.text) {
+                    let y = self.register(&operand)?;
+                    (Instruction::SER(RegisterRegister { first_reg: x, second_reg: y }),
+                     Instruction::SNER(RegisterRegister { first_reg: x, second_reg: y }))
+                } else {
+                    let value = self.byte(&operand)?;
+                    (Instruction::SE(RegisterVal { register: x, value }),
+                     Instruction::SNE(RegisterVal { register: x, value }))
+                };
+                if operator.text == "==" { Ok((equal, not_equal)) } else { Ok((not_equal, equal)) }
+            },
+            other => Err(self.error(&format!("unsupported comparison `{}`", other)))
+        }
+    }
+
+    fn i_statement(&mut self) -> Result<()> {
+        let operator = self.next()?;
+        match operator.text.as_str() {
+            "+=" => {
+                let x = self.next_register()?;
+                self.emit_ok(Instruction::ADDI(x))
+            },
+            ":=" => {
+                let operand = self.next()?;
+                match operand.text.as_str() {
+                    "hex" => {
+                        let x = self.next_register()?;
+                        self.emit_ok(Instruction::LDFONT(x))
+                    },
+                    "bighex" => {
+                        let x = self.next_register()?;
+                        self.emit_ok(Instruction::LDHF(x))
+                    },
+                    "long" => {
+                        let target = self.next()?;
+                        let offset = self.rom.len();
+                        self.emit(Instruction::LDILONG);
+                        self.rom.extend_from_slice(&[0, 0]);
+                        match self.known_value(&target)? {
+                            Some(value) if (0..=0xFFFF).contains(&value) => {
+                                self.rom[offset + 2] = (value >> 8) as Byte;
+                                self.rom[offset + 3] = value as Byte;
+                            },
+                            Some(value) => return Err(self.error(&format!("{} does not fit in 16 bits", value))),
+                            None => self.fixups.push(Fixup { offset, label: target.text, line: target.line, long: true })
+                        }
+                        Ok(())
+                    },
+                    _ => self.emit_target(Instruction::LDI(Word::new_from_full(0)), &operand)
+                }
+            },
+            other => Err(self.error(&format!("unknown operator `{}` for `i`", other)))
+        }
+    }
+
+    fn register_statement(&mut self, target: &Token) -> Result<()> {
+        let x = self.register(target)?;
+        let operator = self.next()?;
+        let operand = self.next()?;
+        let is_register = self.is_register(&operand.text);
+        let both = |y| RegisterRegister { first_reg: x, second_reg: y };
+
+        let instruction = match (operator.text.as_str(), operand.text.as_str()) {
+            (":=", "delay") => Instruction::LDVDT(x),
+            (":=", "key") => Instruction::LDK(x),
+            (":=", "random") => {
+                let value = self.sized_value(0xFF)? as Byte;
+                Instruction::RND(RegisterVal { register: x, value })
+            },
+            (":=", _) if is_register => Instruction::LDR(both(self.register(&operand)?)),
+            (":=", _) => Instruction::LD(RegisterVal { register: x, value: self.byte(&operand)? }),
+            ("+=", _) if is_register => Instruction::ADDR(both(self.register(&operand)?)),
+            ("+=", _) => Instruction::ADD(RegisterVal { register: x, value: self.byte(&operand)? }),
+            ("-=", _) if is_register => Instruction::SUBR(both(self.register(&operand)?)),
+            ("-=", _) => {
+                let value = self.byte(&operand)?.wrapping_neg();
+                Instruction::ADD(RegisterVal { register: x, value })
+            },
+            ("=-", _) => Instruction::SUBNR(both(self.register(&operand)?)),
+            ("|=", _) => Instruction::ORR(both(self.register(&operand)?)),
+            ("&=", _) => Instruction::ANDR(both(self.register(&operand)?)),
+            ("^=", _) => Instruction::XORR(both(self.register(&operand)?)),
+            (">>=", _) => Instruction::SHR(both(self.register(&operand)?)),
+            ("<<=", _) => Instruction::SHL(both(self.register(&operand)?)),
+            (other, _) => return Err(self.error(&format!("unknown operator `{}` for `{}`", other, target.text)))
+        };
+        self.emit_ok(instruction)
+    }
+
+    fn define_macro(&mut self) -> Result<()> {
+        let name = self.name()?;
+        let mut parameters = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token.text == "{" {
+                break;
+            }
+            parameters.push(token.text);
+        }
+        let body = self.block_body()?;
+        self.macros.insert(name, Macro { parameters, body });
+        Ok(())
+    }
+
+    /// Replaces a macro call with its body, substituting the arguments that
+    /// follow it for the parameters.
+    fn expand_macro(&mut self, call: &Token) -> Result<()> {
+        if call.depth == MAX_MACRO_DEPTH {
+            return Err(self.error(&format!("macro `{}` expands too deeply", call.text)));
+        }
+        let (parameters, body) = {
+            let definition = &self.macros[&call.text];
+            (definition.parameters.clone(), definition.body.clone())
+        };
+        let mut arguments = HashMap::new();
+        for parameter in parameters {
+            let argument = self.next()?;
+            arguments.insert(parameter, argument.text);
+        }
+        for token in body.into_iter().rev() {
+            let text = arguments.get(&token.text).cloned().unwrap_or(token.text);
+            self.tokens.push_front(Token { text, line: token.line, depth: call.depth + 1 });
+        }
+        Ok(())
+    }
+
+    /// The tokens up to the `}` matching an already consumed `{`.
+    fn block_body(&mut self) -> Result<Vec<Token>> {
+        let mut depth = 1;
+        let mut body = Vec::new();
+        loop {
+            let token = self.next()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(body);
+                    }
+                },
+                _ => {}
+            }
+            body.push(token);
+        }
+    }
+
+    fn calc_block(&mut self) -> Result<i64> {
+        self.expect("{")?;
+        let mut tokens: VecDeque<Token> = self.block_body()?.into_iter().collect();
+        let value = self.expression(&mut tokens)?;
+        if let Some(extra) = tokens.front() {
+            return Err(self.error(&format!("unexpected `{}` in expression", extra.text)));
+        }
+        Ok(value)
+    }
+
+    /// `term (operator expression)?`, so a chain of operators is evaluated
+    /// from the right.
+    fn expression(&self, tokens: &mut VecDeque<Token>) -> Result<i64> {
+        let left = self.term(tokens)?;
+        let operator = match tokens.front() {
+            Some(token) if token.text != ")" => tokens.pop_front().unwrap(),
+            _ => return Ok(left)
+        };
+        let right = self.expression(tokens)?;
+        let value = match operator.text.as_str() {
+            "+" => left.wrapping_add(right),
+            "-" => left.wrapping_sub(right),
+            "*" => left.wrapping_mul(right),
+            "/" | "%" if right == 0 => return Err(self.error("division by zero")),
+            "/" => left / right,
+            "%" => left % right,
+            "&" => left & right,
+            "|" => left | right,
+            "^" => left ^ right,
+            "<<" => left.wrapping_shl(right as u32),
+            ">>" => left.wrapping_shr(right as u32),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => (left < right) as i64,
+            ">" => (left > right) as i64,
+            "<=" => (left <= right) as i64,
+            ">=" => (left >= right) as i64,
+            "==" => (left == right) as i64,
+            "!=" => (left != right) as i64,
+            other => return Err(self.error(&format!("unknown operator `{}`", other)))
+        };
+        Ok(value)
+    }
+
+    fn term(&self, tokens: &mut VecDeque<Token>) -> Result<i64> {
+        let token = tokens.pop_front().ok_or_else(|| self.error("expression ended early"))?;
+        match token.text.as_str() {
+            "(" => {
+                let value = self.expression(tokens)?;
+                match tokens.pop_front() {
+                    Some(ref close) if close.text == ")" => Ok(value),
+                    _ => Err(self.error("missing `)`"))
+                }
+            },
+            "-" => Ok(-self.term(tokens)?),
+            "~" => Ok(!self.term(tokens)?),
+            "!" => Ok((self.term(tokens)? == 0) as i64),
+            "HERE" => Ok(self.here() as i64),
+            _ => self.value(&token)
+        }
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        let opcode = encoder::encode(&instruction);
+        self.rom.push(opcode.high());
+        self.rom.push(opcode.low());
+    }
+
+    fn emit_ok(&mut self, instruction: Instruction) -> Result<()> {
+        self.emit(instruction);
+        Ok(())
+    }
+
+    fn emit_byte(&mut self, value: i64) -> Result<()> {
+        if !(-128..=255).contains(&value) {
+            return Err(self.error(&format!("{} does not fit in a byte", value)));
+        }
+        self.rom.push(value as Byte);
+        Ok(())
+    }
+
+    fn emit_placeholder_jump(&mut self) -> usize {
+        let offset = self.rom.len();
+        self.emit(Instruction::JUMP(Word::new_from_full(0)));
+        offset
+    }
+
+    fn emit_address(&mut self, instruction: Instruction, label: &str, line: usize) {
+        let offset = self.rom.len();
+        self.emit(instruction);
+        self.fixups.push(Fixup { offset, label: label.to_string(), line, long: false });
+    }
+
+    /// Emits an instruction taking a 12 bit address, which may be a label
+    /// defined further down.
+    fn emit_target(&mut self, instruction: Instruction, target: &Token) -> Result<()> {
+        match self.known_value(target)? {
+            Some(address) if (0..=0xFFF).contains(&address) => {
+                let offset = self.rom.len();
+                self.emit(instruction);
+                patch(&mut self.rom, offset, address as u16);
+            },
+            Some(address) => return Err(self.error(&format!("address 0x{:X} is past 0xFFF", address))),
+            None if is_name(&target.text) => self.emit_address(instruction, &target.text, target.line),
+            None => return Err(self.error(&format!("`{}` is not an address", target.text)))
+        }
+        Ok(())
+    }
+
+    fn define_label(&mut self, name: String, address: u16) -> Result<()> {
+        if self.labels.contains_key(&name) {
+            return Err(self.error(&format!("label `{}` is defined twice", name)));
+        }
+        self.labels.insert(name, address);
+        Ok(())
+    }
+
+    fn here(&self) -> u16 {
+        PC_START + self.rom.len() as u16
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.line = token.line;
+                Ok(token)
+            },
+            None => Err(self.error("unexpected end of file"))
+        }
+    }
+
+    fn peek_is(&self, text: &str) -> bool {
+        self.tokens.front().is_some_and(|token| token.text == text)
+    }
+
+    fn expect(&mut self, text: &str) -> Result<()> {
+        let token = self.next()?;
+        if token.text != text {
+            return Err(self.error(&format!("expected `{}`, found `{}`", text, token.text)));
+        }
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let token = self.next()?;
+        if !is_name(&token.text) {
+            return Err(self.error(&format!("`{}` is not a valid name", token.text)));
+        }
+        Ok(token.text)
+    }
+
+    fn is_register(&self, text: &str) -> bool {
+        register_number(text).is_some() || self.aliases.contains_key(text)
+    }
+
+    fn register(&self, token: &Token) -> Result<Byte> {
+        register_number(&token.text)
+            .or_else(|| self.aliases.get(&token.text).cloned())
+            .ok_or_else(|| self.error(&format!("expected a register, found `{}`", token.text)))
+    }
+
+    fn next_register(&mut self) -> Result<Byte> {
+        let token = self.next()?;
+        self.register(&token)
+    }
+
+    /// A number, constant or label that has already been defined.
+    fn known_value(&self, token: &Token) -> Result<Option<i64>> {
+        if let Some(number) = parse_number(&token.text) {
+            return Ok(Some(number));
+        }
+        if let Some(&value) = self.constants.get(&token.text) {
+            return Ok(Some(value));
+        }
+        Ok(self.labels.get(&token.text).map(|&address| address as i64))
+    }
+
+    fn value(&self, token: &Token) -> Result<i64> {
+        self.known_value(token)?
+            .ok_or_else(|| self.error(&format!("`{}` is not a number or a known name", token.text)))
+    }
+
+    /// A byte operand, which may be written as a negative number.
+    fn byte(&self, token: &Token) -> Result<Byte> {
+        let value = self.value(token)?;
+        if !(-128..=255).contains(&value) {
+            return Err(self.error(&format!("{} does not fit in a byte", value)));
+        }
+        Ok(value as Byte)
+    }
+
+    fn sized_value(&mut self, max: i64) -> Result<i64> {
+        let token = self.next()?;
+        let value = self.value(&token)?;
+        if !(0..=max).contains(&value) {
+            return Err(self.error(&format!("{} is out of range (0 to {})", value, max)));
+        }
+        Ok(value)
+    }
+
+    fn error(&self, message: &str) -> OctoError {
+        OctoError { line: self.line, message: message.to_string() }
+    }
+}
+
+/// Points the jump-like instruction at `offset` to `address`.
+fn patch(rom: &mut [Byte], offset: usize, address: u16) {
+    rom[offset] = (rom[offset] & 0xF0) | (address >> 8) as Byte & 0x0F;
+    rom[offset + 1] = address as Byte;
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text)
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else {
+        digits.parse().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn register_number(text: &str) -> Option<Byte> {
+    let digit = text.strip_prefix('v').or_else(|| text.strip_prefix('V'))?;
+    if digit.len() != 1 {
+        return None;
+    }
+    u8::from_str_radix(digit, 16).ok()
+}
+
+fn is_name(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {},
+        _ => return false
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod test {
+    use octo;
+    use emustate::Chip8State;
+
+    #[test]
+    fn test_instructions() {
+        let rom = octo::compile("clear v3 := 0x12 v3 += v4 i := 0x345 sprite v0 v1 5 ; save v2").unwrap();
+
+        assert_eq!(rom, vec![0x00, 0xE0, 0x63, 0x12, 0x83, 0x44, 0xA3, 0x45, 0xD0, 0x15, 0x00, 0xEE, 0xF2, 0x55]);
+    }
+
+    #[test]
+    fn test_main_and_forward_labels() {
+        let source = "
+            : sprite 0xF0 0x90
+            : main
+                i := sprite
+                draw
+                jump main
+            : draw sprite v0 v0 2 ;
+        ";
+        let rom = octo::compile(source).unwrap();
+
+        assert_eq!(rom, vec![0x12, 0x04, 0xF0, 0x90, 0xA2, 0x02, 0x22, 0x0A, 0x12, 0x04, 0xD0, 0x02, 0x00, 0xEE]);
+    }
+
+    #[test]
+    fn test_metaprogramming() {
+        let source = "
+            :alias counter v5
+            :const STEP 2
+            :calc DOUBLE { STEP * ( 3 + 1 ) }
+            :macro bump amount { counter += amount }
+            bump DOUBLE
+            :next target v0 := 0
+            :byte { target - 0x200 }
+        ";
+        let rom = octo::compile(source).unwrap();
+
+        assert_eq!(rom, vec![0x75, 0x08, 0x60, 0x00, 0x03]);
+    }
+
+    #[test]
+    fn test_calc_evaluates_right_to_left() {
+        let rom = octo::compile(":calc X { 10 - 2 - 1 } :byte X").unwrap();
+
+        assert_eq!(rom, vec![9]);
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let source = "
+            loop
+                while v0 != 5
+                if v1 == v2 then v0 += 1
+                if v3 key begin
+                    v4 := 1
+                else
+                    v4 := 2
+                end
+            again
+        ";
+        let rom = octo::compile(source).unwrap();
+
+        assert_eq!(rom, vec![
+            0x40, 0x05, 0x12, 0x14,             // while: skip if v0 != 5, else leave
+            0x91, 0x20, 0x70, 0x01,             // if v1 == v2 then
+            0xE3, 0x9E, 0x12, 0x10,             // begin: skip if pressed, else jump to else
+            0x64, 0x01, 0x12, 0x12,
+            0x64, 0x02,
+            0x12, 0x00                          // again
+        ]);
+    }
+
+    #[test]
+    fn test_errors_name_the_line() {
+        assert_eq!(octo::compile("clear\n\nv0 := 300").unwrap_err().to_string(), "line 3: 300 does not fit in a byte");
+        assert_eq!(octo::compile("clear\njump nowhere").unwrap_err().to_string(), "line 2: undefined label `nowhere`");
+        assert_eq!(octo::compile("\nloop\nclear").unwrap_err().line, 2);
+        assert_eq!(octo::compile("v0 := 1\nv0 ** v1").unwrap_err().to_string(), "line 2: unknown operator `**` for `v0`");
+        assert_eq!(octo::compile(":macro m a { m a }\n: main m 1").unwrap_err().to_string(), "line 1: macro `m` expands too deeply");
+        let bytes = ": main\n".to_string() + &":byte 0 ".repeat(0xFE00) + "\n:byte 0";
+        assert_eq!(octo::compile(&bytes).unwrap_err().to_string(), "line 3: program does not fit in memory");
+    }
+
+    #[test]
+    fn test_output_runs() {
+        let rom = octo::compile(": main v0 := 7 v1 := v0 v1 += 1").unwrap();
+        let mut state = Chip8State::default();
+        state.load_instructions(rom).unwrap();
+        for _ in 0..3 {
+            state.run_next_cycle().unwrap();
+        }
+
+        assert_eq!(state.cpu().get_reg(1), 8);
+    }
+}
@@ -0,0 +1,51 @@
+use quirks::Quirks;
+
+/// The instruction set a ROM was written for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Platform {
+    #[default]
+    Chip8,
+    /// SUPER-CHIP 1.1: 128x64 high resolution mode, scrolling, 16x16
+    /// sprites, a large font and RPL flag registers.
+    SuperChip,
+    /// XO-CHIP: SUPER-CHIP plus 64 KiB of memory, two bitplanes, register
+    /// range loads and stores, and programmable audio.
+    XoChip
+}
+
+impl Platform {
+    /// Looks a platform up by its `--platform` name.
+    pub fn from_name(name: &str) -> Option<Platform> {
+        match name {
+            "chip8" => Some(Platform::Chip8),
+            "schip" => Some(Platform::SuperChip),
+            "xochip" => Some(Platform::XoChip),
+            _ => None
+        }
+    }
+
+    /// The `--platform` name, as accepted by `from_name`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Platform::Chip8 => "chip8",
+            Platform::SuperChip => "schip",
+            Platform::XoChip => "xochip"
+        }
+    }
+
+    /// The quirks ROMs for this platform usually expect.
+    pub fn default_quirks(&self) -> Quirks {
+        match *self {
+            Platform::Chip8 => Quirks::default(),
+            Platform::SuperChip => Quirks::superchip(),
+            Platform::XoChip => Quirks::xochip()
+        }
+    }
+
+    pub fn memory_size(&self) -> usize {
+        match *self {
+            Platform::Chip8 | Platform::SuperChip => 0x1000,
+            Platform::XoChip => 0x10000
+        }
+    }
+}
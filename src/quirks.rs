@@ -0,0 +1,114 @@
+//! Behaviour which differs between CHIP-8 interpreters. Most ROMs were
+//! written against one of a handful of interpreters and rely on its choices.
+
+/// What `LD [I], Vx` and `LD Vx, [I]` do to I afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadStore {
+    /// I is left pointing past the last register: I += x + 1 (COSMAC VIP).
+    Increment,
+    /// I += x, one short of the VIP (CHIP-48).
+    IncrementByX,
+    /// I is not changed (SUPER-CHIP).
+    Unchanged
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// `SHR`/`SHL` shift Vx in place. Otherwise Vy is shifted into Vx.
+    pub shift: bool,
+    pub load_store: LoadStore,
+    /// `JP V0, addr` becomes `JP Vx, addr`, adding the register named by the
+    /// top nibble of the address instead of V0.
+    pub jump: bool,
+    /// `OR`, `AND` and `XOR` set VF to 0.
+    pub vf_reset: bool,
+    /// Sprites are cut off at the screen edges instead of wrapping around.
+    pub clipping: bool,
+    /// In hires mode `DRW` sets VF to the number of sprite rows that hit
+    /// something or were cut off at the bottom, rather than to 1.
+    pub collision_rows: bool,
+    /// `DRW` waits for the next frame before the program continues, limiting
+    /// programs to one sprite per frame.
+    pub display_wait: bool
+}
+
+impl Quirks {
+    /// The original COSMAC VIP interpreter.
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift: false,
+            load_store: LoadStore::Increment,
+            jump: false,
+            vf_reset: true,
+            clipping: true,
+            collision_rows: false,
+            display_wait: true
+        }
+    }
+
+    /// CHIP-48 on the HP-48 calculators.
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift: true,
+            load_store: LoadStore::IncrementByX,
+            jump: true,
+            vf_reset: false,
+            clipping: true,
+            collision_rows: false,
+            display_wait: false
+        }
+    }
+
+    /// SUPER-CHIP 1.1.
+    pub fn superchip() -> Quirks {
+        Quirks {
+            shift: true,
+            load_store: LoadStore::Unchanged,
+            jump: true,
+            vf_reset: false,
+            clipping: true,
+            collision_rows: true,
+            display_wait: false
+        }
+    }
+
+    /// XO-CHIP as implemented by Octo.
+    pub fn xochip() -> Quirks {
+        Quirks {
+            shift: false,
+            load_store: LoadStore::Increment,
+            jump: false,
+            vf_reset: false,
+            clipping: false,
+            collision_rows: false,
+            display_wait: false
+        }
+    }
+
+    /// Looks a preset up by its `--quirks` name.
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        match name {
+            "vip" => Some(Quirks::cosmac_vip()),
+            "chip48" => Some(Quirks::chip48()),
+            "schip" => Some(Quirks::superchip()),
+            "xochip" => Some(Quirks::xochip()),
+            _ => None
+        }
+    }
+}
+
+/// What this emulator has always done: in place shifts, I unchanged by loads
+/// and stores, `JP V0` and wrapping sprites.
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks {
+            shift: true,
+            load_store: LoadStore::Unchanged,
+            jump: false,
+            vf_reset: false,
+            clipping: false,
+            collision_rows: false,
+            display_wait: false
+        }
+    }
+}
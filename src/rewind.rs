@@ -0,0 +1,136 @@
+use std::collections::VecDeque;
+
+use emustate::Chip8State;
+use memory::Byte;
+
+/// Memory kept for rewinding by default, in bytes. A CHIP-8 snapshot is
+/// about 13 KB so this is roughly 40 seconds; XO-CHIP's 64 KB of memory
+/// makes it about 7.
+pub const DEFAULT_BUDGET : usize = 32 * 1024 * 1024;
+
+/// A ring buffer of save states, one per frame, for stepping backwards in
+/// time. Once the budget is used up the oldest snapshots are dropped.
+#[derive(Debug)]
+pub struct Rewind {
+    snapshots: VecDeque<Vec<Byte>>,
+    budget: usize,
+    used: usize
+}
+
+impl Rewind {
+    /// A budget of 0 turns rewinding off.
+    pub fn new(budget: usize) -> Rewind {
+        Rewind {
+            snapshots: VecDeque::new(),
+            budget,
+            used: 0
+        }
+    }
+
+    /// Number of frames that can currently be rewound.
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    /// Bytes held by the snapshots.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Snapshots the machine at the end of a frame.
+    pub fn record(&mut self, state: &Chip8State) {
+        let snapshot = state.save_state();
+        if snapshot.len() > self.budget {
+            return;
+        }
+        while self.used + snapshot.len() > self.budget {
+            let oldest = self.snapshots.pop_front().expect("budget is only used by snapshots");
+            self.used -= oldest.len();
+        }
+        self.used += snapshot.len();
+        self.snapshots.push_back(snapshot);
+    }
+
+    /// Puts the machine back to the most recent snapshot and forgets it, so
+    /// calling this once per frame steps back a frame at a time. Returns
+    /// false once there is nothing left to rewind.
+    pub fn rewind(&mut self, state: &mut Chip8State) -> bool {
+        match self.snapshots.pop_back() {
+            Some(snapshot) => {
+                self.used -= snapshot.len();
+                state.load_state(&snapshot).expect("snapshots we took ourselves can be loaded");
+                true
+            },
+            None => false
+        }
+    }
+}
+
+impl Default for Rewind {
+    fn default() -> Rewind {
+        Rewind::new(DEFAULT_BUDGET)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rewind::Rewind;
+    use emustate::Chip8State;
+
+    // ADD V0, 1; JP 0x200
+    const COUNTER: [u8; 4] = [0x70, 0x01, 0x12, 0x00];
+
+    fn counter() -> Chip8State {
+        let mut state = Chip8State::default();
+        state.load_instructions(COUNTER.to_vec()).unwrap();
+        state
+    }
+
+    #[test]
+    fn test_rewinds_a_frame_at_a_time() {
+        let mut state = counter();
+        let mut rewind = Rewind::default();
+        for _ in 0..5 {
+            state.run_next_cycle().unwrap();
+            state.run_next_cycle().unwrap();
+            rewind.record(&state);
+        }
+        assert_eq!(state.cpu().get_reg(0), 5);
+
+        assert!(rewind.rewind(&mut state));
+        assert!(rewind.rewind(&mut state));
+        assert_eq!(state.cpu().get_reg(0), 4);
+        assert_eq!(rewind.len(), 3);
+    }
+
+    #[test]
+    fn test_budget_drops_oldest_snapshots() {
+        let mut state = counter();
+        let size = state.save_state().len();
+        let mut rewind = Rewind::new(size * 3);
+        for _ in 0..10 {
+            state.run_next_cycle().unwrap();
+            state.run_next_cycle().unwrap();
+            rewind.record(&state);
+        }
+        assert_eq!(rewind.len(), 3);
+        assert_eq!(rewind.used(), size * 3);
+
+        while rewind.rewind(&mut state) {}
+        assert_eq!(state.cpu().get_reg(0), 8);
+        assert!(rewind.is_empty());
+    }
+
+    #[test]
+    fn test_zero_budget_disables() {
+        let mut state = counter();
+        let mut rewind = Rewind::new(0);
+        rewind.record(&state);
+
+        assert!(!rewind.rewind(&mut state));
+    }
+}
@@ -0,0 +1,132 @@
+use rand;
+
+use memory::Byte;
+use savestate::{StateWriter, StateReader, SaveStateError};
+
+/// The random number generator behind `RND`. Normally this is xorshift64*,
+/// whose whole state is one number, so a run can be reproduced from the seed
+/// it started with. Tests can swap in a fixed sequence instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rng {
+    XorShift { state: u64 },
+    /// Hands out `bytes` in order, starting again at the beginning once
+    /// they run out.
+    Sequence { bytes: Vec<Byte>, position: usize }
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng::XorShift { state: mix(seed) }
+    }
+
+    /// A generator with an unpredictable seed, for normal play.
+    pub fn from_entropy() -> Rng {
+        Rng::new(random_seed())
+    }
+
+    /// A generator that returns exactly `bytes`, over and over.
+    pub fn sequence(bytes: Vec<Byte>) -> Rng {
+        assert!(!bytes.is_empty(), "a random sequence needs at least one byte");
+        Rng::Sequence { bytes, position: 0 }
+    }
+
+    pub fn next_byte(&mut self) -> Byte {
+        match *self {
+            Rng::XorShift { ref mut state } => {
+                *state ^= *state >> 12;
+                *state ^= *state << 25;
+                *state ^= *state >> 27;
+                (state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as Byte
+            },
+            Rng::Sequence { ref bytes, ref mut position } => {
+                let byte = bytes[*position];
+                *position = (*position + 1) % bytes.len();
+                byte
+            }
+        }
+    }
+
+    pub fn write_state(&self, writer: &mut StateWriter) {
+        match *self {
+            Rng::XorShift { state } => {
+                writer.u8(0);
+                writer.u32((state >> 32) as u32);
+                writer.u32(state as u32);
+            },
+            Rng::Sequence { ref bytes, position } => {
+                writer.u8(1);
+                writer.bytes(bytes);
+                writer.u32(position as u32);
+            }
+        }
+    }
+
+    pub fn read_state(reader: &mut StateReader) -> Result<Rng, SaveStateError> {
+        match reader.u8()? {
+            0 => {
+                let state = (reader.u32()? as u64) << 32 | reader.u32()? as u64;
+                if state == 0 {
+                    return Err(SaveStateError::Invalid("random state"));
+                }
+                Ok(Rng::XorShift { state })
+            },
+            1 => {
+                let bytes = reader.bytes()?.to_vec();
+                let position = reader.u32()? as usize;
+                if position >= bytes.len() {
+                    return Err(SaveStateError::Invalid("random sequence"));
+                }
+                Ok(Rng::Sequence { bytes, position })
+            },
+            _ => Err(SaveStateError::Invalid("random generator"))
+        }
+    }
+}
+
+/// A fresh seed for runs that should be different each time.
+pub fn random_seed() -> u64 {
+    rand::random()
+}
+
+/// Spreads the seed over the whole state (splitmix64), so that small seeds
+/// give unrelated sequences and a seed of 0 doesn't get stuck at 0.
+fn mix(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    let mixed = z ^ (z >> 31);
+    if mixed == 0 { 1 } else { mixed }
+}
+
+#[cfg(test)]
+mod test {
+    use rng::Rng;
+    use savestate::{StateWriter, StateReader};
+
+    #[test]
+    fn test_same_seed_same_sequence() {
+        let mut first = Rng::new(42);
+        let mut second = Rng::new(42);
+        let mut other = Rng::new(43);
+        let a: Vec<u8> = (0..32).map(|_| first.next_byte()).collect();
+        let b: Vec<u8> = (0..32).map(|_| second.next_byte()).collect();
+        let c: Vec<u8> = (0..32).map(|_| other.next_byte()).collect();
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn test_sequence_repeats_and_round_trips() {
+        let mut rng = Rng::sequence(vec![1, 2, 3]);
+        assert_eq!(rng.next_byte(), 1);
+
+        let mut writer = StateWriter::new();
+        rng.write_state(&mut writer);
+        let bytes = writer.finish();
+        let mut restored = Rng::read_state(&mut StateReader::new(&bytes).unwrap()).unwrap();
+
+        let rest: Vec<u8> = (0..4).map(|_| restored.next_byte()).collect();
+        assert_eq!(rest, vec![2, 3, 1, 2]);
+    }
+}
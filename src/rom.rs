@@ -0,0 +1,23 @@
+//! Loading programs from disk, whether they are ROM images or Octo source.
+
+use memory::Byte;
+use octo;
+
+use std::fs;
+use std::path::Path;
+
+/// Reads a ROM image, compiling it first if it's Octo source (`.8o`).
+/// Errors start with the file name.
+pub fn read_rom<P: AsRef<Path>>(filename: P) -> Result<Vec<Byte>, String> {
+    let filename = filename.as_ref();
+    let error = |message: String| format!("{}: {}", filename.display(), message);
+    if is_octo_source(filename) {
+        let source = fs::read_to_string(filename).map_err(|e| error(e.to_string()))?;
+        return octo::compile(&source).map_err(|e| error(e.to_string()));
+    }
+    fs::read(filename).map_err(|e| error(e.to_string()))
+}
+
+pub fn is_octo_source<P: AsRef<Path>>(filename: P) -> bool {
+    filename.as_ref().extension().is_some_and(|extension| extension == "8o")
+}
@@ -0,0 +1,250 @@
+//! The binary save state format. A state is the magic bytes, a format
+//! version, then the CPU, memory and framebuffer sections in that order.
+//! Multi-byte numbers are big endian, like CHIP-8 itself.
+
+use memory::{Byte, Word};
+use platform::Platform;
+use quirks::{Quirks, LoadStore};
+
+use std::error::Error;
+use std::fmt;
+
+pub const MAGIC: [u8; 4] = *b"C8ST";
+
+/// Bump this whenever the layout changes, and keep reading older versions
+/// where that is possible. Version 2 added the random number generator and
+/// version 3 the `collision_rows` quirk.
+pub const VERSION: u16 = 3;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SaveStateError {
+    NotASaveState,
+    UnsupportedVersion(u16),
+    Truncated,
+    Invalid(&'static str)
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SaveStateError::NotASaveState => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(version) => {
+                write!(f, "save state version {} is not supported (expected up to {})", version, VERSION)
+            },
+            SaveStateError::Truncated => write!(f, "save state is truncated"),
+            SaveStateError::Invalid(what) => write!(f, "save state has an invalid {}", what)
+        }
+    }
+}
+
+impl Error for SaveStateError {}
+
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    bytes: Vec<Byte>
+}
+
+impl StateWriter {
+    /// Starts a state with the header already written.
+    pub fn new() -> StateWriter {
+        let mut writer = StateWriter { bytes: Vec::new() };
+        writer.bytes.extend_from_slice(&MAGIC);
+        writer.u16(VERSION);
+        writer
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.push((value >> 8) as Byte);
+        self.bytes.push(value as Byte);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.u16((value >> 16) as u16);
+        self.u16(value as u16);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn word(&mut self, value: &Word) {
+        self.u16(value.full());
+    }
+
+    /// A length prefixed run of bytes.
+    pub fn bytes(&mut self, bytes: &[Byte]) {
+        self.u32(bytes.len() as u32);
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn platform(&mut self, platform: Platform) {
+        self.u8(match platform {
+            Platform::Chip8 => 0,
+            Platform::SuperChip => 1,
+            Platform::XoChip => 2
+        });
+    }
+
+    pub fn quirks(&mut self, quirks: &Quirks) {
+        self.bool(quirks.shift);
+        self.u8(match quirks.load_store {
+            LoadStore::Increment => 0,
+            LoadStore::IncrementByX => 1,
+            LoadStore::Unchanged => 2
+        });
+        self.bool(quirks.jump);
+        self.bool(quirks.vf_reset);
+        self.bool(quirks.clipping);
+        self.bool(quirks.display_wait);
+        self.bool(quirks.collision_rows);
+    }
+
+    pub fn finish(self) -> Vec<Byte> {
+        self.bytes
+    }
+}
+
+#[derive(Debug)]
+pub struct StateReader<'a> {
+    bytes: &'a [Byte],
+    version: u16
+}
+
+impl<'a> StateReader<'a> {
+    /// Checks the header, leaving the reader at the first section.
+    pub fn new(bytes: &'a [Byte]) -> Result<StateReader<'a>, SaveStateError> {
+        if bytes.len() < MAGIC.len() || bytes[..MAGIC.len()] != MAGIC {
+            return Err(SaveStateError::NotASaveState);
+        }
+        let mut reader = StateReader { bytes: &bytes[MAGIC.len()..], version: 0 };
+        reader.version = reader.u16()?;
+        if reader.version == 0 || reader.version > VERSION {
+            return Err(SaveStateError::UnsupportedVersion(reader.version));
+        }
+        Ok(reader)
+    }
+
+    /// The format version the state was written with.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [Byte], SaveStateError> {
+        if self.bytes.len() < count {
+            return Err(SaveStateError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, SaveStateError> {
+        let bytes = self.take(2)?;
+        Ok((bytes[0] as u16) << 8 | bytes[1] as u16)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SaveStateError> {
+        Ok((self.u16()? as u32) << 16 | self.u16()? as u32)
+    }
+
+    pub fn bool(&mut self) -> Result<bool, SaveStateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SaveStateError::Invalid("flag"))
+        }
+    }
+
+    pub fn word(&mut self) -> Result<Word, SaveStateError> {
+        Ok(Word::new_from_full(self.u16()?))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [Byte], SaveStateError> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+
+    pub fn platform(&mut self) -> Result<Platform, SaveStateError> {
+        match self.u8()? {
+            0 => Ok(Platform::Chip8),
+            1 => Ok(Platform::SuperChip),
+            2 => Ok(Platform::XoChip),
+            _ => Err(SaveStateError::Invalid("platform"))
+        }
+    }
+
+    pub fn quirks(&mut self) -> Result<Quirks, SaveStateError> {
+        let mut quirks = Quirks {
+            shift: self.bool()?,
+            load_store: match self.u8()? {
+                0 => LoadStore::Increment,
+                1 => LoadStore::IncrementByX,
+                2 => LoadStore::Unchanged,
+                _ => return Err(SaveStateError::Invalid("load/store quirk"))
+            },
+            jump: self.bool()?,
+            vf_reset: self.bool()?,
+            clipping: self.bool()?,
+            collision_rows: false,
+            display_wait: self.bool()?
+        };
+        if self.version >= 3 {
+            quirks.collision_rows = self.bool()?;
+        }
+        Ok(quirks)
+    }
+
+    /// Fails if anything is left over, which means the sections didn't
+    /// line up with what we expected.
+    pub fn finish(self) -> Result<(), SaveStateError> {
+        if !self.bytes.is_empty() {
+            return Err(SaveStateError::Invalid("length"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use savestate::{StateWriter, StateReader, SaveStateError, MAGIC};
+    use quirks::Quirks;
+    use platform::Platform;
+
+    #[test]
+    fn test_values_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.u16(0x1234);
+        writer.bytes(&[1, 2, 3]);
+        writer.platform(Platform::XoChip);
+        writer.quirks(&Quirks::cosmac_vip());
+        let bytes = writer.finish();
+
+        let mut reader = StateReader::new(&bytes).unwrap();
+        assert_eq!(reader.u16(), Ok(0x1234));
+        assert_eq!(reader.bytes(), Ok(&[1, 2, 3][..]));
+        assert_eq!(reader.platform(), Ok(Platform::XoChip));
+        assert_eq!(reader.quirks(), Ok(Quirks::cosmac_vip()));
+        assert_eq!(reader.finish(), Ok(()));
+    }
+
+    #[test]
+    fn test_bad_headers() {
+        assert_eq!(StateReader::new(b"ROM!").unwrap_err(), SaveStateError::NotASaveState);
+
+        let mut future = MAGIC.to_vec();
+        future.extend_from_slice(&[0xFF, 0xFF]);
+        assert_eq!(StateReader::new(&future).unwrap_err(), SaveStateError::UnsupportedVersion(0xFFFF));
+
+        let empty = StateWriter::new().finish();
+        let mut reader = StateReader::new(&empty).unwrap();
+        assert_eq!(reader.u8(), Err(SaveStateError::Truncated));
+    }
+}
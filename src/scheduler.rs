@@ -0,0 +1,283 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use emustate::Chip8State;
+use input::{InputSource, FrontendEvent};
+use error::EmulatorError;
+
+/// The delay and sound timers count down, and the screen refreshes, at 60 Hz.
+pub const FRAME_RATE : u32 = 60;
+pub const DEFAULT_INSTRUCTIONS_PER_FRAME : u32 = 10;
+
+/// If we fall this many frames behind (a breakpoint, the window being
+/// dragged) stop trying to catch up and restart the frame clock.
+const MAX_FRAMES_BEHIND : u32 = 5;
+
+/// Splits emulation into 60 Hz frames. Each frame polls input once, runs the
+/// frame's share of instructions, ticks the timers once and presents the
+/// screen once.
+pub struct Scheduler {
+    clock_hz: u32,
+    frames: u64,
+    remaining: u32,
+    frame_duration: Duration,
+    next_frame: Option<Instant>
+}
+
+impl Scheduler {
+    pub fn new(instructions_per_frame: u32) -> Scheduler {
+        Scheduler::with_clock_speed(instructions_per_frame.saturating_mul(FRAME_RATE))
+    }
+
+    /// Runs instructions at `clock_hz` on average. Rates which are not a
+    /// multiple of 60 spread the remainder over the frames of each second.
+    pub fn with_clock_speed(clock_hz: u32) -> Scheduler {
+        Scheduler {
+            clock_hz: clock_hz.max(1),
+            frames: 0,
+            remaining: 0,
+            frame_duration: Duration::new(0, 1_000_000_000 / FRAME_RATE),
+            next_frame: None
+        }
+    }
+
+    pub fn clock_speed(&self) -> u32 {
+        self.clock_hz
+    }
+
+    /// Number of frames completed so far.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// True if no instructions of the current frame have run yet.
+    pub fn at_frame_start(&self) -> bool {
+        self.remaining == 0
+    }
+
+    /// Runs a whole frame, or the rest of the current one if it was started
+    /// by `step`. After an error the frame can be resumed with `step`.
+    pub fn run_frame(&mut self, state: &mut Chip8State, input: &mut dyn InputSource, events: &mut Vec<FrontendEvent>) -> Result<(), EmulatorError> {
+        if self.at_frame_start() {
+            self.begin_frame(state, input, events);
+        }
+        while self.remaining > 0 {
+            self.execute(state)?;
+        }
+        Ok(())
+    }
+
+    /// Polls input for the next frame without running any of it, so events
+    /// can be acted on first. Does nothing part way through a frame.
+    pub fn poll(&mut self, state: &mut Chip8State, input: &mut dyn InputSource, events: &mut Vec<FrontendEvent>) {
+        if self.at_frame_start() {
+            self.begin_frame(state, input, events);
+        }
+    }
+
+    /// Drops the rest of the current frame without running it, ticking the
+    /// timers or counting it.
+    pub fn skip_frame(&mut self) {
+        self.remaining = 0;
+    }
+
+    /// Runs a single instruction, beginning or ending a frame around it as
+    /// needed so that stepping keeps the same timing as running freely.
+    pub fn step(&mut self, state: &mut Chip8State, input: &mut dyn InputSource, events: &mut Vec<FrontendEvent>) -> Result<(), EmulatorError> {
+        if self.at_frame_start() {
+            self.begin_frame(state, input, events);
+            // Clocks below 60 Hz leave some frames with nothing to run, so
+            // go on to the next one, keeping the events of those skipped.
+            while self.at_frame_start() {
+                let mut more = Vec::new();
+                self.begin_frame(state, input, &mut more);
+                events.append(&mut more);
+            }
+        }
+        self.execute(state)
+    }
+
+    /// Sleeps until the next frame is due, keeping a steady 60 Hz over time.
+    pub fn wait_for_next_frame(&mut self) {
+        let now = Instant::now();
+        let next_frame = match self.next_frame {
+            Some(next_frame) if next_frame + self.frame_duration * MAX_FRAMES_BEHIND > now => next_frame,
+            _ => now
+        };
+
+        if next_frame > now {
+            thread::sleep(next_frame - now);
+        }
+        self.next_frame = Some(next_frame + self.frame_duration);
+    }
+
+    /// Polls input, replacing the events of the previous frame.
+    fn begin_frame(&mut self, state: &mut Chip8State, input: &mut dyn InputSource, events: &mut Vec<FrontendEvent>) {
+        events.clear();
+        let keys = input.poll(events);
+        state.set_keypad(keys);
+
+        let second = self.frames % FRAME_RATE as u64;
+        let clock = self.clock_hz as u64;
+        self.remaining = ((second + 1) * clock / FRAME_RATE as u64 - second * clock / FRAME_RATE as u64) as u32;
+        if self.remaining == 0 {
+            self.end_frame(state);
+        }
+    }
+
+    fn execute(&mut self, state: &mut Chip8State) -> Result<(), EmulatorError> {
+        state.run_next_cycle()?;
+        self.remaining -= 1;
+        if state.cpu().waiting_for_vblank() {
+            self.remaining = 0;
+        }
+        if self.remaining == 0 {
+            self.end_frame(state);
+        }
+        Ok(())
+    }
+
+    fn end_frame(&mut self, state: &mut Chip8State) {
+        state.tick_timers();
+        state.present();
+        self.frames += 1;
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Scheduler {
+        Scheduler::new(DEFAULT_INSTRUCTIONS_PER_FRAME)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use scheduler::Scheduler;
+    use emustate::Chip8State;
+    use input::{ScriptedInput, FrontendEvent};
+    use quirks::Quirks;
+
+    // LD V0, 0x3C; LD DT, V0; loop: ADD V1, 1; JP loop
+    const COUNT_LOOP : [u8; 8] = [0x60, 0x3C, 0xF0, 0x15, 0x71, 0x01, 0x12, 0x04];
+
+    fn run_frames(scheduler: &mut Scheduler, frames: u32) -> Chip8State {
+        let mut state = Chip8State::default();
+        state.load_instructions(COUNT_LOOP.to_vec()).unwrap();
+        let mut input = ScriptedInput::new();
+        let mut events = Vec::new();
+        for _ in 0..frames {
+            scheduler.run_frame(&mut state, &mut input, &mut events).unwrap();
+        }
+        state
+    }
+
+    #[test]
+    fn test_timers_tick_once_per_frame_at_any_speed() {
+        for ipf in &[2, 10, 50] {
+            let state = run_frames(&mut Scheduler::new(*ipf), 11);
+            assert_eq!(state.cpu().delay_timer(), 0x3C - 11);
+        }
+    }
+
+    #[test]
+    fn test_clock_speed_spreads_remainder_over_a_second() {
+        let mut scheduler = Scheduler::with_clock_speed(100);
+        let state = run_frames(&mut scheduler, 60);
+
+        // Two instructions set DT, the rest of the 100 alternate ADD and JP.
+        assert_eq!(state.cpu().get_reg(1), 49);
+        assert_eq!(scheduler.frames(), 60);
+    }
+
+    #[test]
+    fn test_step_keeps_frame_timing() {
+        let mut scheduler = Scheduler::new(4);
+        let mut state = Chip8State::default();
+        state.load_instructions(COUNT_LOOP.to_vec()).unwrap();
+        let mut input = ScriptedInput::new();
+        let mut events = Vec::new();
+
+        for _ in 0..8 {
+            scheduler.step(&mut state, &mut input, &mut events).unwrap();
+        }
+
+        assert_eq!(scheduler.frames(), 2);
+        assert_eq!(state.cpu().delay_timer(), 0x3C - 2);
+    }
+
+    #[test]
+    fn test_step_skips_empty_frames_at_slow_clocks() {
+        let mut scheduler = Scheduler::with_clock_speed(30);
+        let mut state = Chip8State::default();
+        state.load_instructions(COUNT_LOOP.to_vec()).unwrap();
+        let mut input = ScriptedInput::new().quit_at(0);
+        let mut events = Vec::new();
+
+        for _ in 0..5 {
+            scheduler.step(&mut state, &mut input, &mut events).unwrap();
+        }
+
+        assert_eq!(state.cycles(), 5);
+        assert_eq!(scheduler.frames(), 10);
+        assert_eq!(state.cpu().delay_timer(), 0x3C - 7);
+
+        let mut events = Vec::new();
+        let mut input = ScriptedInput::new().quit_at(1);
+        let mut scheduler = Scheduler::with_clock_speed(30);
+        scheduler.step(&mut state, &mut input, &mut events).unwrap();
+        assert_eq!(events, vec![FrontendEvent::Quit]);
+    }
+
+    #[test]
+    fn test_display_wait_ends_frame_after_draw() {
+        // loop: DRW V0, V0, 1; ADD V1, 1; JP loop
+        let program = [0xD0, 0x01, 0x71, 0x01, 0x12, 0x00];
+        let mut scheduler = Scheduler::new(30);
+        let mut state = Chip8State::default();
+        state.set_quirks(Quirks::cosmac_vip());
+        state.load_instructions(program.to_vec()).unwrap();
+        let mut input = ScriptedInput::new();
+        let mut events = Vec::new();
+
+        for _ in 0..3 {
+            scheduler.run_frame(&mut state, &mut input, &mut events).unwrap();
+        }
+
+        assert_eq!(scheduler.frames(), 3);
+        assert_eq!(state.cpu().get_reg(1), 2);
+    }
+
+    #[test]
+    fn test_events_only_last_one_frame() {
+        // JP 0x200
+        let mut scheduler = Scheduler::new(1);
+        let mut state = Chip8State::default();
+        state.load_instructions(vec![0x12, 0x00]).unwrap();
+        let mut input = ScriptedInput::new().quit_at(0);
+        let mut events = Vec::new();
+
+        scheduler.run_frame(&mut state, &mut input, &mut events).unwrap();
+        assert_eq!(events, vec![FrontendEvent::Quit]);
+        scheduler.run_frame(&mut state, &mut input, &mut events).unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn test_skipped_frames_run_nothing() {
+        let mut scheduler = Scheduler::new(4);
+        let mut state = Chip8State::default();
+        state.load_instructions(COUNT_LOOP.to_vec()).unwrap();
+        let mut input = ScriptedInput::new().quit_at(0);
+        let mut events = Vec::new();
+
+        scheduler.poll(&mut state, &mut input, &mut events);
+        assert_eq!(events, vec![FrontendEvent::Quit]);
+        scheduler.skip_frame();
+        assert_eq!((scheduler.frames(), state.cycles()), (0, 0));
+
+        scheduler.poll(&mut state, &mut input, &mut events);
+        assert!(events.is_empty());
+        scheduler.run_frame(&mut state, &mut input, &mut events).unwrap();
+        assert_eq!((scheduler.frames(), state.cycles()), (1, 4));
+    }
+}
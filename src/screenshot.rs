@@ -0,0 +1,148 @@
+//! Writes the framebuffer out without a window: as ASCII art, a PBM or PNG
+//! image, or a hash of its contents, for headless runs and tests.
+
+use display::{Framebuffer, PALETTE};
+use hash;
+
+/// The character for each pixel value. Single plane screens only use the
+/// first two.
+const ASCII_PIXELS : [char; 4] = ['.', '#', '+', '@'];
+
+/// One line per row of the current resolution, `#` for lit pixels and `.`
+/// for unlit ones. XO-CHIP pixels only in the second plane are `+`, and
+/// pixels in both are `@`.
+pub fn to_ascii(framebuffer: &Framebuffer) -> String {
+    let mut text = String::with_capacity((framebuffer.width() + 1) * framebuffer.height());
+    for y in 0..framebuffer.height() {
+        for x in 0..framebuffer.width() {
+            text.push(ASCII_PIXELS[framebuffer.pixel_value(x, y) as usize & 0b11]);
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// A stable hash of the resolution and every pixel value.
+pub fn hash(framebuffer: &Framebuffer) -> u64 {
+    let mut bytes = vec![framebuffer.width() as u8, framebuffer.height() as u8];
+    for y in 0..framebuffer.height() {
+        for x in 0..framebuffer.width() {
+            bytes.push(framebuffer.pixel_value(x, y));
+        }
+    }
+    hash::fnv1a(&bytes)
+}
+
+/// A binary (P4) PBM image, black where a pixel is lit in any plane.
+pub fn to_pbm(framebuffer: &Framebuffer) -> Vec<u8> {
+    let mut image = format!("P4\n{} {}\n", framebuffer.width(), framebuffer.height()).into_bytes();
+    for y in 0..framebuffer.height() {
+        for x in (0..framebuffer.width()).step_by(8) {
+            let byte = (0..8).fold(0u8, |byte, bit| byte | ((framebuffer.pixel(x + bit, y) as u8) << (7 - bit)));
+            image.push(byte);
+        }
+    }
+    image
+}
+
+/// A PNG image in the `PALETTE` colours, one image pixel per CHIP-8 pixel.
+/// The pixel data is stored without compression, which keeps this short and
+/// is still tiny at these sizes.
+pub fn to_png(framebuffer: &Framebuffer) -> Vec<u8> {
+    let (width, height) = (framebuffer.width(), framebuffer.height());
+    let mut image = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bit palette indices, default compression, filtering and no interlacing.
+    header.extend_from_slice(&[8, 3, 0, 0, 0]);
+    png_chunk(&mut image, b"IHDR", &header);
+
+    let palette: Vec<u8> = PALETTE.iter().flat_map(|colour| colour.iter().cloned()).collect();
+    png_chunk(&mut image, b"PLTE", &palette);
+
+    // Each row starts with its filter type, 0 for none.
+    let mut pixels = Vec::with_capacity((width + 1) * height);
+    for y in 0..height {
+        pixels.push(0);
+        pixels.extend((0..width).map(|x| framebuffer.pixel_value(x, y)));
+    }
+    png_chunk(&mut image, b"IDAT", &zlib_stored(&pixels));
+    png_chunk(&mut image, b"IEND", &[]);
+    image
+}
+
+fn png_chunk(image: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    image.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = image.len();
+    image.extend_from_slice(kind);
+    image.extend_from_slice(data);
+    let crc = crc32(&image[start..]);
+    image.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// A zlib stream of uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        stream.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        stream.push(blocks.peek().is_none() as u8);
+        stream.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        stream.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    stream.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    stream
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use screenshot::{self, crc32};
+    use display::{Framebuffer, Sprite};
+    use quirks::Quirks;
+
+    #[test]
+    fn test_ascii_and_pbm() {
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.draw_sprite(0, 1, &Sprite::new(&[0b1010_0000]), &Quirks::default());
+
+        let ascii = screenshot::to_ascii(&framebuffer);
+        let lines: Vec<&str> = ascii.lines().collect();
+        assert_eq!(lines.len(), 32);
+        assert_eq!(lines[1], format!("#.#{}", ".".repeat(61)));
+
+        let pbm = screenshot::to_pbm(&framebuffer);
+        assert!(pbm.starts_with(b"P4\n64 32\n"));
+        assert_eq!(pbm.len(), 9 + 8 * 32);
+        assert_eq!(pbm[9 + 8], 0b1010_0000);
+
+        assert_ne!(screenshot::hash(&framebuffer), screenshot::hash(&Framebuffer::new()));
+    }
+
+    #[test]
+    fn test_png_structure() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+
+        let png = screenshot::to_png(&Framebuffer::new());
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..24], &[0, 0, 0, 64, 0, 0, 0, 32]);
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xAE\x42\x60\x82");
+    }
+}
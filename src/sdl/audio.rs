@@ -0,0 +1,64 @@
+use sdl2;
+use sdl2::audio::{AudioCallback, AudioSpecDesired};
+
+use audio::{Audio, Oscillator, Pattern, SAMPLE_RATE};
+
+/// Plays the buzzer through the default SDL playback device.
+pub struct SdlAudio {
+    audio_device: sdl2::audio::AudioDevice<Oscillator>,
+    playing: bool,
+    pattern: Option<Pattern>
+}
+
+impl AudioCallback for Oscillator {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        for x in out.iter_mut() {
+            *x = self.next_sample();
+        }
+    }
+}
+
+impl SdlAudio {
+    /// Fails if SDL has no audio subsystem or no playback device can be
+    /// opened, in which case callers should fall back to `SilentAudio`.
+    pub fn new(context: &sdl2::Sdl) -> Result<SdlAudio, String> {
+        let sound_system = context.audio()?;
+        let desired_spec = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE as i32),
+            channels: Some(1),
+            samples: None
+        };
+
+        let device = sound_system.open_playback(None, &desired_spec, |spec| {
+            Oscillator::new(spec.freq as u32)
+        })?;
+
+        Ok(SdlAudio {
+            audio_device: device,
+            playing: false,
+            pattern: None
+        })
+    }
+}
+
+impl Audio for SdlAudio {
+    fn update(&mut self, buzzer: bool, pattern: Option<&Pattern>) {
+        if pattern != self.pattern.as_ref() {
+            self.pattern = pattern.cloned();
+            self.audio_device.lock().set_pattern(pattern);
+        }
+
+        if buzzer == self.playing {
+            return;
+        }
+
+        if buzzer {
+            self.audio_device.resume();
+        } else {
+            self.audio_device.pause();
+        }
+        self.playing = buzzer;
+    }
+}
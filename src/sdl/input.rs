@@ -0,0 +1,119 @@
+use sdl2;
+use sdl2::event::Event;
+use sdl2::keyboard::{self, Scancode};
+
+use input::{InputSource, Keypad, FrontendEvent};
+
+/// Reads the keypad from the keyboard, using the left hand block 4-7/R-U/F-J/V-M.
+/// F1-F9 save the machine to that numbered slot, and Shift+F1-F9 load it.
+/// Holding Backspace rewinds.
+pub struct SdlInput {
+    input_driver : sdl2::EventPump
+}
+
+impl SdlInput {
+    pub fn new(context: &sdl2::Sdl) -> SdlInput {
+        SdlInput {
+            input_driver: context.event_pump().unwrap()
+        }
+    }
+}
+
+impl InputSource for SdlInput {
+    fn poll(&mut self, events: &mut Vec<FrontendEvent>) -> Keypad {
+        let mut keys = Keypad::new();
+
+        for event in self.input_driver.poll_iter() {
+            match event {
+                Event::Quit {..} => events.push(FrontendEvent::Quit),
+                Event::KeyDown { scancode: Some(scancode), keymod, repeat: false, .. } => {
+                    if let Some(slot) = state_slot(scancode) {
+                        if keymod.intersects(keyboard::LSHIFTMOD | keyboard::RSHIFTMOD) {
+                            events.push(FrontendEvent::LoadState(slot));
+                        } else {
+                            events.push(FrontendEvent::SaveState(slot));
+                        }
+                    }
+                },
+                _ => {}
+            }
+        };
+
+        let state : Vec<sdl2::keyboard::Scancode> = self.input_driver.keyboard_state().pressed_scancodes().collect();
+
+        for key in state {
+            match key {
+                Scancode::Backspace => {
+                    events.push(FrontendEvent::Rewind);
+                },
+                Scancode::B => {
+                    keys.set(0x0, true);
+                }
+                Scancode::Num4 => {
+                    keys.set(0x1, true);
+                },
+                Scancode::Num5 => {
+                    keys.set(0x2, true);
+                },
+                Scancode::Num6 => {
+                    keys.set(0x3, true);
+                },
+                Scancode::R => {
+                    keys.set(0x4, true);
+                },
+                Scancode::T => {
+                    keys.set(0x5, true);
+                },
+                Scancode::Y => {
+                    keys.set(0x6, true);
+                },
+                Scancode::F => {
+                    keys.set(0x7, true);
+                },
+                Scancode::G => {
+                    keys.set(0x8, true);
+                },
+                Scancode::H => {
+                    keys.set(0x9, true);
+                },
+                Scancode::V => {
+                    keys.set(0xA, true);
+                },
+                Scancode::N => {
+                    keys.set(0xB, true);
+                },
+                Scancode::Num7 => {
+                    keys.set(0xC, true);
+                },
+                Scancode::U => {
+                    keys.set(0xD, true);
+                },
+                Scancode::J => {
+                    keys.set(0xE, true);
+                },
+                Scancode::M => {
+                    keys.set(0xF, true);
+                }
+                _ => continue
+            }
+        }
+
+        keys
+    }
+}
+
+fn state_slot(scancode: Scancode) -> Option<u8> {
+    let slot = match scancode {
+        Scancode::F1 => 1,
+        Scancode::F2 => 2,
+        Scancode::F3 => 3,
+        Scancode::F4 => 4,
+        Scancode::F5 => 5,
+        Scancode::F6 => 6,
+        Scancode::F7 => 7,
+        Scancode::F8 => 8,
+        Scancode::F9 => 9,
+        _ => return None
+    };
+    Some(slot)
+}
@@ -0,0 +1,18 @@
+//! SDL2 frontend: a window that renders the CHIP-8 display, a square wave
+//! buzzer and keyboard input. Only built with the `sdl` feature.
+
+use sdl2;
+
+pub mod video;
+pub mod audio;
+pub mod input;
+
+pub use self::video::SdlVideo;
+pub use self::audio::SdlAudio;
+pub use self::input::SdlInput;
+
+/// Initialises SDL. The returned context is shared by the video, audio and
+/// input backends.
+pub fn init() -> sdl2::Sdl {
+    sdl2::init().unwrap()
+}
@@ -0,0 +1,85 @@
+use sdl2;
+use sdl2::pixels;
+use sdl2::rect;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+
+use display::{Framebuffer, Video, CHIP8_WIDTH, CHIP8_HEIGHT, PALETTE};
+
+/// Size of a low resolution pixel. High resolution pixels are half this.
+const PIXEL_SIZE : u32 = 10;
+
+/// Renders the framebuffer into an SDL window, scaling each CHIP-8 pixel to
+/// a square so the window size doesn't change with the resolution.
+pub struct SdlVideo {
+    canvas: Canvas<Window>,
+    framebuffer: Framebuffer
+}
+
+#[derive(Debug)]
+struct Pixel {
+    colour: pixels::Color,
+    representation: rect::Rect
+}
+
+impl SdlVideo {
+    pub fn new(context: &sdl2::Sdl) -> SdlVideo {
+        let mut window_builder = context.video().unwrap().window(
+                                                        "Test window",
+                                                        CHIP8_WIDTH as u32 * PIXEL_SIZE, 
+                                                        CHIP8_HEIGHT as u32 * PIXEL_SIZE);
+        let window = window_builder.opengl().build().unwrap();
+        let mut canvas = window.into_canvas().present_vsync().build().unwrap();
+
+        canvas.set_draw_color(pixels::Color::RGB(0, 0, 0));
+        canvas.clear();
+        canvas.present();
+
+        SdlVideo {
+            canvas,
+            framebuffer: Framebuffer::new()
+        }
+    }
+}
+
+impl Video for SdlVideo {
+    fn framebuffer(&self) -> &Framebuffer {
+        &self.framebuffer
+    }
+
+    fn framebuffer_mut(&mut self) -> &mut Framebuffer {
+        &mut self.framebuffer
+    }
+
+    fn present(&mut self) {
+        if !self.framebuffer.vram_changed() {
+            return;
+        }
+
+        self.canvas.clear();
+        let size = PIXEL_SIZE * CHIP8_WIDTH as u32 / self.framebuffer.width() as u32;
+        for y in 0..self.framebuffer.height() {
+            for x in 0..self.framebuffer.width() {
+                let pixel = Pixel::new(x, y, size, self.framebuffer.pixel_value(x, y));
+                self.canvas.set_draw_color(pixel.colour);
+                self.canvas.fill_rect(pixel.representation).unwrap();
+            }
+        }
+        self.canvas.present();
+        self.framebuffer.mark_presented();
+    }
+}
+
+impl Pixel {
+    fn new(xpos : usize, ypos : usize, size: u32, value: u8) -> Pixel {
+        let [red, green, blue] = PALETTE[value as usize];
+        Pixel {
+            colour: pixels::Color::RGB(red, green, blue),
+            representation: rect::Rect::new(
+                                xpos as i32 * size as i32, 
+                                ypos as i32 * size as i32,
+                                size, 
+                                size)
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use chip8_emulator::{asm, conformance, disasm, rom, trace, tracediff};
+use chip8_emulator::{Chip8State, EmulatorError, Platform, Quirks};
+use chip8_emulator::input::ScriptedInput;
+
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+pub fn disassemble(filename: &str, platform: Platform) -> Result<(), Box<dyn Error>> {
+    let rom = rom::read_rom(filename)?;
+    if rom.len() > disasm::max_rom_size() {
+        return Err(Box::new(EmulatorError::RomTooLarge { size: rom.len(), capacity: disasm::max_rom_size() }));
+    }
+    print!("{}", disasm::disassemble(&rom, platform));
+    Ok(())
+}
+
+pub fn assemble(source: &str, output: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let rom = if rom::is_octo_source(source) {
+        rom::read_rom(source)?
+    } else {
+        asm::assemble_file(Path::new(source))?
+    };
+    let source = Path::new(source);
+    let output = match output {
+        Some(output) => Path::new(output).to_path_buf(),
+        None => source.with_extension("ch8")
+    };
+    fs::write(&output, rom)?;
+    Ok(())
+}
+
+pub fn conformance(directory: &str, bless: bool) -> Result<(), Box<dyn Error>> {
+    let (report, failures) = conformance::run_directory(Path::new(directory), bless)?;
+    print!("{}", report);
+    if failures > 0 {
+        return Err(From::from(format!("{} conformance case(s) failed", failures)));
+    }
+    Ok(())
+}
+
+pub fn tracediff(first: &str, second: &str, platform: Platform, context: usize) -> Result<(), Box<dyn Error>> {
+    let read = |filename: &str| fs::read(filename)
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| trace::read(&bytes))
+        .map_err(|e| format!("{}: {}", filename, e));
+    let (first_platform, first) = read(first)?;
+    let (_, second) = read(second)?;
+    print!("{}", tracediff::diff_traces(&first, &second, first_platform.unwrap_or(platform), context));
+    Ok(())
+}
+
+/// Settings for `tracediff --lockstep`; the two runs differ only in their
+/// ROMs and quirks.
+pub struct Lockstep {
+    pub roms: (String, String),
+    pub platform: Platform,
+    pub quirks: (Quirks, Quirks),
+    pub clock_hz: u32,
+    pub cycles: u64,
+    pub input: Option<String>,
+    pub seed: u64,
+    pub context: usize
+}
+
+pub fn lockstep(config: &Lockstep) -> Result<(), Box<dyn Error>> {
+    let machine = |filename: &str, quirks: Quirks| -> Result<Chip8State, Box<dyn Error>> {
+        let mut state = Chip8State::default();
+        state.set_platform(config.platform);
+        state.set_quirks(quirks);
+        state.seed_rng(config.seed);
+        state.load_instructions(rom::read_rom(filename)?)?;
+        state.load_font();
+        Ok(state)
+    };
+    let mut first = machine(&config.roms.0, config.quirks.0)?;
+    let mut second = machine(&config.roms.1, config.quirks.1)?;
+    let input = match config.input {
+        Some(ref filename) => ScriptedInput::from_file(filename)?,
+        None => ScriptedInput::new()
+    };
+    print!("{}", tracediff::lockstep(&mut first, &mut second, &input, config.clock_hz, config.cycles, config.context));
+    Ok(())
+}
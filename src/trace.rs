@@ -0,0 +1,466 @@
+//! Execution traces: one record per instruction with the registers it
+//! changed and the memory it wrote, for diffing runs and feeding other tools.
+//!
+//! The text format is one line per instruction:
+//!
+//! ```text
+//!       cycle  PC   op    instruction           changes
+//!          12  204  F033  LD B, V0              [300]=01 [301]=02 [302]=03
+//! ```
+//!
+//! The binary format is `C8TR`, a version byte and a platform byte, then per
+//! record: the cycle as a LEB128 delta from the previous record, PC and
+//! opcode (big endian u16s), a count of register changes each written as a
+//! register id (0-15 for V0-VF, then I, SP, DT, ST) and its new value (two
+//! bytes for I), and a count of memory writes each an address and a byte.
+
+use std::fmt;
+use std::io::{self, Write};
+
+use cpu::Cpu;
+use decoder;
+use memory::{Byte, Memory, Word};
+use platform::Platform;
+
+pub const MAGIC: [u8; 4] = *b"C8TR";
+pub const VERSION: u8 = 1;
+
+const REGISTER_I: Byte = 16;
+const REGISTER_SP: Byte = 17;
+const REGISTER_DT: Byte = 18;
+const REGISTER_ST: Byte = 19;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Binary
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "binary" => Some(Format::Binary),
+            _ => None
+        }
+    }
+}
+
+/// A register an instruction changed, with its new value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    V(Byte, Byte),
+    I(u16),
+    Sp(Byte),
+    Dt(Byte),
+    St(Byte)
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Change::V(register, value) => write!(f, "V{:X}={:02X}", register, value),
+            Change::I(value) => write!(f, "I={:03X}", value),
+            Change::Sp(value) => write!(f, "SP={}", value),
+            Change::Dt(value) => write!(f, "DT={:02X}", value),
+            Change::St(value) => write!(f, "ST={:02X}", value)
+        }
+    }
+}
+
+/// One executed instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub cycle: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    pub changes: Vec<Change>,
+    pub writes: Vec<(u16, Byte)>
+}
+
+impl Record {
+    /// The record as a line of the text format, without the newline.
+    pub fn to_text(&self, platform: Platform) -> String {
+        let instruction = decoder::decode(Word::new_from_full(self.opcode), platform).to_string();
+        let mut line = format!("{:>10}  {:03X}  {:04X}  {:<20}", self.cycle, self.pc, self.opcode, instruction);
+        for change in &self.changes {
+            line += &format!(" {}", change);
+        }
+        for &(address, value) in &self.writes {
+            line += &format!(" [{:03X}]={:02X}", address, value);
+        }
+        line.trim_end().to_string()
+    }
+}
+
+/// The registers an instruction can change, taken before and after it runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    v: [Byte; 16],
+    i: u16,
+    sp: Byte,
+    dt: Byte,
+    st: Byte
+}
+
+impl Snapshot {
+    pub fn take(cpu: &Cpu, memory: &Memory) -> Snapshot {
+        let mut v = [0; 16];
+        for (register, value) in v.iter_mut().enumerate() {
+            *value = cpu.get_reg(register as Byte);
+        }
+        Snapshot {
+            v,
+            i: cpu.i_register().full(),
+            sp: memory.stack().len() as Byte,
+            dt: cpu.delay_timer(),
+            st: cpu.sound_timer()
+        }
+    }
+
+    pub fn changes_to(&self, after: &Snapshot) -> Vec<Change> {
+        let mut changes: Vec<Change> = (0..16)
+            .filter(|&register| self.v[register] != after.v[register])
+            .map(|register| Change::V(register as Byte, after.v[register]))
+            .collect();
+        if self.i != after.i {
+            changes.push(Change::I(after.i));
+        }
+        if self.sp != after.sp {
+            changes.push(Change::Sp(after.sp));
+        }
+        if self.dt != after.dt {
+            changes.push(Change::Dt(after.dt));
+        }
+        if self.st != after.st {
+            changes.push(Change::St(after.st));
+        }
+        changes
+    }
+}
+
+enum Output {
+    Writer(Box<dyn Write>, Format),
+    Memory(Vec<Record>)
+}
+
+/// Writes records for the instructions whose address is in one of `ranges`
+/// (every instruction if there are none).
+pub struct Tracer {
+    output: Output,
+    platform: Platform,
+    ranges: Vec<(u16, u16)>,
+    last_cycle: u64
+}
+
+impl Tracer {
+    /// Starts a trace, writing the header for the binary format.
+    pub fn new(mut output: Box<dyn Write>, format: Format, platform: Platform) -> io::Result<Tracer> {
+        if format == Format::Binary {
+            output.write_all(&MAGIC)?;
+            output.write_all(&[VERSION, platform_id(platform)])?;
+        }
+        Ok(Tracer {
+            output: Output::Writer(output, format),
+            platform,
+            ranges: Vec::new(),
+            last_cycle: 0
+        })
+    }
+
+    /// A tracer that keeps the records for `take_records` instead of
+    /// writing them anywhere.
+    pub fn in_memory(platform: Platform) -> Tracer {
+        Tracer {
+            output: Output::Memory(Vec::new()),
+            platform,
+            ranges: Vec::new(),
+            last_cycle: 0
+        }
+    }
+
+    /// The records kept since the last call, for an in-memory tracer.
+    pub fn take_records(&mut self) -> Vec<Record> {
+        match self.output {
+            Output::Memory(ref mut records) => records.split_off(0),
+            Output::Writer(..) => Vec::new()
+        }
+    }
+
+    /// Only traces instructions at addresses from `start` to `end` inclusive.
+    /// Can be called more than once to trace several ranges.
+    pub fn add_range(&mut self, start: u16, end: u16) {
+        self.ranges.push((start, end));
+    }
+
+    pub fn wants(&self, pc: u16) -> bool {
+        self.ranges.is_empty() || self.ranges.iter().any(|&(start, end)| start <= pc && pc <= end)
+    }
+
+    pub fn record(&mut self, record: &Record) -> io::Result<()> {
+        match self.output {
+            Output::Writer(ref mut output, Format::Text) => writeln!(output, "{}", record.to_text(self.platform)),
+            Output::Writer(ref mut output, Format::Binary) => {
+                let bytes = encode(record, self.last_cycle);
+                self.last_cycle = record.cycle;
+                output.write_all(&bytes)
+            },
+            Output::Memory(ref mut records) => {
+                records.push(record.clone());
+                Ok(())
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.output {
+            Output::Writer(ref mut output, _) => output.flush(),
+            Output::Memory(_) => Ok(())
+        }
+    }
+}
+
+fn encode(record: &Record, last_cycle: u64) -> Vec<Byte> {
+    let mut bytes = Vec::new();
+    let mut delta = record.cycle.wrapping_sub(last_cycle);
+    loop {
+        let low = (delta & 0x7F) as Byte;
+        delta >>= 7;
+        if delta == 0 {
+            bytes.push(low);
+            break;
+        }
+        bytes.push(low | 0x80);
+    }
+    bytes.extend_from_slice(&[(record.pc >> 8) as Byte, record.pc as Byte]);
+    bytes.extend_from_slice(&[(record.opcode >> 8) as Byte, record.opcode as Byte]);
+    bytes.push(record.changes.len() as Byte);
+    for change in &record.changes {
+        match *change {
+            Change::V(register, value) => bytes.extend_from_slice(&[register, value]),
+            Change::I(value) => bytes.extend_from_slice(&[REGISTER_I, (value >> 8) as Byte, value as Byte]),
+            Change::Sp(value) => bytes.extend_from_slice(&[REGISTER_SP, value]),
+            Change::Dt(value) => bytes.extend_from_slice(&[REGISTER_DT, value]),
+            Change::St(value) => bytes.extend_from_slice(&[REGISTER_ST, value])
+        }
+    }
+    bytes.push(record.writes.len() as Byte);
+    for &(address, value) in &record.writes {
+        bytes.extend_from_slice(&[(address >> 8) as Byte, address as Byte, value]);
+    }
+    bytes
+}
+
+/// Reads a trace in either format. Text traces don't say which platform
+/// they were made on.
+pub fn read(bytes: &[Byte]) -> Result<(Option<Platform>, Vec<Record>), String> {
+    if bytes.starts_with(&MAGIC) {
+        let (platform, records) = read_binary(bytes)?;
+        return Ok((Some(platform), records));
+    }
+    let text = String::from_utf8_lossy(bytes);
+    let records = text.lines()
+        .enumerate()
+        .filter(|&(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| parse_line(line).map_err(|e| format!("line {}: {}", number + 1, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((None, records))
+}
+
+/// Parses a line of the text format. The disassembly is skipped as it can
+/// be worked out from the opcode; everything after it with an `=` is a
+/// change or a write.
+fn parse_line(line: &str) -> Result<Record, String> {
+    let mut fields = line.split_whitespace();
+    let mut field = |what: &str| fields.next().ok_or_else(|| format!("missing {}", what));
+    let cycle = field("cycle")?.parse::<u64>().map_err(|_| String::from("bad cycle"))?;
+    let pc = hex(field("PC")?)?;
+    let opcode = hex(field("opcode")?)?;
+
+    let mut changes = Vec::new();
+    let mut writes = Vec::new();
+    for item in fields.filter(|item| item.contains('=')) {
+        let (name, value) = item.split_once('=').expect("filtered on =");
+        let value = hex(value)?;
+        if let Some(address) = name.strip_prefix('[').and_then(|name| name.strip_suffix(']')) {
+            writes.push((hex(address)?, value as Byte));
+            continue;
+        }
+        changes.push(match name {
+            "I" => Change::I(value),
+            "SP" => Change::Sp(value as Byte),
+            "DT" => Change::Dt(value as Byte),
+            "ST" => Change::St(value as Byte),
+            _ if name.len() == 2 && name.starts_with('V') => Change::V(hex(&name[1..])? as Byte, value as Byte),
+            _ => return Err(format!("unknown register `{}`", name))
+        });
+    }
+    Ok(Record { cycle, pc, opcode, changes, writes })
+}
+
+fn hex(text: &str) -> Result<u16, String> {
+    u16::from_str_radix(text, 16).map_err(|_| format!("`{}` is not a hex number", text))
+}
+
+/// Reads a whole binary trace back.
+pub fn read_binary(bytes: &[Byte]) -> Result<(Platform, Vec<Record>), String> {
+    if bytes.len() < MAGIC.len() + 2 || bytes[..MAGIC.len()] != MAGIC {
+        return Err(String::from("not a binary trace"));
+    }
+    if bytes[4] != VERSION {
+        return Err(format!("trace version {} is not supported", bytes[4]));
+    }
+    let platform = match bytes[5] {
+        0 => Platform::Chip8,
+        1 => Platform::SuperChip,
+        2 => Platform::XoChip,
+        _ => return Err(String::from("trace has an unknown platform"))
+    };
+
+    let mut reader = Reader { bytes: &bytes[6..] };
+    let mut records = Vec::new();
+    let mut cycle: u64 = 0;
+    while !reader.bytes.is_empty() {
+        let truncated = || format!("trace is truncated in record {}", records.len() + 1);
+        cycle = cycle.wrapping_add(reader.leb128().ok_or_else(truncated)?);
+        let pc = reader.u16().ok_or_else(truncated)?;
+        let opcode = reader.u16().ok_or_else(truncated)?;
+        let mut changes = Vec::new();
+        for _ in 0..reader.u8().ok_or_else(truncated)? {
+            changes.push(match reader.u8().ok_or_else(truncated)? {
+                register @ 0..=15 => Change::V(register, reader.u8().ok_or_else(truncated)?),
+                REGISTER_I => Change::I(reader.u16().ok_or_else(truncated)?),
+                REGISTER_SP => Change::Sp(reader.u8().ok_or_else(truncated)?),
+                REGISTER_DT => Change::Dt(reader.u8().ok_or_else(truncated)?),
+                REGISTER_ST => Change::St(reader.u8().ok_or_else(truncated)?),
+                other => return Err(format!("unknown register {} in record {}", other, records.len() + 1))
+            });
+        }
+        let mut writes = Vec::new();
+        for _ in 0..reader.u8().ok_or_else(truncated)? {
+            writes.push((reader.u16().ok_or_else(truncated)?, reader.u8().ok_or_else(truncated)?));
+        }
+        records.push(Record { cycle, pc, opcode, changes, writes });
+    }
+    Ok((platform, records))
+}
+
+struct Reader<'a> {
+    bytes: &'a [Byte]
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Option<Byte> {
+        let (&first, rest) = self.bytes.split_first()?;
+        self.bytes = rest;
+        Some(first)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some((self.u8()? as u16) << 8 | self.u8()? as u16)
+    }
+
+    fn leb128(&mut self) -> Option<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+}
+
+fn platform_id(platform: Platform) -> Byte {
+    match platform {
+        Platform::Chip8 => 0,
+        Platform::SuperChip => 1,
+        Platform::XoChip => 2
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use trace::{Tracer, Format, Record, Change, read, read_binary};
+    use emustate::Chip8State;
+    use platform::Platform;
+
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    /// A writer the test can still read after handing it to the tracer.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // LD I, 0x300; LD V0, 123; LD B, V0; CALL 0x20A; JP 0x208; (0x20A) RET
+    const PROGRAM: [u8; 12] = [0xA3, 0x00, 0x60, 0x7B, 0xF0, 0x33, 0x22, 0x0A, 0x12, 0x08, 0x00, 0xEE];
+
+    fn trace(format: Format, ranges: &[(u16, u16)]) -> Vec<u8> {
+        let output = Shared::default();
+        let mut tracer = Tracer::new(Box::new(output.clone()), format, Platform::Chip8).unwrap();
+        for &(start, end) in ranges {
+            tracer.add_range(start, end);
+        }
+        let mut state = Chip8State::default();
+        state.load_instructions(PROGRAM.to_vec()).unwrap();
+        state.set_tracer(Some(tracer));
+        for _ in 0..6 {
+            state.run_next_cycle().unwrap();
+        }
+        let bytes = output.0.borrow().clone();
+        bytes
+    }
+
+    #[test]
+    fn test_text_trace() {
+        let text = String::from_utf8(trace(Format::Text, &[])).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], "         0  200  A300  LD I, 0x300          I=300");
+        assert_eq!(lines[2], "         2  204  F033  LD B, V0             [300]=01 [301]=02 [302]=03");
+        assert_eq!(lines[3], "         3  206  220A  CALL 0x20A           SP=1");
+        assert_eq!(lines[5], "         5  208  1208  JP 0x208");
+    }
+
+    #[test]
+    fn test_text_trace_reads_back() {
+        let text = trace(Format::Text, &[]);
+        let (platform, records) = read(&text).unwrap();
+
+        assert_eq!(platform, None);
+        let mut in_memory = Chip8State::default();
+        in_memory.load_instructions(PROGRAM.to_vec()).unwrap();
+        in_memory.set_tracer(Some(Tracer::in_memory(Platform::Chip8)));
+        for _ in 0..6 {
+            in_memory.run_next_cycle().unwrap();
+        }
+        assert_eq!(records, in_memory.tracer_mut().unwrap().take_records());
+        assert_eq!(read(b"1 200 zz").unwrap_err(), "line 1: `zz` is not a hex number");
+    }
+
+    #[test]
+    fn test_binary_trace_round_trips_with_ranges() {
+        let bytes = trace(Format::Binary, &[(0x202, 0x204), (0x20A, 0x20A)]);
+        let (platform, records) = read_binary(&bytes).unwrap();
+
+        assert_eq!(platform, Platform::Chip8);
+        assert_eq!(records.iter().map(|record| record.cycle).collect::<Vec<_>>(), vec![1, 2, 4]);
+        assert_eq!(records[0], Record { cycle: 1, pc: 0x202, opcode: 0x607B, changes: vec![Change::V(0, 0x7B)], writes: vec![] });
+        assert_eq!(records[2].changes, vec![Change::Sp(0)]);
+        assert!(read_binary(&bytes[..bytes.len() - 1]).unwrap_err().contains("truncated in record 3"));
+    }
+}
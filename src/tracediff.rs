@@ -0,0 +1,244 @@
+//! Finds where two executions part ways, either from two traces or by
+//! running two machines side by side, and describes the first instruction
+//! where they disagree.
+
+use std::collections::VecDeque;
+
+use emustate::Chip8State;
+use input::ScriptedInput;
+use memory::Byte;
+use platform::Platform;
+use scheduler::Scheduler;
+use trace::{Record, Change, Tracer};
+
+/// Index of the first record that differs, or where one trace ends before
+/// the other. None if they are the same.
+pub fn first_difference(first: &[Record], second: &[Record]) -> Option<usize> {
+    first.iter().zip(second)
+        .position(|(a, b)| a != b)
+        .or_else(|| if first.len() == second.len() { None } else { Some(first.len().min(second.len())) })
+}
+
+/// Compares two traces, showing `context` instructions either side of the
+/// first difference.
+pub fn diff_traces(first: &[Record], second: &[Record], platform: Platform, context: usize) -> String {
+    match first_difference(first, second) {
+        None => format!("Traces match ({} instructions)\n", first.len()),
+        Some(index) => {
+            let after = |trace: &[Record]| trace[index.min(trace.len())..(index + context).min(trace.len())].to_vec();
+            describe(&first[index.saturating_sub(context)..index], &after(first), &after(second), platform)
+        }
+    }
+}
+
+/// Runs two machines an instruction at a time with the same input until
+/// they execute something different, for at most `max_cycles` instructions.
+pub fn lockstep(first: &mut Chip8State, second: &mut Chip8State, input: &ScriptedInput, clock_hz: u32, max_cycles: u64, context: usize) -> String {
+    let mut machines = [Lockstep::new(first, input, clock_hz), Lockstep::new(second, input, clock_hz)];
+    let mut before = VecDeque::with_capacity(context + 1);
+
+    for cycle in 0..max_cycles {
+        let a = machines[0].step();
+        let b = machines[1].step();
+        if a != b {
+            let mut after = [a.into_iter().collect::<Vec<_>>(), b.into_iter().collect::<Vec<_>>()];
+            for (machine, after) in machines.iter_mut().zip(after.iter_mut()) {
+                while after.len() < context && machine.stopped.is_none() {
+                    after.extend(machine.step());
+                }
+            }
+            let mut report = describe(&Vec::from(before), &after[0], &after[1], machines[0].state.cpu().platform());
+            for (name, machine) in ["first", "second"].iter().zip(&machines) {
+                if let Some(ref stopped) = machine.stopped {
+                    report += &format!("The {} run stopped: {}\n", name, stopped);
+                }
+            }
+            return report;
+        }
+        match a {
+            Some(record) => {
+                if before.len() == context {
+                    before.pop_front();
+                }
+                if context > 0 {
+                    before.push_back(record);
+                }
+            },
+            None => {
+                let stopped = machines[0].stopped.clone().unwrap_or_default();
+                return format!("Both runs stopped the same way after {} instructions: {}\n", cycle, stopped);
+            }
+        }
+    }
+    format!("Runs match for {} instructions\n", max_cycles)
+}
+
+struct Lockstep<'a> {
+    state: &'a mut Chip8State,
+    scheduler: Scheduler,
+    input: ScriptedInput,
+    events: Vec<::input::FrontendEvent>,
+    stopped: Option<String>
+}
+
+impl<'a> Lockstep<'a> {
+    fn new(state: &'a mut Chip8State, input: &ScriptedInput, clock_hz: u32) -> Lockstep<'a> {
+        state.set_tracer(Some(Tracer::in_memory(state.cpu().platform())));
+        Lockstep {
+            state,
+            scheduler: Scheduler::with_clock_speed(clock_hz),
+            input: input.clone(),
+            events: Vec::new(),
+            stopped: None
+        }
+    }
+
+    /// The record of the next instruction, or None once the machine has
+    /// stopped with an error or by exiting.
+    fn step(&mut self) -> Option<Record> {
+        if self.stopped.is_some() {
+            return None;
+        }
+        if self.state.cpu().halted() {
+            self.stopped = Some(String::from("the program exited"));
+            return None;
+        }
+        if let Err(error) = self.scheduler.step(self.state, &mut self.input, &mut self.events) {
+            self.stopped = Some(error.to_string());
+            return None;
+        }
+        self.state.tracer_mut().and_then(|tracer| tracer.take_records().pop())
+    }
+}
+
+/// Shows the shared history, then each side from the first difference, then
+/// what the first differing instructions did differently.
+fn describe(before: &[Record], first: &[Record], second: &[Record], platform: Platform) -> String {
+    let cycle = first.first().or(second.first()).map(|record| record.cycle)
+        .or(before.last().map(|record| record.cycle + 1))
+        .unwrap_or(0);
+    let mut report = format!("First difference at cycle {}\n", cycle);
+    if !before.is_empty() {
+        report += "Both:\n";
+        for record in before {
+            report += &format!("  {}\n", record.to_text(platform));
+        }
+    }
+    for (name, side) in [("First", first), ("Second", second)].iter() {
+        report += &format!("{}:\n", name);
+        if side.is_empty() {
+            report += "  (ends here)\n";
+        }
+        for (index, record) in side.iter().enumerate() {
+            let marker = if index == 0 { '>' } else { ' ' };
+            report += &format!("{} {}\n", marker, record.to_text(platform));
+        }
+    }
+    if let (Some(a), Some(b)) = (first.first(), second.first()) {
+        report += &deltas(a, b);
+    }
+    report
+}
+
+fn deltas(a: &Record, b: &Record) -> String {
+    let mut report = String::from("Differences:\n");
+    if a.pc != b.pc {
+        report += &format!("  PC: {:03X} vs {:03X}\n", a.pc, b.pc);
+    }
+    if a.opcode != b.opcode {
+        report += &format!("  opcode: {:04X} vs {:04X}\n", a.opcode, b.opcode);
+    }
+
+    let mut names: Vec<String> = a.changes.iter().chain(&b.changes).map(register_name).collect();
+    names.sort();
+    names.dedup();
+    for name in names {
+        let value = |record: &Record| record.changes.iter()
+            .find(|change| register_name(change) == name)
+            .map_or(String::from("unchanged"), |change| change.to_string().split_off(name.len() + 1));
+        let (first, second) = (value(a), value(b));
+        if first != second {
+            report += &format!("  {}: {} vs {}\n", name, first, second);
+        }
+    }
+
+    let mut addresses: Vec<u16> = a.writes.iter().chain(&b.writes).map(|&(address, _)| address).collect();
+    addresses.sort_unstable();
+    addresses.dedup();
+    for address in addresses {
+        let value = |writes: &[(u16, Byte)]| writes.iter().rev()
+            .find(|&&(written, _)| written == address)
+            .map_or(String::from("not written"), |&(_, value)| format!("{:02X}", value));
+        let (first, second) = (value(&a.writes), value(&b.writes));
+        if first != second {
+            report += &format!("  [{:03X}]: {} vs {}\n", address, first, second);
+        }
+    }
+    report
+}
+
+fn register_name(change: &Change) -> String {
+    let text = change.to_string();
+    text[..text.find('=').expect("changes print as NAME=VALUE")].to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use tracediff::{first_difference, diff_traces, lockstep};
+    use trace::{Record, Change};
+    use emustate::Chip8State;
+    use input::ScriptedInput;
+    use platform::Platform;
+    use quirks::Quirks;
+
+    fn record(cycle: u64, pc: u16, opcode: u16, changes: Vec<Change>) -> Record {
+        Record { cycle, pc, opcode, changes, writes: vec![] }
+    }
+
+    #[test]
+    fn test_first_difference() {
+        let a = vec![record(0, 0x200, 0x6001, vec![Change::V(0, 1)]), record(1, 0x202, 0x1202, vec![])];
+        let mut b = a.clone();
+        assert_eq!(first_difference(&a, &b), None);
+        b.pop();
+        assert_eq!(first_difference(&a, &b), Some(1));
+        b.push(record(1, 0x202, 0x1200, vec![]));
+        assert_eq!(first_difference(&a, &b), Some(1));
+
+        let report = diff_traces(&a, &b, Platform::Chip8, 3);
+        assert_eq!(report, "First difference at cycle 1\n\
+                            Both:\n           0  200  6001  LD V0, 0x01          V0=01\n\
+                            First:\n>          1  202  1202  JP 0x202\n\
+                            Second:\n>          1  202  1200  JP 0x200\n\
+                            Differences:\n  opcode: 1202 vs 1200\n");
+    }
+
+    #[test]
+    fn test_lockstep_finds_quirk_divergence() {
+        // LD V1, 0x81; SHR V0, V1; LD [I], V0; JP 0x206
+        let program = vec![0x61, 0x81, 0x80, 0x16, 0xF0, 0x55, 0x12, 0x06];
+        let mut first = Chip8State::default();
+        first.set_quirks(Quirks::cosmac_vip());
+        first.load_instructions(program.clone()).unwrap();
+        let mut second = Chip8State::default();
+        second.set_quirks(Quirks::superchip());
+        second.load_instructions(program).unwrap();
+
+        let report = lockstep(&mut first, &mut second, &ScriptedInput::new(), 600, 1000, 2);
+
+        assert!(report.starts_with("First difference at cycle 1\nBoth:\n           0  200  6181"), "{}", report);
+        assert!(report.contains("  2  204  F055  LD [I], V0           [000]=00\n"), "{}", report);
+        assert!(report.ends_with("Differences:\n  V0: 40 vs unchanged\n  VF: 01 vs unchanged\n"), "{}", report);
+    }
+
+    #[test]
+    fn test_lockstep_matching_runs() {
+        let program = vec![0x70, 0x01, 0x12, 0x00];
+        let mut first = Chip8State::default();
+        first.load_instructions(program.clone()).unwrap();
+        let mut second = Chip8State::default();
+        second.load_instructions(program).unwrap();
+
+        assert_eq!(lockstep(&mut first, &mut second, &ScriptedInput::new(), 600, 500, 3), "Runs match for 500 instructions\n");
+    }
+}